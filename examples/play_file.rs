@@ -0,0 +1,275 @@
+//! Plays a WAV file to the default output device.
+//!
+//! Usage: `cargo run --example play_file -- path/to/file.wav`
+//!
+//! Only uncompressed 16-bit, 24-bit, and 32-bit integer PCM files and 32-bit
+//! float files are supported.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rainout::error::RunConfigError;
+use rainout::{ProcessHandler, ProcessInfo, RainoutConfig, RunOptions, StreamInfo, StreamMsg};
+
+pub fn main() {
+    simple_logger::SimpleLogger::new().with_level(log::LevelFilter::Debug).init().unwrap();
+
+    let path = match std::env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("Usage: play_file <path to WAV file>");
+            return;
+        }
+    };
+
+    let file = match WavFile::load(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Failed to load {}: {}", &path, e);
+            return;
+        }
+    };
+
+    println!(
+        "Loaded {}: {} channels, {} Hz, {:.2} seconds",
+        &path,
+        file.channels.len(),
+        file.sample_rate,
+        file.num_frames() as f64 / f64::from(file.sample_rate)
+    );
+
+    let finished = Arc::new(AtomicBool::new(false));
+
+    let player = FilePlayer { file, playhead: 0.0, step: 1.0, finished: Arc::clone(&finished) };
+
+    // Use the default backend and device.
+    let config = RainoutConfig::default();
+    let options = RunOptions { detect_clipping: true, ..Default::default() };
+
+    let mut stream_handle = match rainout::run(&config, &options, player) {
+        Ok(stream_handle) => stream_handle,
+        Err(RunConfigError::AutoDeviceIsInputOnly) => {
+            eprintln!("The audio device is capture-only, so it can't play the file");
+            return;
+        }
+        Err(RunConfigError::AutoNoAudioDeviceFound) => {
+            eprintln!("No audio device is available, check that one is connected");
+            return;
+        }
+        Err(e) => {
+            eprintln!("Failed to run stream: {}", e);
+            return;
+        }
+    };
+
+    loop {
+        while let Some(msg) = stream_handle.messages.pop() {
+            match msg {
+                StreamMsg::Started(stream_info) => {
+                    println!(
+                        "Playing on {} at {} Hz",
+                        stream_info.audio_backend.as_str(),
+                        stream_info.sample_rate
+                    );
+                }
+                StreamMsg::ConfigWarning(warning) => {
+                    println!("Config warning: {:?}", warning);
+                }
+                StreamMsg::OutputClipped { channel, count } => {
+                    println!("Output channel {} clipped {} samples", channel, count);
+                }
+                StreamMsg::Error(e) => {
+                    eprintln!("{}", e);
+                    return;
+                }
+                StreamMsg::Closed => {
+                    println!("Stream closed");
+                    return;
+                }
+                msg => {
+                    println!("{:?}", msg);
+                }
+            }
+        }
+
+        if finished.load(Ordering::Relaxed) {
+            println!("Finished playing");
+            break;
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    // The stream is automatically closed when `stream_handle` is dropped.
+}
+
+pub struct FilePlayer {
+    file: WavFile,
+    // The current position in the file in frames. This is fractional so that the
+    // file can be resampled to the sample rate of the stream.
+    playhead: f64,
+    // The number of file frames to advance per output frame.
+    step: f64,
+    finished: Arc<AtomicBool>,
+}
+
+impl FilePlayer {
+    /// Read the sample at the current playhead with linear interpolation.
+    fn sample(&self, channel: &[f32]) -> f32 {
+        let i = self.playhead as usize;
+        let fract = (self.playhead - i as f64) as f32;
+
+        let a = channel.get(i).copied().unwrap_or(0.0);
+        let b = channel.get(i + 1).copied().unwrap_or(0.0);
+
+        a + (b - a) * fract
+    }
+}
+
+impl ProcessHandler for FilePlayer {
+    fn init(&mut self, stream_info: &StreamInfo) {
+        if stream_info.sample_rate != self.file.sample_rate {
+            println!(
+                "Resampling file from {} Hz to {} Hz",
+                self.file.sample_rate, stream_info.sample_rate
+            );
+        }
+
+        self.step = f64::from(self.file.sample_rate) / f64::from(stream_info.sample_rate.max(1));
+    }
+
+    fn stream_changed(&mut self, stream_info: &StreamInfo) {
+        self.init(stream_info);
+    }
+
+    fn process<'a>(&mut self, proc_info: ProcessInfo<'a>) {
+        let num_file_frames = self.file.num_frames();
+
+        for frame in 0..proc_info.frames {
+            if self.playhead >= num_file_frames as f64 {
+                // Output silence once the file has finished.
+                for out in proc_info.audio_outputs.iter_mut() {
+                    if let Some(smp) = out.get_mut(frame) {
+                        *smp = 0.0;
+                    }
+                }
+                continue;
+            }
+
+            for (out_i, out) in proc_info.audio_outputs.iter_mut().enumerate() {
+                // Play a mono file on every output, and otherwise map each file
+                // channel to the output with the same index.
+                let channel = if self.file.channels.len() == 1 {
+                    Some(&self.file.channels[0])
+                } else {
+                    self.file.channels.get(out_i)
+                };
+
+                let smp = channel.map(|c| self.sample(c)).unwrap_or(0.0);
+                if let Some(out_smp) = out.get_mut(frame) {
+                    *out_smp = smp;
+                }
+            }
+
+            self.playhead += self.step;
+        }
+
+        if self.playhead >= num_file_frames as f64 {
+            self.finished.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A WAV file that has been fully loaded into memory.
+pub struct WavFile {
+    sample_rate: u32,
+    // The de-interleaved samples of each channel.
+    channels: Vec<Vec<f32>>,
+}
+
+impl WavFile {
+    fn load(path: &str) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err(String::from("not a WAV file"));
+        }
+
+        let mut format: Option<(u16, u16, u32, u16)> = None;
+        let mut data: Option<&[u8]> = None;
+
+        // Walk through each chunk in the file.
+        let mut pos = 12;
+        while pos + 8 <= bytes.len() {
+            let id = &bytes[pos..pos + 4];
+            let size = u32::from_le_bytes([
+                bytes[pos + 4],
+                bytes[pos + 5],
+                bytes[pos + 6],
+                bytes[pos + 7],
+            ]) as usize;
+            let body = &bytes[pos + 8..(pos + 8 + size).min(bytes.len())];
+
+            if id == b"fmt " && body.len() >= 16 {
+                let mut format_tag = u16::from_le_bytes([body[0], body[1]]);
+                let num_channels = u16::from_le_bytes([body[2], body[3]]);
+                let sample_rate = u32::from_le_bytes([body[4], body[5], body[6], body[7]]);
+                let bits_per_sample = u16::from_le_bytes([body[14], body[15]]);
+
+                // WAVE_FORMAT_EXTENSIBLE stores the actual format in the sub-format GUID.
+                if format_tag == 0xFFFE && body.len() >= 26 {
+                    format_tag = u16::from_le_bytes([body[24], body[25]]);
+                }
+
+                format = Some((format_tag, num_channels, sample_rate, bits_per_sample));
+            } else if id == b"data" {
+                data = Some(body);
+            }
+
+            // Chunks are padded to an even number of bytes.
+            pos += 8 + size + (size & 1);
+        }
+
+        let (format_tag, num_channels, sample_rate, bits_per_sample) =
+            format.ok_or_else(|| String::from("missing fmt chunk"))?;
+        let data = data.ok_or_else(|| String::from("missing data chunk"))?;
+
+        if num_channels == 0 || sample_rate == 0 {
+            return Err(String::from("invalid fmt chunk"));
+        }
+
+        let decode: fn(&[u8]) -> f32 = match (format_tag, bits_per_sample) {
+            // PCM
+            (1, 16) => |b| f32::from(i16::from_le_bytes([b[0], b[1]])) / 32_768.0,
+            (1, 24) => |b| (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8_388_608.0,
+            (1, 32) => |b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2_147_483_648.0,
+            // IEEE float
+            (3, 32) => |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            _ => {
+                return Err(format!(
+                    "unsupported format {} with {} bits per sample",
+                    format_tag, bits_per_sample
+                ))
+            }
+        };
+
+        let bytes_per_sample = usize::from(bits_per_sample / 8);
+        let bytes_per_frame = bytes_per_sample * usize::from(num_channels);
+
+        let mut channels: Vec<Vec<f32>> =
+            (0..num_channels).map(|_| Vec::with_capacity(data.len() / bytes_per_frame)).collect();
+
+        for frame in data.chunks_exact(bytes_per_frame) {
+            for (channel, smp) in channels.iter_mut().zip(frame.chunks_exact(bytes_per_sample)) {
+                channel.push(decode(smp));
+            }
+        }
+
+        Ok(Self { sample_rate, channels })
+    }
+
+    fn num_frames(&self) -> usize {
+        self.channels[0].len()
+    }
+}
@@ -0,0 +1,338 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    available_audio_backends, enumerate_audio_backend, enumerate_audio_device, AudioBackendOptions,
+    AudioDeviceConfigOptions, AudioDeviceOptions, Backend, DeviceID,
+};
+
+#[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+use crate::{enumerate_jack_audio_device, JackAudioDeviceOptions};
+
+#[derive(Debug, Clone)]
+/// Every available audio backend, along with their devices and the
+/// configuration options of each device
+pub struct AudioSummary {
+    /// Each available audio backend, in the same order as
+    /// `available_audio_backends()`.
+    pub backends: Vec<AudioBackendSummary>,
+}
+
+impl AudioSummary {
+    /// Re-enumerate a single backend and all of its devices, leaving the
+    /// summaries of every other backend as they are.
+    ///
+    /// This is much faster than calling `full_audio_summary()` again when only
+    /// the currently selected backend needs to be refreshed (i.e. when the user
+    /// clicks "Refresh" in a settings GUI).
+    ///
+    /// If the backend was not in this summary, then it is added at the end.
+    /// The new summary of the backend is returned.
+    pub fn refresh_backend(&mut self, backend: Backend) -> &AudioBackendSummary {
+        let summary = summarize_backend(backend);
+
+        let i = match self.backends.iter().position(|b| b.backend == backend) {
+            Some(i) => {
+                self.backends[i] = summary;
+                i
+            }
+            None => {
+                self.backends.push(summary);
+                self.backends.len() - 1
+            }
+        };
+
+        &self.backends[i]
+    }
+}
+
+#[derive(Debug, Clone)]
+/// An audio backend, along with its devices and the configuration options of
+/// each device
+pub struct AudioBackendSummary {
+    /// The audio backend
+    pub backend: Backend,
+
+    /// The options of this backend.
+    ///
+    /// This will be `None` if the backend could not be enumerated.
+    pub options: Option<AudioBackendOptions>,
+
+    /// Each device of this backend. If a device is listed as both an input and
+    /// an output device, then it is only listed once.
+    pub devices: Vec<AudioDeviceSummary>,
+
+    #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+    /// The options of the system-wide Jack device.
+    ///
+    /// This will be `None` if this backend is not Jack, or if the Jack server
+    /// is not running.
+    pub jack_device: Option<JackAudioDeviceOptions>,
+
+    /// How long it took to enumerate this backend and all of its devices.
+    pub enumeration_time: Duration,
+}
+
+#[derive(Debug, Clone)]
+/// An audio device, along with its configuration options
+pub struct AudioDeviceSummary {
+    /// The name/ID of this device
+    pub id: DeviceID,
+
+    /// `true` if this is the system's default device (or one of the default
+    /// input/output devices).
+    pub is_default: bool,
+
+    /// The configuration options of this device.
+    ///
+    /// This will be `None` if the device could not be enumerated.
+    pub config_options: Option<AudioDeviceConfigOptions>,
+
+    /// How long it took to enumerate this device.
+    pub enumeration_time: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A change to the devices of an audio backend between two summaries
+pub enum DeviceChange {
+    /// The device was not in the old summary.
+    Added(DeviceID),
+    /// The device is no longer in the new summary.
+    Removed(DeviceID),
+    /// The device is in both summaries, but its configuration options changed
+    /// (i.e. its available sample rates).
+    ConfigChanged(DeviceID),
+}
+
+/// Returns the devices that were added, removed, or changed between two
+/// summaries of the same backend (i.e. before and after a hot-plug or a call
+/// to `AudioSummary::refresh_backend()`).
+///
+/// This lets a settings GUI update only the devices that changed, and keep the
+/// current selection if the selected device is still there. Devices are
+/// matched by their identifier when both have one, so a device that was
+/// renamed is not reported as removed and added again.
+///
+/// Removed devices are listed first, in the order of `old`, followed by the
+/// added and changed devices in the order of `new`.
+pub fn diff_audio_backends(
+    old: &AudioBackendSummary,
+    new: &AudioBackendSummary,
+) -> Vec<DeviceChange> {
+    let mut changes: Vec<DeviceChange> = old
+        .devices
+        .iter()
+        .filter(|old_device| !new.devices.iter().any(|d| d.id == old_device.id))
+        .map(|old_device| DeviceChange::Removed(old_device.id.clone()))
+        .collect();
+
+    for new_device in new.devices.iter() {
+        match old.devices.iter().find(|d| d.id == new_device.id) {
+            Some(old_device) => {
+                if old_device.config_options != new_device.config_options {
+                    changes.push(DeviceChange::ConfigChanged(new_device.id.clone()));
+                }
+            }
+            None => changes.push(DeviceChange::Added(new_device.id.clone())),
+        }
+    }
+
+    changes
+}
+
+/// Enumerate every available audio backend, along with all of their devices
+/// and the configuration options of each device.
+///
+/// This is a convenience over calling `available_audio_backends()`,
+/// `enumerate_audio_backend()`, and `enumerate_audio_device()` for each
+/// backend and device. Backends and devices that could not be enumerated are
+/// still listed, with their options set to `None`.
+///
+/// This can take a while (see `enumerate_audio_backend_async()`), so the time
+/// each backend and device took is included to help find slow ones.
+pub fn full_audio_summary() -> AudioSummary {
+    AudioSummary {
+        backends: available_audio_backends().iter().map(|b| summarize_backend(*b)).collect(),
+    }
+}
+
+fn summarize_backend(backend: Backend) -> AudioBackendSummary {
+    let start = Instant::now();
+
+    let options = enumerate_audio_backend(backend).ok();
+
+    // The devices to enumerate, and whether each one is a default device.
+    let mut device_ids: Vec<(DeviceID, bool)> = Vec::new();
+    let mut add_device = |id: &DeviceID, is_default: bool| {
+        if let Some((_, d)) = device_ids.iter_mut().find(|(other, _)| other == id) {
+            *d = *d || is_default;
+        } else {
+            device_ids.push((id.clone(), is_default));
+        }
+    };
+
+    #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+    let mut jack_device = None;
+
+    match options.as_ref().and_then(|o| o.device_options.as_ref()) {
+        Some(AudioDeviceOptions::SingleDeviceOnly { options, default_device }) => {
+            for (i, id) in options.iter().enumerate() {
+                add_device(id, *default_device == Some(i));
+            }
+        }
+        Some(AudioDeviceOptions::LinkedInOutDevice {
+            in_devices,
+            out_devices,
+            default_in_device,
+            default_out_device,
+        }) => {
+            for (i, id) in in_devices.iter().enumerate() {
+                add_device(id, *default_in_device == Some(i));
+            }
+            for (i, id) in out_devices.iter().enumerate() {
+                add_device(id, *default_out_device == Some(i));
+            }
+        }
+        #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+        Some(AudioDeviceOptions::JackSystemWideDevice) => {
+            jack_device = enumerate_jack_audio_device().ok();
+        }
+        _ => {}
+    }
+
+    let devices = device_ids
+        .into_iter()
+        .map(|(id, is_default)| {
+            let start = Instant::now();
+            let config_options = enumerate_audio_device(backend, &id).ok();
+
+            AudioDeviceSummary { id, is_default, config_options, enumeration_time: start.elapsed() }
+        })
+        .collect();
+
+    AudioBackendSummary {
+        backend,
+        options,
+        devices,
+        #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+        jack_device,
+        enumeration_time: start.elapsed(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChannelLayout;
+
+    fn config_options(native_sample_rate: u32) -> AudioDeviceConfigOptions {
+        AudioDeviceConfigOptions {
+            sample_rates: None,
+            native_sample_rate: Some(native_sample_rate),
+            block_sizes: None,
+            exclusive_min_period: None,
+            num_in_channels: 0,
+            num_out_channels: 2,
+            in_channel_names: Vec::new(),
+            out_channel_names: vec![String::from("Output 1"), String::from("Output 2")],
+            in_channel_layout: ChannelLayout::Unspecified,
+            out_channel_layout: ChannelLayout::Stereo,
+            can_take_exclusive_access: false,
+            supports_direct_monitoring: false,
+            in_jack_is_unpopulated: false,
+            out_jack_is_unpopulated: false,
+        }
+    }
+
+    fn id(name: &str, identifier: Option<&str>) -> DeviceID {
+        DeviceID { name: String::from(name), identifier: identifier.map(String::from) }
+    }
+
+    fn device(id: DeviceID, native_sample_rate: u32) -> AudioDeviceSummary {
+        AudioDeviceSummary {
+            id,
+            is_default: false,
+            config_options: Some(config_options(native_sample_rate)),
+            enumeration_time: Duration::ZERO,
+        }
+    }
+
+    fn backend(devices: Vec<AudioDeviceSummary>) -> AudioBackendSummary {
+        AudioBackendSummary {
+            backend: Backend::Wasapi,
+            options: None,
+            devices,
+            #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+            jack_device: None,
+            enumeration_time: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn diff_unchanged_backend() {
+        let old = backend(vec![device(id("Speakers", Some("{1}")), 48_000)]);
+        let new = backend(vec![device(id("Speakers", Some("{1}")), 48_000)]);
+
+        assert!(diff_audio_backends(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn diff_added_removed_and_changed_devices() {
+        let old = backend(vec![
+            device(id("Speakers", Some("{1}")), 48_000),
+            device(id("Headphones", Some("{2}")), 48_000),
+            device(id("Line Out", Some("{3}")), 44_100),
+            device(id("HDMI", Some("{4}")), 48_000),
+        ]);
+        let new = backend(vec![
+            device(id("USB Interface", Some("{5}")), 96_000),
+            device(id("HDMI", Some("{4}")), 48_000),
+            device(id("Speakers", Some("{1}")), 44_100),
+            device(id("Microphone", Some("{6}")), 48_000),
+        ]);
+
+        assert_eq!(
+            diff_audio_backends(&old, &new),
+            vec![
+                DeviceChange::Removed(id("Headphones", Some("{2}"))),
+                DeviceChange::Removed(id("Line Out", Some("{3}"))),
+                DeviceChange::Added(id("USB Interface", Some("{5}"))),
+                DeviceChange::ConfigChanged(id("Speakers", Some("{1}"))),
+                DeviceChange::Added(id("Microphone", Some("{6}"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_matches_renamed_devices_by_identifier() {
+        let old = backend(vec![device(id("Speakers", Some("{1}")), 48_000)]);
+        let new = backend(vec![device(id("Speakers (2- USB Audio)", Some("{1}")), 48_000)]);
+
+        assert!(diff_audio_backends(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn diff_devices_without_identifiers() {
+        let old = backend(vec![device(id("Speakers", None), 48_000)]);
+        let new = backend(vec![device(id("Headphones", None), 48_000)]);
+
+        assert_eq!(
+            diff_audio_backends(&old, &new),
+            vec![
+                DeviceChange::Removed(id("Speakers", None)),
+                DeviceChange::Added(id("Headphones", None)),
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_device_that_failed_to_enumerate() {
+        let old = backend(vec![device(id("Speakers", Some("{1}")), 48_000)]);
+        let mut new = backend(vec![device(id("Speakers", Some("{1}")), 48_000)]);
+        new.devices[0].config_options = None;
+
+        assert_eq!(
+            diff_audio_backends(&old, &new),
+            vec![DeviceChange::ConfigChanged(id("Speakers", Some("{1}")))]
+        );
+    }
+}
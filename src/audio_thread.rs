@@ -0,0 +1,51 @@
+use std::cell::Cell;
+
+thread_local! {
+    static IS_AUDIO_THREAD: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Returns `true` if this is called from the realtime audio thread of a running
+/// stream (the thread that calls `ProcessHandler::process()`).
+///
+/// This is useful for `debug_assert!`-ing that code which allocates or locks is
+/// never called from the audio thread (or that realtime-only code is never
+/// called from anywhere else).
+pub fn is_audio_thread() -> bool {
+    IS_AUDIO_THREAD.with(|is_audio_thread| is_audio_thread.get())
+}
+
+/// Mark the current thread as the realtime audio thread. This is realtime-safe.
+#[cfg_attr(
+    not(any(
+        target_os = "windows",
+        feature = "jack-linux",
+        feature = "jack-macos",
+        feature = "jack-windows"
+    )),
+    allow(dead_code)
+)]
+pub(crate) fn mark_audio_thread() {
+    IS_AUDIO_THREAD.with(|is_audio_thread| is_audio_thread.set(true));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_marked_thread_is_the_audio_thread() {
+        let audio_thread = std::thread::spawn(|| {
+            assert!(!is_audio_thread());
+            mark_audio_thread();
+            assert!(is_audio_thread());
+
+            // Marking it again is harmless.
+            mark_audio_thread();
+            is_audio_thread()
+        });
+        assert!(audio_thread.join().unwrap());
+
+        let other_thread = std::thread::spawn(is_audio_thread);
+        assert!(!other_thread.join().unwrap());
+    }
+}
@@ -0,0 +1,160 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// The number of buckets in the block size histogram. The last bucket holds
+/// every block size of `2^(NUM_BUCKETS - 1)` frames or more.
+const NUM_BUCKETS: usize = 17;
+
+#[derive(Debug, Clone, PartialEq)]
+/// Statistics about the block sizes (the number of frames) passed to the
+/// process method of a running stream
+pub struct BlockSizeStats {
+    /// The smallest block size that was observed
+    pub min: u32,
+
+    /// The largest block size that was observed
+    pub max: u32,
+
+    /// The average block size
+    pub mean: f64,
+
+    /// The number of process cycles for each power-of-two range of block sizes,
+    /// as `(lowest block size in the range, number of process cycles)`.
+    ///
+    /// A range starting at `n` contains the block sizes in `n..2n`. Only ranges
+    /// that were observed at least once are included, in ascending order.
+    pub histogram: Vec<(u32, u64)>,
+}
+
+/// The block size statistics shared between the audio thread and the stream
+/// handle. The audio thread only ever updates atomic counters.
+pub(crate) struct SharedBlockSizeStats {
+    min: AtomicU32,
+    max: AtomicU32,
+    num_blocks: AtomicU64,
+    total_frames: AtomicU64,
+    buckets: [AtomicU64; NUM_BUCKETS],
+}
+
+impl SharedBlockSizeStats {
+    pub fn new() -> Self {
+        Self {
+            min: AtomicU32::new(u32::MAX),
+            max: AtomicU32::new(0),
+            num_blocks: AtomicU64::new(0),
+            total_frames: AtomicU64::new(0),
+            buckets: Default::default(),
+        }
+    }
+
+    /// Record the block size of a single process cycle. This is realtime-safe.
+    pub fn record(&self, frames: usize) {
+        let frames = frames.min(u32::MAX as usize) as u32;
+
+        self.min.fetch_min(frames, Ordering::Relaxed);
+        self.max.fetch_max(frames, Ordering::Relaxed);
+        self.num_blocks.fetch_add(1, Ordering::Relaxed);
+        self.total_frames.fetch_add(u64::from(frames), Ordering::Relaxed);
+
+        let bucket = if frames == 0 { 0 } else { 31 - frames.leading_zeros() as usize };
+        self.buckets[bucket.min(NUM_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns a snapshot of the current statistics.
+    ///
+    /// This will return `None` if no process cycles have been recorded yet.
+    pub fn snapshot(&self) -> Option<BlockSizeStats> {
+        let num_blocks = self.num_blocks.load(Ordering::Relaxed);
+        if num_blocks == 0 {
+            return None;
+        }
+
+        let histogram = self
+            .buckets
+            .iter()
+            .enumerate()
+            .filter_map(|(i, count)| {
+                let count = count.load(Ordering::Relaxed);
+                if count > 0 {
+                    Some((1 << i, count))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        Some(BlockSizeStats {
+            min: self.min.load(Ordering::Relaxed),
+            max: self.max.load(Ordering::Relaxed),
+            mean: self.total_frames.load(Ordering::Relaxed) as f64 / num_blocks as f64,
+            histogram,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_blocks_recorded() {
+        assert_eq!(SharedBlockSizeStats::new().snapshot(), None);
+    }
+
+    #[test]
+    fn fixed_block_size() {
+        let stats = SharedBlockSizeStats::new();
+        for _ in 0..10 {
+            stats.record(256);
+        }
+
+        assert_eq!(
+            stats.snapshot(),
+            Some(BlockSizeStats { min: 256, max: 256, mean: 256.0, histogram: vec![(256, 10)] })
+        );
+    }
+
+    #[test]
+    fn variable_block_sizes() {
+        let stats = SharedBlockSizeStats::new();
+
+        // A WASAPI shared mode stream, where the device sometimes asks for a
+        // partial block.
+        for frames in [480, 480, 32, 448, 480, 511, 512] {
+            stats.record(frames);
+        }
+
+        let snapshot = stats.snapshot().unwrap();
+        assert_eq!(snapshot.min, 32);
+        assert_eq!(snapshot.max, 512);
+        assert_eq!(snapshot.mean, 2943.0 / 7.0);
+        assert_eq!(snapshot.histogram, vec![(32, 1), (256, 5), (512, 1)]);
+    }
+
+    #[test]
+    fn huge_block_sizes_go_in_the_last_bucket() {
+        let stats = SharedBlockSizeStats::new();
+        stats.record(1 << (NUM_BUCKETS - 1));
+        stats.record(1_000_000);
+        stats.record(usize::MAX);
+
+        let snapshot = stats.snapshot().unwrap();
+        assert_eq!(snapshot.max, u32::MAX);
+        assert_eq!(snapshot.histogram, vec![(1 << (NUM_BUCKETS - 1), 3)]);
+    }
+
+    #[test]
+    fn record_from_another_thread() {
+        let stats = std::sync::Arc::new(SharedBlockSizeStats::new());
+
+        let audio_thread_stats = std::sync::Arc::clone(&stats);
+        std::thread::spawn(move || {
+            for _ in 0..1000 {
+                audio_thread_stats.record(128);
+            }
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(stats.snapshot().unwrap().histogram, vec![(128, 1000)]);
+    }
+}
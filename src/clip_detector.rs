@@ -0,0 +1,150 @@
+use ringbuf::Producer;
+
+use crate::StreamMsg;
+
+/// Counts the output samples that fall outside of the range `[-1.0, 1.0]`
+/// and periodically reports them to the stream handle.
+pub(crate) struct ClipDetector {
+    clipped_counts: Vec<usize>,
+    channel_indices: Vec<usize>,
+    frames_until_report: usize,
+    report_interval_frames: usize,
+}
+
+impl ClipDetector {
+    /// Create a new clip detector that reports at most once per second.
+    #[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+    pub fn new(num_channels: usize, sample_rate: u32) -> Self {
+        Self::with_channel_indices((0..num_channels).collect(), sample_rate)
+    }
+
+    /// Create a new clip detector where the buffer at index `i` is reported
+    /// as the channel `channel_indices[i]`.
+    pub fn with_channel_indices(channel_indices: Vec<usize>, sample_rate: u32) -> Self {
+        Self {
+            clipped_counts: vec![0; channel_indices.len()],
+            channel_indices,
+            frames_until_report: sample_rate as usize,
+            report_interval_frames: sample_rate as usize,
+        }
+    }
+
+    /// Count the clipped samples in each of the given output buffers.
+    pub fn process(&mut self, audio_outputs: &[Vec<f32>], frames: usize) {
+        for (count, buffer) in self.clipped_counts.iter_mut().zip(audio_outputs.iter()) {
+            let frames = frames.min(buffer.len());
+            *count += buffer[0..frames].iter().filter(|smp| smp.abs() > 1.0).count();
+        }
+
+        self.frames_until_report = self.frames_until_report.saturating_sub(frames);
+    }
+
+    /// Returns `true` if enough time has passed since the last report.
+    pub fn is_report_due(&self) -> bool {
+        self.frames_until_report == 0
+    }
+
+    /// Send a message for every channel that has clipped since the last report.
+    pub fn report(&mut self, to_stream_handle_tx: &mut Producer<StreamMsg>) {
+        for (&channel, count) in self.channel_indices.iter().zip(self.clipped_counts.iter_mut()) {
+            if *count > 0 {
+                if let Err(e) =
+                    to_stream_handle_tx.push(StreamMsg::OutputClipped { channel, count: *count })
+                {
+                    log::error!("Failed to send stream message {:?}: message buffer is full!", e);
+                }
+                *count = 0;
+            }
+        }
+
+        self.frames_until_report = self.report_interval_frames;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ringbuf::{Consumer, RingBuffer};
+
+    fn drain(rx: &mut Consumer<StreamMsg>) -> Vec<(usize, usize)> {
+        let mut clipped = Vec::new();
+        while let Some(msg) = rx.pop() {
+            match msg {
+                StreamMsg::OutputClipped { channel, count } => clipped.push((channel, count)),
+                msg => panic!("unexpected message {:?}", msg),
+            }
+        }
+        clipped
+    }
+
+    /// Feeds `blocks` blocks of 100 frames, reporting whenever it is due.
+    fn run_blocks(
+        detector: &mut ClipDetector,
+        buffers: &[Vec<f32>],
+        blocks: usize,
+        tx: &mut Producer<StreamMsg>,
+    ) {
+        for _ in 0..blocks {
+            detector.process(buffers, 100);
+            if detector.is_report_due() {
+                detector.report(tx);
+            }
+        }
+    }
+
+    #[test]
+    fn clipped_output_is_reported() {
+        let (mut tx, mut rx) = RingBuffer::<StreamMsg>::new(16).split();
+        let mut detector = ClipDetector::new(2, 1000);
+
+        let loud: Vec<f32> = (0..100).map(|i| if i % 2 == 0 { 2.0 } else { -2.0 }).collect();
+        let quiet = vec![0.5; 100];
+        run_blocks(&mut detector, &[quiet, loud], 10, &mut tx);
+
+        assert_eq!(drain(&mut rx), vec![(1, 1000)]);
+    }
+
+    #[test]
+    fn samples_at_full_scale_are_not_clipped() {
+        let (mut tx, mut rx) = RingBuffer::<StreamMsg>::new(16).split();
+        let mut detector = ClipDetector::new(1, 1000);
+
+        run_blocks(&mut detector, &[vec![1.0; 100]], 10, &mut tx);
+        run_blocks(&mut detector, &[vec![-1.0; 100]], 10, &mut tx);
+
+        assert!(drain(&mut rx).is_empty());
+    }
+
+    #[test]
+    fn clip_reports_are_rate_limited() {
+        let (mut tx, mut rx) = RingBuffer::<StreamMsg>::new(16).split();
+        let mut detector = ClipDetector::new(1, 1000);
+        let loud = vec![vec![2.0; 100]];
+
+        // Nothing is reported until a full second of frames has been processed.
+        run_blocks(&mut detector, &loud, 9, &mut tx);
+        assert!(drain(&mut rx).is_empty());
+
+        // The clips of the whole second are reported in one message.
+        run_blocks(&mut detector, &loud, 1, &mut tx);
+        assert_eq!(drain(&mut rx), vec![(0, 1000)]);
+
+        // The count is reset after each report.
+        run_blocks(&mut detector, &loud, 10, &mut tx);
+        assert_eq!(drain(&mut rx), vec![(0, 1000)]);
+
+        // A second without any clipping is not reported.
+        run_blocks(&mut detector, &[vec![0.0; 100]], 10, &mut tx);
+        assert!(drain(&mut rx).is_empty());
+    }
+
+    #[test]
+    fn clips_are_reported_with_channel_indices() {
+        let (mut tx, mut rx) = RingBuffer::<StreamMsg>::new(16).split();
+        let mut detector = ClipDetector::with_channel_indices(vec![4, 7], 1000);
+
+        run_blocks(&mut detector, &[vec![0.0; 100], vec![1.5; 100]], 10, &mut tx);
+
+        assert_eq!(drain(&mut rx), vec![(7, 1000)]);
+    }
+}
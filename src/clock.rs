@@ -0,0 +1,40 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A source of the current time of a stream, in frames.
+pub trait Clock: Send + Sync {
+    /// The current time in frames since the clock was started.
+    fn now(&self) -> u64;
+}
+
+/// A clock that only advances when it is told to.
+///
+/// This is used to make timing-dependent tests (i.e. MIDI scheduling and
+/// clock drift) deterministic, by advancing the clock by exactly the number
+/// of frames in each simulated process cycle.
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    frames: AtomicU64,
+}
+
+impl ManualClock {
+    /// Create a new clock starting at frame `0`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Advance the clock by the given number of frames.
+    pub fn advance(&self, frames: u64) {
+        self.frames.fetch_add(frames, Ordering::Relaxed);
+    }
+
+    /// Set the clock to the given frame.
+    pub fn set(&self, frame: u64) {
+        self.frames.store(frame, Ordering::Relaxed);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.frames.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,148 @@
+use std::time::{Duration, Instant};
+
+use crate::RainoutConfig;
+
+/// Returns `true` if the stream has to be restarted to go from the `current`
+/// configuration to the `new` one.
+///
+/// No backend can currently change its configuration while running (see
+/// `StreamHandle::can_change_block_size()`), so this is `true` whenever the
+/// two configurations differ.
+pub fn config_requires_restart(current: &RainoutConfig, new: &RainoutConfig) -> bool {
+    current != new
+}
+
+/// Coalesces rapid changes to a configuration (i.e. while the user is dragging
+/// a buffer size slider in a settings GUI) so that the stream is only restarted
+/// once the user has stopped changing it.
+///
+/// Pass every change to `propose()`, and call `poll()` regularly (i.e. once per
+/// GUI frame). `poll()` only returns a configuration once no new one has been
+/// proposed for the quiet period, and only if it actually requires a restart.
+#[derive(Debug, Clone)]
+pub struct ConfigChangeDebouncer {
+    quiet_period: Duration,
+    current: Option<RainoutConfig>,
+    pending: Option<(RainoutConfig, Instant)>,
+}
+
+impl ConfigChangeDebouncer {
+    /// Create a new debouncer that waits for `quiet_period` after the last
+    /// change. Something around 300 milliseconds works well for sliders.
+    pub fn new(quiet_period: Duration) -> Self {
+        Self { quiet_period, current: None, pending: None }
+    }
+
+    /// Set the configuration of the stream that is currently running, so that
+    /// proposing that same configuration does not cause a restart.
+    pub fn set_current(&mut self, config: RainoutConfig) {
+        self.current = Some(config);
+    }
+
+    /// Propose a new configuration. This replaces any configuration that was
+    /// proposed before and restarts the quiet period.
+    pub fn propose(&mut self, config: RainoutConfig) {
+        self.propose_at(config, Instant::now());
+    }
+
+    fn propose_at(&mut self, config: RainoutConfig, now: Instant) {
+        self.pending = Some((config, now));
+    }
+
+    /// Returns `true` if a proposed configuration is still waiting for the quiet
+    /// period to pass.
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Returns the latest proposed configuration if the quiet period has passed
+    /// since it was proposed and it differs from the current configuration.
+    ///
+    /// The returned configuration becomes the current configuration, so restart
+    /// the stream with it.
+    pub fn poll(&mut self) -> Option<RainoutConfig> {
+        self.poll_at(Instant::now())
+    }
+
+    fn poll_at(&mut self, now: Instant) -> Option<RainoutConfig> {
+        match &self.pending {
+            Some((_, proposed_at)) if now.duration_since(*proposed_at) >= self.quiet_period => {}
+            _ => return None,
+        }
+
+        let (config, _) = self.pending.take()?;
+
+        if let Some(current) = &self.current {
+            if !config_requires_restart(current, &config) {
+                return None;
+            }
+        }
+
+        self.current = Some(config.clone());
+        Some(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AutoOption;
+
+    const QUIET_PERIOD: Duration = Duration::from_millis(300);
+
+    fn config(block_size: u32) -> RainoutConfig {
+        RainoutConfig { block_size: AutoOption::Use(block_size), ..Default::default() }
+    }
+
+    #[test]
+    fn burst_of_changes_is_emitted_once() {
+        let start = Instant::now();
+        let ms = |ms: u64| start + Duration::from_millis(ms);
+
+        let mut debouncer = ConfigChangeDebouncer::new(QUIET_PERIOD);
+        debouncer.set_current(config(512));
+
+        // The user drags a slider, proposing a new value every 50 ms.
+        for (i, block_size) in [256, 128, 64, 128, 1024].into_iter().enumerate() {
+            let now = ms(i as u64 * 50);
+            debouncer.propose_at(config(block_size), now);
+            assert_eq!(debouncer.poll_at(now), None);
+        }
+
+        // The last change was at 200 ms.
+        assert_eq!(debouncer.poll_at(ms(499)), None);
+        assert!(debouncer.is_pending());
+
+        assert_eq!(debouncer.poll_at(ms(500)), Some(config(1024)));
+        assert!(!debouncer.is_pending());
+        assert_eq!(debouncer.poll_at(ms(1000)), None);
+    }
+
+    #[test]
+    fn unchanged_config_is_not_emitted() {
+        let start = Instant::now();
+        let mut debouncer = ConfigChangeDebouncer::new(QUIET_PERIOD);
+        debouncer.set_current(config(512));
+
+        // The user drags the slider away and back again.
+        debouncer.propose_at(config(256), start);
+        debouncer.propose_at(config(512), start + Duration::from_millis(100));
+
+        assert_eq!(debouncer.poll_at(start + Duration::from_secs(1)), None);
+        assert!(!debouncer.is_pending());
+    }
+
+    #[test]
+    fn emitted_config_becomes_current() {
+        let start = Instant::now();
+        let mut debouncer = ConfigChangeDebouncer::new(QUIET_PERIOD);
+
+        // Without a current config, the first proposal is always emitted.
+        debouncer.propose_at(config(256), start);
+        assert_eq!(debouncer.poll_at(start + QUIET_PERIOD), Some(config(256)));
+
+        let later = start + Duration::from_secs(1);
+        debouncer.propose_at(config(256), later);
+        assert_eq!(debouncer.poll_at(later + QUIET_PERIOD), None);
+    }
+}
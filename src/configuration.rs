@@ -1,6 +1,7 @@
 use std::fmt::Debug;
 
-use crate::{Backend, DeviceID};
+use crate::error::ConfigError;
+use crate::{Backend, DeviceID, NativeSampleFormat, StreamInfo};
 
 #[cfg(feature = "midi")]
 use crate::MidiControlScheme;
@@ -41,8 +42,10 @@ pub struct RainoutConfig {
 
     /// The sample rate to use.
     ///
-    /// Set this to `AutoOption::Auto` to automatically select the best
-    /// sample rate to use.
+    /// Set this to `AutoOption::Auto` to use the device's native sample rate
+    /// (`AudioDeviceConfigOptions::native_sample_rate`), so that the OS does
+    /// not have to resample the stream. The sample rate that was actually used
+    /// is given in `StreamInfo::sample_rate`.
     pub sample_rate: AutoOption<u32>,
 
     /// The block/buffer size to use.
@@ -58,6 +61,18 @@ pub struct RainoutConfig {
     /// `false` on other backends and platforms.
     pub take_exclusive_access: bool,
 
+    /// The exact format to open the device with, usually the format of a
+    /// stream that was already checked with `validate_config()` (see
+    /// `ExactFormat::from_stream_info()`).
+    ///
+    /// If the device no longer supports this format, then the format is
+    /// negotiated as usual and a `ConfigWarning::ExactFormatNotUsed` is sent.
+    ///
+    /// This is only relevant for WASAPI on Windows.
+    ///
+    /// By default this is set to `None`.
+    pub exact_format: Option<ExactFormat>,
+
     #[cfg(feature = "midi")]
     /// The configuration of MIDI devices.
     ///
@@ -74,6 +89,7 @@ impl Default for RainoutConfig {
             block_size: AutoOption::Auto,
 
             take_exclusive_access: false,
+            exact_format: None,
 
             #[cfg(feature = "midi")]
             midi_config: None,
@@ -96,6 +112,10 @@ pub enum AudioDeviceConfig {
     #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
     /// When the audio backend is Jack, the names of the audio ports to use.
     ///
+    /// Client ports are only registered for the ports listed here, so a
+    /// playback-only client (i.e. a synth) or a capture-only client (i.e. a
+    /// recorder) can be created by leaving `in_ports` or `out_ports` empty.
+    ///
     /// This is only relevent when the audio backend is Jack.
     Jack {
         /// The names of the audio input ports to use.
@@ -117,14 +137,210 @@ pub enum AudioDeviceConfig {
         /// If a port with the given name does not exist, then an unconnected
         /// virtual port with that same name will be created.
         ///
+        /// The same port may appear more than once in this Vec. In that case
+        /// the buffers of each occurrence are summed together into that port.
+        ///
         /// You may also pass in an empty Vec to have no audio outputs.
         out_ports: Vec<String>,
     },
 
+    /// Use the single system-wide device of a backend that has no separate
+    /// devices to select from (i.e. Jack), connected to the default system
+    /// ports.
+    ///
+    /// This is only supported by backends where
+    /// `Backend::has_system_wide_device()` is `true`.
+    SystemWide,
+
     /// Automatically select the best configuration.
     Auto,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-config", derive(serde::Serialize, serde::Deserialize))]
+/// An exact format to open a device with.
+pub struct ExactFormat {
+    /// The sample rate
+    pub sample_rate: u32,
+    /// The sample format
+    pub sample_format: NativeSampleFormat,
+    /// Whether to take exclusive access of the device
+    pub take_exclusive_access: bool,
+}
+
+impl ExactFormat {
+    /// Get the exact format of a stream, i.e. the `StreamInfo` returned by
+    /// `validate_config()`. `take_exclusive_access` should be the same as the
+    /// config that was validated.
+    ///
+    /// This returns `None` if the backend does not report the native format of
+    /// its streams.
+    pub fn from_stream_info(stream_info: &StreamInfo, take_exclusive_access: bool) -> Option<Self> {
+        stream_info.native_format.map(|sample_format| ExactFormat {
+            sample_rate: stream_info.sample_rate,
+            sample_format,
+            take_exclusive_access,
+        })
+    }
+}
+
+/// A builder for a `RainoutConfig` that checks the config for contradictions.
+///
+/// Any option that is not set is left as `AutoOption::Auto`.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigBuilder {
+    config: RainoutConfig,
+    // The IDs of the busses added with `add_out_bus()`, and whether each one
+    // had any ports.
+    #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+    out_busses: Vec<(String, bool)>,
+}
+
+impl ConfigBuilder {
+    /// A builder where every option is set to `AutoOption::Auto` (the same as
+    /// `RainoutConfig::default()`).
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The audio backend to use.
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.config.audio_backend = AutoOption::Use(backend);
+        self
+    }
+
+    /// The audio device/devices to use.
+    pub fn device(mut self, device: AudioDeviceConfig) -> Self {
+        self.config.audio_device = device;
+        self
+    }
+
+    /// The sample rate to use.
+    pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+        self.config.sample_rate = AutoOption::Use(sample_rate);
+        self
+    }
+
+    /// The block/buffer size to use.
+    pub fn block_size(mut self, block_size: u32) -> Self {
+        self.config.block_size = AutoOption::Use(block_size);
+        self
+    }
+
+    /// Request to take exclusive access of the device.
+    pub fn take_exclusive_access(mut self, take_exclusive_access: bool) -> Self {
+        self.config.take_exclusive_access = take_exclusive_access;
+        self
+    }
+
+    #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+    /// Add a Jack audio input port with the given system port name.
+    ///
+    /// This sets the device to `AudioDeviceConfig::Jack` if it isn't already.
+    pub fn add_jack_in_port(mut self, port: impl Into<String>) -> Self {
+        self.jack_ports().0.push(port.into());
+        self
+    }
+
+    #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+    /// Add a Jack audio output port with the given system port name.
+    ///
+    /// This sets the device to `AudioDeviceConfig::Jack` if it isn't already.
+    pub fn add_jack_out_port(mut self, port: impl Into<String>) -> Self {
+        self.jack_ports().1.push(port.into());
+        self
+    }
+
+    #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+    /// Add an output bus that is connected to the given Jack system ports.
+    ///
+    /// The ports are appended to the Jack output ports in order, so the buffers
+    /// of the bus appear next to each other in `ProcInfo::audio_out`. `id` is
+    /// only used to identify the bus in a `ConfigError`. Each bus must have a
+    /// unique ID and at-least one port.
+    ///
+    /// This sets the device to `AudioDeviceConfig::Jack` if it isn't already.
+    pub fn add_out_bus<S: Into<String>>(
+        mut self,
+        id: impl Into<String>,
+        ports: impl IntoIterator<Item = S>,
+    ) -> Self {
+        let out_ports = self.jack_ports().1;
+        let num_ports = out_ports.len();
+        out_ports.extend(ports.into_iter().map(Into::into));
+        let has_ports = out_ports.len() > num_ports;
+
+        self.out_busses.push((id.into(), has_ports));
+        self
+    }
+
+    #[cfg(feature = "midi")]
+    /// The configuration of MIDI devices.
+    pub fn midi_config(mut self, midi_config: MidiConfig) -> Self {
+        self.config.midi_config = Some(midi_config);
+        self
+    }
+
+    /// Check the config and return it.
+    pub fn build(self) -> Result<RainoutConfig, ConfigError> {
+        #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+        for (i, (id, has_ports)) in self.out_busses.iter().enumerate() {
+            if self.out_busses[..i].iter().any(|(other_id, _)| other_id == id) {
+                return Err(ConfigError::DuplicateOutBus(id.clone()));
+            }
+            if !has_ports {
+                return Err(ConfigError::EmptyOutBus(id.clone()));
+            }
+        }
+
+        let config = self.config;
+
+        if let AutoOption::Use(0) = config.sample_rate {
+            return Err(ConfigError::InvalidSampleRate(0));
+        }
+        if let AutoOption::Use(0) = config.block_size {
+            return Err(ConfigError::InvalidBlockSize(0));
+        }
+
+        match &config.audio_device {
+            AudioDeviceConfig::LinkedInOut { input: None, output: None } => {
+                return Err(ConfigError::LinkedInOutHasNoDevices);
+            }
+            AudioDeviceConfig::SystemWide => {
+                if let AutoOption::Use(backend) = config.audio_backend {
+                    if !backend.has_system_wide_device() {
+                        return Err(ConfigError::NoSystemWideDevice(backend));
+                    }
+                }
+            }
+            #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+            AudioDeviceConfig::Jack { .. } => {
+                if let AutoOption::Use(backend) = config.audio_backend {
+                    if backend != Backend::Jack {
+                        return Err(ConfigError::JackDeviceWithOtherBackend(backend));
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        Ok(config)
+    }
+
+    #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+    fn jack_ports(&mut self) -> (&mut Vec<String>, &mut Vec<String>) {
+        if !matches!(self.config.audio_device, AudioDeviceConfig::Jack { .. }) {
+            self.config.audio_device =
+                AudioDeviceConfig::Jack { in_ports: Vec::new(), out_ports: Vec::new() };
+        }
+
+        match &mut self.config.audio_device {
+            AudioDeviceConfig::Jack { in_ports, out_ports } => (in_ports, out_ports),
+            _ => unreachable!(),
+        }
+    }
+}
+
 impl Default for AudioDeviceConfig {
     fn default() -> Self {
         AudioDeviceConfig::Auto
@@ -189,3 +405,131 @@ pub struct MidiPortConfig {
     /// The control scheme to use for this port
     pub control_scheme: MidiControlScheme,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_defaults_to_auto() {
+        assert_eq!(ConfigBuilder::new().build(), Ok(RainoutConfig::default()));
+    }
+
+    #[test]
+    fn builder_sets_options() {
+        let config = ConfigBuilder::new()
+            .backend(Backend::Wasapi)
+            .device(AudioDeviceConfig::Single(DeviceID {
+                name: String::from("Speakers"),
+                identifier: None,
+            }))
+            .sample_rate(48000)
+            .block_size(256)
+            .take_exclusive_access(true)
+            .build()
+            .unwrap();
+
+        assert_eq!(config.audio_backend, AutoOption::Use(Backend::Wasapi));
+        assert_eq!(config.sample_rate, AutoOption::Use(48000));
+        assert_eq!(config.block_size, AutoOption::Use(256));
+        assert!(config.take_exclusive_access);
+    }
+
+    #[test]
+    fn builder_invalid_sample_rate() {
+        assert_eq!(
+            ConfigBuilder::new().sample_rate(0).build(),
+            Err(ConfigError::InvalidSampleRate(0))
+        );
+    }
+
+    #[test]
+    fn builder_invalid_block_size() {
+        assert_eq!(
+            ConfigBuilder::new().block_size(0).build(),
+            Err(ConfigError::InvalidBlockSize(0))
+        );
+    }
+
+    #[test]
+    fn builder_linked_in_out_has_no_devices() {
+        assert_eq!(
+            ConfigBuilder::new()
+                .device(AudioDeviceConfig::LinkedInOut { input: None, output: None })
+                .build(),
+            Err(ConfigError::LinkedInOutHasNoDevices)
+        );
+    }
+
+    #[test]
+    fn builder_no_system_wide_device() {
+        assert_eq!(
+            ConfigBuilder::new()
+                .backend(Backend::Wasapi)
+                .device(AudioDeviceConfig::SystemWide)
+                .build(),
+            Err(ConfigError::NoSystemWideDevice(Backend::Wasapi))
+        );
+        assert!(ConfigBuilder::new()
+            .backend(Backend::Jack)
+            .device(AudioDeviceConfig::SystemWide)
+            .build()
+            .is_ok());
+    }
+
+    #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+    #[test]
+    fn builder_jack_device_with_other_backend() {
+        assert_eq!(
+            ConfigBuilder::new()
+                .backend(Backend::Wasapi)
+                .add_jack_out_port("system:playback_1")
+                .build(),
+            Err(ConfigError::JackDeviceWithOtherBackend(Backend::Wasapi))
+        );
+    }
+
+    #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+    #[test]
+    fn builder_out_busses() {
+        let config = ConfigBuilder::new()
+            .backend(Backend::Jack)
+            .add_out_bus("main", ["system:playback_1", "system:playback_2"])
+            .add_out_bus("cue", ["system:playback_3"])
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            config.audio_device,
+            AudioDeviceConfig::Jack {
+                in_ports: Vec::new(),
+                out_ports: vec![
+                    String::from("system:playback_1"),
+                    String::from("system:playback_2"),
+                    String::from("system:playback_3"),
+                ],
+            }
+        );
+    }
+
+    #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+    #[test]
+    fn builder_empty_out_bus() {
+        assert_eq!(
+            ConfigBuilder::new().add_out_bus("main", Vec::<String>::new()).build(),
+            Err(ConfigError::EmptyOutBus(String::from("main")))
+        );
+    }
+
+    #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+    #[test]
+    fn builder_duplicate_out_bus() {
+        assert_eq!(
+            ConfigBuilder::new()
+                .add_out_bus("main", ["system:playback_1"])
+                .add_out_bus("main", ["system:playback_2"])
+                .build(),
+            Err(ConfigError::DuplicateOutBus(String::from("main")))
+        );
+    }
+}
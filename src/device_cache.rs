@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+
+use crate::{
+    enumerate_audio_backend, enumerate_audio_device, AudioBackendOptions, AudioDeviceConfigOptions,
+    AudioDeviceOptions, Backend, DeviceID,
+};
+
+/// A cache of the probed configuration options of audio devices.
+///
+/// Probing a device for its configuration options can be slow, so this can
+/// be used to make repeated calls (i.e. every time the user clicks "Refresh"
+/// in a settings GUI) cheap when the hardware hasn't changed.
+///
+/// Entries are keyed by the backend and the identifier of the device (or the
+/// name of the device if it has no identifier).
+#[derive(Debug, Default)]
+pub struct AudioDeviceCache {
+    entries: HashMap<(Backend, String), AudioDeviceConfigOptions>,
+    hits: u64,
+    misses: u64,
+}
+
+impl AudioDeviceCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the configuration options for the given device, only probing
+    /// the device if it is not already in the cache.
+    ///
+    /// This will return an error if the backend or the device could not
+    /// be found.
+    pub fn enumerate_audio_device(
+        &mut self,
+        backend: Backend,
+        device: &DeviceID,
+    ) -> Result<AudioDeviceConfigOptions, ()> {
+        self.get_or_probe(backend, device, enumerate_audio_device)
+    }
+
+    /// `enumerate_audio_device()` with the probing of the device passed in as
+    /// `probe`.
+    fn get_or_probe(
+        &mut self,
+        backend: Backend,
+        device: &DeviceID,
+        probe: impl FnOnce(Backend, &DeviceID) -> Result<AudioDeviceConfigOptions, ()>,
+    ) -> Result<AudioDeviceConfigOptions, ()> {
+        let key = (backend, cache_key(device));
+
+        if let Some(options) = self.entries.get(&key) {
+            self.hits += 1;
+            return Ok(options.clone());
+        }
+
+        self.misses += 1;
+
+        let options = probe(backend, device)?;
+        self.entries.insert(key, options.clone());
+
+        Ok(options)
+    }
+
+    /// Re-enumerate the list of devices for the given backend.
+    ///
+    /// Any cached devices that are no longer present are removed from the
+    /// cache. If `force` is `true`, then every cached device for this backend
+    /// is removed so that they will all be probed again.
+    pub fn refresh(&mut self, backend: Backend, force: bool) -> Result<AudioBackendOptions, ()> {
+        let backend_options = enumerate_audio_backend(backend)?;
+        self.remove_stale(&backend_options, force);
+
+        Ok(backend_options)
+    }
+
+    /// Remove the cached devices of the backend that are not present in
+    /// `backend_options`, or every cached device of the backend if `force`
+    /// is `true`.
+    fn remove_stale(&mut self, backend_options: &AudioBackendOptions, force: bool) {
+        let backend = backend_options.backend;
+
+        if force {
+            self.entries.retain(|(b, _), _| *b != backend);
+        } else {
+            let present: Vec<String> = match &backend_options.device_options {
+                Some(AudioDeviceOptions::SingleDeviceOnly { options, .. }) => {
+                    options.iter().map(cache_key).collect()
+                }
+                Some(AudioDeviceOptions::LinkedInOutDevice { in_devices, out_devices, .. }) => {
+                    in_devices.iter().chain(out_devices.iter()).map(cache_key).collect()
+                }
+                #[cfg(feature = "asio")]
+                Some(AudioDeviceOptions::SingleAsioDevice { options }) => {
+                    options.iter().map(cache_key).collect()
+                }
+                _ => Vec::new(),
+            };
+
+            self.entries.retain(|(b, key), _| *b != backend || present.contains(key));
+        }
+    }
+
+    /// Remove the given device from the cache so that it will be probed again
+    /// the next time it is enumerated.
+    ///
+    /// This should be called when a stream running on this device fails.
+    pub fn invalidate(&mut self, backend: Backend, device: &DeviceID) {
+        self.entries.remove(&(backend, cache_key(device)));
+    }
+
+    /// Remove every device from the cache.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Returns statistics about this cache for debugging purposes.
+    pub fn stats(&self) -> AudioDeviceCacheStats {
+        AudioDeviceCacheStats { entries: self.entries.len(), hits: self.hits, misses: self.misses }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// Statistics about an `AudioDeviceCache`
+pub struct AudioDeviceCacheStats {
+    /// The number of devices currently in the cache
+    pub entries: usize,
+
+    /// The number of times a device was found in the cache
+    pub hits: u64,
+
+    /// The number of times a device had to be probed
+    pub misses: u64,
+}
+
+fn cache_key(device: &DeviceID) -> String {
+    device.identifier.clone().unwrap_or_else(|| device.name.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BackendStatus, ChannelLayout};
+
+    fn config_options(num_out_channels: usize) -> AudioDeviceConfigOptions {
+        AudioDeviceConfigOptions {
+            sample_rates: None,
+            native_sample_rate: Some(48_000),
+            block_sizes: None,
+            exclusive_min_period: None,
+            num_in_channels: 0,
+            num_out_channels,
+            in_channel_names: Vec::new(),
+            out_channel_names: Vec::new(),
+            in_channel_layout: ChannelLayout::Unspecified,
+            out_channel_layout: ChannelLayout::Unspecified,
+            can_take_exclusive_access: false,
+            supports_direct_monitoring: false,
+            in_jack_is_unpopulated: false,
+            out_jack_is_unpopulated: false,
+        }
+    }
+
+    fn id(name: &str, identifier: Option<&str>) -> DeviceID {
+        DeviceID { name: String::from(name), identifier: identifier.map(String::from) }
+    }
+
+    fn backend_options(backend: Backend, devices: Vec<DeviceID>) -> AudioBackendOptions {
+        AudioBackendOptions {
+            backend,
+            version: None,
+            status: BackendStatus::Running,
+            device_options: Some(AudioDeviceOptions::SingleDeviceOnly {
+                options: devices,
+                default_device: None,
+            }),
+        }
+    }
+
+    /// Probes the device through `cache`, counting how many times it was
+    /// actually probed.
+    fn probe(
+        cache: &mut AudioDeviceCache,
+        backend: Backend,
+        device: &DeviceID,
+        num_probes: &mut usize,
+    ) -> Result<AudioDeviceConfigOptions, ()> {
+        cache.get_or_probe(backend, device, |_, _| {
+            *num_probes += 1;
+            Ok(config_options(2))
+        })
+    }
+
+    #[test]
+    fn devices_are_only_probed_once() {
+        let mut cache = AudioDeviceCache::new();
+        let speakers = id("Speakers", Some("{1}"));
+        let mut num_probes = 0;
+
+        for _ in 0..3 {
+            assert_eq!(
+                probe(&mut cache, Backend::Wasapi, &speakers, &mut num_probes),
+                Ok(config_options(2))
+            );
+        }
+
+        assert_eq!(num_probes, 1);
+        assert_eq!(cache.stats(), AudioDeviceCacheStats { entries: 1, hits: 2, misses: 1 });
+    }
+
+    #[test]
+    fn devices_are_keyed_by_backend_and_identifier() {
+        let mut cache = AudioDeviceCache::new();
+        let mut num_probes = 0;
+
+        probe(&mut cache, Backend::Wasapi, &id("Speakers", Some("{1}")), &mut num_probes).unwrap();
+
+        // A renamed device with the same identifier is still cached.
+        probe(&mut cache, Backend::Wasapi, &id("Lautsprecher", Some("{1}")), &mut num_probes)
+            .unwrap();
+        assert_eq!(num_probes, 1);
+
+        // The same device on another backend is probed again.
+        probe(&mut cache, Backend::Asio, &id("Speakers", Some("{1}")), &mut num_probes).unwrap();
+        assert_eq!(num_probes, 2);
+
+        // A device without an identifier is keyed by its name.
+        probe(&mut cache, Backend::Alsa, &id("hw:0", None), &mut num_probes).unwrap();
+        probe(&mut cache, Backend::Alsa, &id("hw:0", None), &mut num_probes).unwrap();
+        probe(&mut cache, Backend::Alsa, &id("hw:1", None), &mut num_probes).unwrap();
+        assert_eq!(num_probes, 4);
+    }
+
+    #[test]
+    fn failed_probes_are_not_cached() {
+        let mut cache = AudioDeviceCache::new();
+        let speakers = id("Speakers", Some("{1}"));
+
+        assert_eq!(cache.get_or_probe(Backend::Wasapi, &speakers, |_, _| Err(())), Err(()));
+
+        let mut num_probes = 0;
+        probe(&mut cache, Backend::Wasapi, &speakers, &mut num_probes).unwrap();
+        assert_eq!(num_probes, 1);
+        assert_eq!(cache.stats(), AudioDeviceCacheStats { entries: 1, hits: 0, misses: 2 });
+    }
+
+    #[test]
+    fn invalidated_devices_are_probed_again() {
+        let mut cache = AudioDeviceCache::new();
+        let speakers = id("Speakers", Some("{1}"));
+        let headphones = id("Headphones", Some("{2}"));
+        let mut num_probes = 0;
+
+        probe(&mut cache, Backend::Wasapi, &speakers, &mut num_probes).unwrap();
+        probe(&mut cache, Backend::Wasapi, &headphones, &mut num_probes).unwrap();
+
+        cache.invalidate(Backend::Wasapi, &speakers);
+        probe(&mut cache, Backend::Wasapi, &speakers, &mut num_probes).unwrap();
+        probe(&mut cache, Backend::Wasapi, &headphones, &mut num_probes).unwrap();
+        assert_eq!(num_probes, 3);
+
+        cache.clear();
+        assert_eq!(cache.stats().entries, 0);
+        probe(&mut cache, Backend::Wasapi, &speakers, &mut num_probes).unwrap();
+        assert_eq!(num_probes, 4);
+    }
+
+    #[test]
+    fn refresh_removes_devices_that_are_gone() {
+        let mut cache = AudioDeviceCache::new();
+        let speakers = id("Speakers", Some("{1}"));
+        let headphones = id("Headphones", Some("{2}"));
+        let asio_device = id("Speakers", Some("{1}"));
+        let mut num_probes = 0;
+
+        probe(&mut cache, Backend::Wasapi, &speakers, &mut num_probes).unwrap();
+        probe(&mut cache, Backend::Wasapi, &headphones, &mut num_probes).unwrap();
+        probe(&mut cache, Backend::Asio, &asio_device, &mut num_probes).unwrap();
+
+        // The headphones were unplugged.
+        cache.remove_stale(&backend_options(Backend::Wasapi, vec![speakers.clone()]), false);
+        assert_eq!(cache.stats().entries, 2);
+
+        probe(&mut cache, Backend::Wasapi, &speakers, &mut num_probes).unwrap();
+        probe(&mut cache, Backend::Asio, &asio_device, &mut num_probes).unwrap();
+        assert_eq!(num_probes, 3);
+        probe(&mut cache, Backend::Wasapi, &headphones, &mut num_probes).unwrap();
+        assert_eq!(num_probes, 4);
+    }
+
+    #[test]
+    fn forced_refresh_removes_every_device_of_the_backend() {
+        let mut cache = AudioDeviceCache::new();
+        let speakers = id("Speakers", Some("{1}"));
+        let mut num_probes = 0;
+
+        probe(&mut cache, Backend::Wasapi, &speakers, &mut num_probes).unwrap();
+        probe(&mut cache, Backend::Asio, &speakers, &mut num_probes).unwrap();
+
+        cache.remove_stale(&backend_options(Backend::Wasapi, vec![speakers.clone()]), true);
+        assert_eq!(cache.stats().entries, 1);
+
+        probe(&mut cache, Backend::Asio, &speakers, &mut num_probes).unwrap();
+        assert_eq!(num_probes, 2);
+        probe(&mut cache, Backend::Wasapi, &speakers, &mut num_probes).unwrap();
+        assert_eq!(num_probes, 3);
+    }
+}
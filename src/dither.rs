@@ -0,0 +1,130 @@
+use crate::DitherMode;
+
+/// Converts `f32` samples to `i16` samples with dither.
+///
+/// This never allocates, so it is safe to use on the audio thread.
+pub(crate) struct Ditherer {
+    mode: DitherMode,
+    // The state of the xorshift random number generator. This is never zero.
+    rng_state: u32,
+}
+
+impl Ditherer {
+    pub fn new(mode: DitherMode, seed: u32) -> Self {
+        Self { mode, rng_state: seed.max(1) }
+    }
+
+    /// Returns a random number in the range `[-0.5, 0.5)`.
+    #[inline]
+    fn next_noise(&mut self) -> f32 {
+        // xorshift32
+        let mut x = self.rng_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng_state = x;
+
+        (x >> 8) as f32 / (1u32 << 24) as f32 - 0.5
+    }
+
+    /// Convert a sample in the range `[-1.0, 1.0]` to an `i16` sample.
+    ///
+    /// `NaN` is converted to silence, and samples outside of the range are
+    /// clamped.
+    #[inline]
+    pub fn f32_to_i16(&mut self, sample: f32) -> i16 {
+        let sample = if sample.is_nan() { 0.0 } else { sample };
+
+        // The dither noise is in units of the least significant bit.
+        let noise = match self.mode {
+            DitherMode::None => 0.0,
+            DitherMode::Rectangular => self.next_noise(),
+            DitherMode::Tpdf => self.next_noise() + self.next_noise(),
+        };
+
+        (sample * 32_767.0 + noise).round().clamp(-32_768.0, 32_767.0) as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LSB: f32 = 1.0 / 32_767.0;
+    const NUM_SAMPLES: usize = 100_000;
+
+    /// Returns the mean and variance of the rounding error (in units of the
+    /// least significant bit) when converting the constant `lsbs`.
+    fn error_stats(mode: DitherMode, lsbs: f32) -> (f64, f64) {
+        let mut ditherer = Ditherer::new(mode, 12345);
+        let errors: Vec<f64> = (0..NUM_SAMPLES)
+            .map(|_| f64::from(ditherer.f32_to_i16(lsbs * LSB)) - f64::from(lsbs))
+            .collect();
+
+        let mean = errors.iter().sum::<f64>() / NUM_SAMPLES as f64;
+        let variance = errors.iter().map(|e| (e - mean).powi(2)).sum::<f64>() / NUM_SAMPLES as f64;
+        (mean, variance)
+    }
+
+    #[test]
+    fn no_dither_rounds_exactly() {
+        let mut ditherer = Ditherer::new(DitherMode::None, 12345);
+
+        for i in -32_767..=32_767 {
+            assert_eq!(ditherer.f32_to_i16(i as f32 * LSB), i as i16);
+        }
+        assert_eq!(ditherer.f32_to_i16(0.4 * LSB), 0);
+        assert_eq!(ditherer.f32_to_i16(0.6 * LSB), 1);
+        assert_eq!(ditherer.f32_to_i16(-0.6 * LSB), -1);
+        assert_eq!(ditherer.f32_to_i16(1.0), 32_767);
+        assert_eq!(ditherer.f32_to_i16(-1.0), -32_767);
+        assert_eq!(ditherer.f32_to_i16(2.0), 32_767);
+        assert_eq!(ditherer.f32_to_i16(-2.0), -32_768);
+        assert_eq!(ditherer.f32_to_i16(f32::NAN), 0);
+    }
+
+    #[test]
+    fn no_dither_error_depends_on_the_signal() {
+        // A signal below half of the least significant bit is lost entirely.
+        let (mean, variance) = error_stats(DitherMode::None, 0.3);
+        assert!((mean + 0.3).abs() < 1e-3);
+        assert!(variance < 1e-6);
+    }
+
+    #[test]
+    fn tpdf_dither_error_is_decorrelated_from_the_signal() {
+        // The error has a mean of zero and a variance of 1/4 LSB^2 no matter
+        // the level of the signal.
+        for lsbs in [0.0, 0.1, 0.25, 0.3, 0.5, 0.75, 7.5, -3.2] {
+            let (mean, variance) = error_stats(DitherMode::Tpdf, lsbs);
+            assert!(mean.abs() < 0.01, "mean error {} at {} LSB", mean, lsbs);
+            assert!((variance - 0.25).abs() < 0.01, "error variance {} at {} LSB", variance, lsbs);
+        }
+
+        // The error is uncorrelated with a quiet sine wave.
+        let mut ditherer = Ditherer::new(DitherMode::Tpdf, 12345);
+        let mut correlation = 0.0;
+        let mut signal_power = 0.0;
+        for i in 0..NUM_SAMPLES {
+            let signal = 2.0 * (i as f64 * 0.01).sin();
+            let error = f64::from(ditherer.f32_to_i16(signal as f32 * LSB)) - signal;
+            correlation += error * signal;
+            signal_power += signal * signal;
+        }
+        assert!((correlation / signal_power).abs() < 0.01);
+    }
+
+    #[test]
+    fn rectangular_dither_removes_the_mean_error() {
+        for lsbs in [0.0, 0.1, 0.3, 0.5, -3.2] {
+            let (mean, _) = error_stats(DitherMode::Rectangular, lsbs);
+            assert!(mean.abs() < 0.01, "mean error {} at {} LSB", mean, lsbs);
+        }
+
+        // But the level of the noise still depends on the signal.
+        let (_, variance_at_0) = error_stats(DitherMode::Rectangular, 0.0);
+        let (_, variance_at_half) = error_stats(DitherMode::Rectangular, 0.5);
+        assert!(variance_at_0 < 0.01);
+        assert!((variance_at_half - 0.25).abs() < 0.01);
+    }
+}
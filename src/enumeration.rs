@@ -1,3 +1,7 @@
+use std::cmp::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
 use crate::Backend;
 
 /// Returns the list available audio backends for this platform.
@@ -35,6 +39,9 @@ pub fn available_midi_backends() -> &'static [Backend] {
 
 /// Returns the list of available audio devices for the given backend.
 ///
+/// This may be called from any thread. On Windows, COM is initialized on the
+/// calling thread if it has not been initialized already.
+///
 /// This will return an error if the backend with the given name could
 /// not be found.
 pub fn enumerate_audio_backend(backend: Backend) -> Result<AudioBackendOptions, ()> {
@@ -81,8 +88,45 @@ pub fn enumerate_audio_backend(backend: Backend) -> Result<AudioBackendOptions,
     }
 }
 
+/// Enumerate the given backend on a new thread.
+///
+/// Enumerating a backend can take a while on some backends (i.e. up to several
+/// seconds for WASAPI with many endpoints), so this can be used to avoid
+/// blocking the GUI thread. Poll the returned handle with
+/// `JoinHandle::is_finished()` and then call `join()` to get the result.
+///
+/// This will return an error if the thread could not be spawned.
+pub fn enumerate_audio_backend_async(
+    backend: Backend,
+) -> std::io::Result<std::thread::JoinHandle<Result<AudioBackendOptions, ()>>> {
+    std::thread::Builder::new()
+        .name(String::from("rainout enumeration"))
+        .spawn(move || enumerate_audio_backend(backend))
+}
+
+/// Returns the list of available audio devices for the given backend, with the
+/// given filter applied (see `EnumerationFilter`).
+///
+/// This will return an error if the backend with the given name could
+/// not be found.
+pub fn enumerate_audio_backend_filtered(
+    backend: Backend,
+    filter: &EnumerationFilter,
+) -> Result<AudioBackendOptions, ()> {
+    let mut options = enumerate_audio_backend(backend)?;
+
+    if let Some(device_options) = &mut options.device_options {
+        filter.apply(device_options);
+    }
+
+    Ok(options)
+}
+
 /// Returns the configuration options for the given device.
 ///
+/// This may be called from any thread. Note that probing a device can be
+/// slow on some backends (i.e. WASAPI opens a client for the device).
+///
 /// This will return an error if the backend or the device could not
 /// be found.
 #[allow(unused_variables)]
@@ -213,6 +257,11 @@ pub enum BackendStatus {
     /// The backend is installed and running with available devices
     Running,
 
+    /// The backend is installed and running with available devices, but the
+    /// system has no default device. The user should be prompted to pick a
+    /// device instead of one being selected automatically.
+    RunningNoDefault,
+
     /// The backend is installed and running, but no devices were found
     NoDevices,
 
@@ -243,10 +292,31 @@ pub struct AudioBackendOptions {
     /// The available audio devices to select from.
     ///
     /// This will be `None` if this backend's `status` is not of the type
-    /// `BackendStatus::Running`.
+    /// `BackendStatus::Running` or `BackendStatus::RunningNoDefault`.
     pub device_options: Option<AudioDeviceOptions>,
 }
 
+impl AudioBackendOptions {
+    /// Returns `true` if every stream of this backend calls `process()` with
+    /// the same number of frames every cycle (`AudioBufferStreamInfo::FixedSized`).
+    ///
+    /// This is decided by the enumerated devices. The system-wide Jack device
+    /// and ASIO devices always use a fixed buffer size. With other backends
+    /// this depends on the device and the share mode, so this returns `false`.
+    /// For example, WASAPI only uses a fixed period in exclusive mode, where
+    /// `AudioDeviceConfigOptions::buffer_size_info()` returns a
+    /// `AudioBufferSizeInfo::FixedList`.
+    pub fn supports_fixed_buffer_size(&self) -> bool {
+        match &self.device_options {
+            #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+            Some(AudioDeviceOptions::JackSystemWideDevice) => true,
+            #[cfg(feature = "asio")]
+            Some(AudioDeviceOptions::SingleAsioDevice { .. }) => true,
+            _ => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 /// The available audio devices to select from
 pub enum AudioDeviceOptions {
@@ -256,6 +326,11 @@ pub enum AudioDeviceOptions {
     SingleDeviceOnly {
         /// The available audio devices to select from.
         options: Vec<DeviceID>,
+
+        /// The index of the system's default device in `options`.
+        ///
+        /// This will be `None` if the system has no default device.
+        default_device: Option<usize>,
     },
 
     /// A single input and output device pair can be selected from this list.
@@ -267,10 +342,21 @@ pub enum AudioDeviceOptions {
         in_devices: Vec<DeviceID>,
         /// The names/IDs of the available output devices to select from
         out_devices: Vec<DeviceID>,
+
+        /// The index of the system's default input device in `in_devices`
+        /// (if there is one)
+        default_in_device: Option<usize>,
+        /// The index of the system's default output device in `out_devices`
+        /// (if there is one)
+        default_out_device: Option<usize>,
     },
 
     #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
-    /// There is a single "monolithic" system-wide Jack audio device
+    /// There is a single "monolithic" system-wide Jack audio device, which is
+    /// always the default device.
+    ///
+    /// Use `AudioDeviceConfig::SystemWide` to select this device, or
+    /// `AudioDeviceConfig::Jack` to select specific ports.
     JackSystemWideDevice,
 
     #[cfg(feature = "asio")]
@@ -281,6 +367,242 @@ pub enum AudioDeviceOptions {
     },
 }
 
+impl AudioDeviceOptions {
+    /// Returns the index of the device that should be selected by default,
+    /// along with the reason why it was chosen. For `LinkedInOutDevice`, this
+    /// is the index into `out_devices`.
+    ///
+    /// If the system does not specify a default device, then the device that
+    /// is most likely to be a real hardware device is chosen, based on its
+    /// name (i.e. "null", "dmix", and "loopback" devices are avoided). For
+    /// `LinkedInOutDevice`, an output device that is also listed as an input
+    /// device (a duplex device) is preferred over an output-only device.
+    ///
+    /// This will return `None` if there are no devices to select from.
+    pub fn preferred_default_device(&self) -> Option<(usize, DefaultDeviceReason)> {
+        let (options, in_devices, system_default): (_, &[DeviceID], _) = match self {
+            AudioDeviceOptions::SingleDeviceOnly { options, default_device } => {
+                (options, &[], *default_device)
+            }
+            AudioDeviceOptions::LinkedInOutDevice {
+                in_devices,
+                out_devices,
+                default_out_device,
+                ..
+            } => (out_devices, in_devices, *default_out_device),
+            #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+            AudioDeviceOptions::JackSystemWideDevice => return None,
+            #[cfg(feature = "asio")]
+            AudioDeviceOptions::SingleAsioDevice { options } => (options, &[], None),
+        };
+
+        if let Some(i) = system_default {
+            return Some((i, DefaultDeviceReason::SystemDefault));
+        }
+
+        // Pick the device with the best score. The first device wins a tie.
+        let mut best: Option<(usize, i32)> = None;
+        for (i, id) in options.iter().enumerate() {
+            let mut score = default_device_score(id);
+            if score >= 0 && in_devices.contains(id) {
+                score += 1;
+            }
+
+            if best.map(|(_, best_score)| score > best_score).unwrap_or(true) {
+                best = Some((i, score));
+            }
+        }
+
+        best.map(|(i, score)| {
+            let reason = if score < 0 {
+                DefaultDeviceReason::OnlyVirtualDevices
+            } else {
+                DefaultDeviceReason::BestGuess
+            };
+            (i, reason)
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Why a device was chosen as the default device
+pub enum DefaultDeviceReason {
+    /// The system reported this device as its default device.
+    SystemDefault,
+
+    /// The system has no default device, so this device was chosen because it
+    /// looks like a real hardware device.
+    BestGuess,
+
+    /// The system has no default device, and every device looks like a
+    /// virtual device (i.e. "null" or "loopback"), so the first one was
+    /// chosen.
+    OnlyVirtualDevices,
+}
+
+type DeviceFilterFn = dyn Fn(&DeviceID) -> bool + Send + Sync;
+type DeviceCompareFn = dyn Fn(&DeviceID, &DeviceID) -> Ordering + Send + Sync;
+
+#[derive(Clone, Default)]
+/// Filters and orders the devices returned by
+/// `enumerate_audio_backend_filtered()`, so that a host with a curated device
+/// list doesn't have to do this itself after every enumeration.
+///
+/// The index of the system's default device is updated to match the new list,
+/// and is `None` if the default device was filtered out.
+pub struct EnumerationFilter {
+    keep: Option<Arc<DeviceFilterFn>>,
+    compare: Option<Arc<DeviceCompareFn>>,
+}
+
+impl EnumerationFilter {
+    /// A filter that keeps every device in the order that the backend
+    /// returned them.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A filter that hides devices that look like virtual devices (i.e.
+    /// "null", "loopback", and "virtual cable" devices), based on their name.
+    pub fn hide_virtual_devices() -> Self {
+        Self::new().with_keep(|id| default_device_score(id) >= 0)
+    }
+
+    /// A filter that only keeps devices that look like physical hardware,
+    /// based on their name.
+    ///
+    /// This is stricter than `hide_virtual_devices()`, and also hides devices
+    /// that route to whichever hardware the system picks, like ALSA's
+    /// "default" and "plughw" devices, or the "PipeWire" and "JACK" devices
+    /// that forward to a sound server.
+    pub fn physical_only() -> Self {
+        Self::new().with_keep(|id| {
+            !name_matches(id, &VIRTUAL_DEVICE_NAMES) && !name_matches(id, &ROUTING_DEVICE_NAMES)
+        })
+    }
+
+    /// Only keep the devices for which `keep` returns `true`.
+    pub fn with_keep(mut self, keep: impl Fn(&DeviceID) -> bool + Send + Sync + 'static) -> Self {
+        self.keep = Some(Arc::new(keep));
+        self
+    }
+
+    /// Sort the devices with the given comparator. The sort is stable, so
+    /// devices that compare equal stay in the order the backend returned them.
+    pub fn with_sort(
+        mut self,
+        compare: impl Fn(&DeviceID, &DeviceID) -> Ordering + Send + Sync + 'static,
+    ) -> Self {
+        self.compare = Some(Arc::new(compare));
+        self
+    }
+
+    /// Apply this filter to the given device options.
+    pub fn apply(&self, options: &mut AudioDeviceOptions) {
+        match options {
+            AudioDeviceOptions::SingleDeviceOnly { options, default_device } => {
+                self.apply_to_list(options, default_device);
+            }
+            AudioDeviceOptions::LinkedInOutDevice {
+                in_devices,
+                out_devices,
+                default_in_device,
+                default_out_device,
+            } => {
+                self.apply_to_list(in_devices, default_in_device);
+                self.apply_to_list(out_devices, default_out_device);
+            }
+            #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+            AudioDeviceOptions::JackSystemWideDevice => {}
+            #[cfg(feature = "asio")]
+            AudioDeviceOptions::SingleAsioDevice { options } => {
+                self.apply_to_list(options, &mut None);
+            }
+        }
+    }
+
+    fn apply_to_list(&self, devices: &mut Vec<DeviceID>, default_device: &mut Option<usize>) {
+        let default_id = default_device.and_then(|i| devices.get(i).cloned());
+
+        if let Some(keep) = &self.keep {
+            devices.retain(|id| keep(id));
+        }
+        if let Some(compare) = &self.compare {
+            devices.sort_by(|a, b| compare(a, b));
+        }
+
+        *default_device =
+            default_id.and_then(|default_id| devices.iter().position(|id| *id == default_id));
+    }
+}
+
+impl std::fmt::Debug for EnumerationFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EnumerationFilter")
+            .field("keep", &self.keep.is_some())
+            .field("compare", &self.compare.is_some())
+            .finish()
+    }
+}
+
+/// Sequences of words in device names that indicate that a device is a
+/// virtual device rather than real hardware.
+///
+/// These are matched against whole words, so that real hardware like "Studio
+/// Monitors" or "USB Cable Adapter" is not mistaken for a PulseAudio monitor
+/// source ("Monitor of ...") or a virtual cable ("CABLE Input").
+const VIRTUAL_DEVICE_NAMES: [&[&str]; 12] = [
+    &["null"],
+    &["dummy"],
+    &["dmix"],
+    &["dsnoop"],
+    &["pulse"],
+    &["pulseaudio"],
+    &["loopback"],
+    &["monitor", "of"],
+    &["virtual"],
+    &["vb", "audio"],
+    &["cable", "input"],
+    &["cable", "output"],
+];
+
+/// Sequences of words in device names that indicate that a device is not
+/// itself hardware, but routes audio to whichever hardware the system (or a
+/// sound server) picks.
+const ROUTING_DEVICE_NAMES: [&[&str]; 11] = [
+    &["default"],
+    &["sysdefault"],
+    &["plughw"],
+    &["pipewire"],
+    &["jack"],
+    &["upmix"],
+    &["vdownmix"],
+    &["samplerate"],
+    &["speexrate"],
+    &["sound", "mapper"],
+    &["primary", "sound", "driver"],
+];
+
+/// Returns `true` if the name of the device contains any of the given
+/// sequences of (lowercase) words.
+fn name_matches(id: &DeviceID, patterns: &[&[&str]]) -> bool {
+    let name = id.name.to_lowercase();
+    let words: Vec<&str> =
+        name.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()).collect();
+
+    patterns.iter().any(|pattern| words.windows(pattern.len()).any(|w| w == *pattern))
+}
+
+/// A score for how likely a device is to be a useful default device. Devices
+/// that look like virtual devices get a negative score.
+fn default_device_score(id: &DeviceID) -> i32 {
+    if name_matches(id, &VIRTUAL_DEVICE_NAMES) {
+        -1
+    } else {
+        0
+    }
+}
+
 #[derive(Debug, Clone, Hash)]
 #[cfg_attr(feature = "serde-config", derive(serde::Serialize, serde::Deserialize))]
 /// The name/ID of a device
@@ -307,14 +629,21 @@ impl PartialEq for DeviceID {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 /// The available configuration options for the audio device/devices
 pub struct AudioDeviceConfigOptions {
     /// The available sample rates to choose from.
     ///
     /// If the available sample rates could not be determined at this time,
     /// then this will be `None`.
-    pub sample_rates: Option<Vec<u32>>,
+    pub sample_rates: Option<SampleRateOptions>,
+
+    /// The device's native (preferred) sample rate, which is what
+    /// `AutoOption::Auto` resolves to. Running at any other rate may make the
+    /// OS resample the stream.
+    ///
+    /// This will be `None` if the backend can't query it.
+    pub native_sample_rate: Option<u32>,
 
     /// The available range of fixed block/buffer sizes
     ///
@@ -322,11 +651,34 @@ pub struct AudioDeviceConfigOptions {
     /// will be `None`.
     pub block_sizes: Option<BlockSizeRange>,
 
+    /// The minimum period of the device when taking exclusive access of the
+    /// device.
+    ///
+    /// This is only relevant for WASAPI on Windows, where exclusive mode only
+    /// accepts block sizes that are multiples of this period. The number of
+    /// frames in a period depends on the sample rate, so use
+    /// `buffer_size_info()` to get the block sizes at a given sample rate. This
+    /// will be `None` if `can_take_exclusive_access` is `false`.
+    pub exclusive_min_period: Option<Duration>,
+
     /// The number of input audio channels
     pub num_in_channels: usize,
     /// The number of output audio channels
     pub num_out_channels: usize,
 
+    /// The name of each input audio channel (i.e. "Mic/Line 1" or "Front
+    /// Left"). This always has `num_in_channels` entries.
+    ///
+    /// If the device does not provide a name for a channel, then it will be
+    /// named "Input N" (starting from 1).
+    pub in_channel_names: Vec<String>,
+    /// The name of each output audio channel (i.e. "ADAT 1" or "Front Left").
+    /// This always has `num_out_channels` entries.
+    ///
+    /// If the device does not provide a name for a channel, then it will be
+    /// named "Output N" (starting from 1).
+    pub out_channel_names: Vec<String>,
+
     /// The layout of the input audio channels
     pub in_channel_layout: ChannelLayout,
     /// The layout of the output audio channels
@@ -339,6 +691,16 @@ pub struct AudioDeviceConfigOptions {
     /// `false` on other backends and platforms.
     pub can_take_exclusive_access: bool,
 
+    // TODO: Report the clock sources that the device can sync to (internal clock,
+    // word clock, ADAT, S/PDIF) once a backend can query them (CoreAudio's
+    // `kAudioDevicePropertyClockSource`, or ASIO's clock sources), and let the
+    // config select one.
+    /// Whether or not the device can route its inputs directly to its outputs
+    /// in hardware (see `StreamHandle::set_direct_monitoring()`).
+    ///
+    /// No backend currently supports this, so this is always `false`.
+    pub supports_direct_monitoring: bool,
+
     /// If this is `true`, then it means that the audio device is active,
     /// but there is no mic plugged into the device's hardware jack. This can
     /// be used to show a warning to the user that they need to plug something
@@ -360,6 +722,74 @@ pub struct AudioDeviceConfigOptions {
     pub out_jack_is_unpopulated: bool,
 }
 
+impl AudioDeviceConfigOptions {
+    /// Returns `true` if there is more than one block/buffer size to choose
+    /// from for this device, either in `block_sizes` or in exclusive mode (at
+    /// the native sample rate).
+    ///
+    /// This can be used to decide whether to show a block size selector in a
+    /// settings GUI.
+    pub fn buffer_size_is_configurable(&self) -> bool {
+        let in_range = self.block_sizes.as_ref().map(|r| r.min != r.max).unwrap_or(false);
+        let in_exclusive_list = match (self.exclusive_min_period, self.native_sample_rate) {
+            (Some(min_period), Some(sample_rate)) => {
+                exclusive_block_sizes(min_period, sample_rate).len() > 1
+            }
+            _ => false,
+        };
+
+        in_range || in_exclusive_list
+    }
+
+    /// Returns the block/buffer sizes that can be used with this device at the
+    /// given sample rate.
+    ///
+    /// If `exclusive` is `true` and the device has an exclusive mode period,
+    /// then the multiples of that period at `sample_rate` are returned as a
+    /// `AudioBufferSizeInfo::FixedList`. Otherwise the range of fixed sizes is
+    /// returned (if there is one).
+    pub fn buffer_size_info(&self, exclusive: bool, sample_rate: u32) -> AudioBufferSizeInfo {
+        match (self.exclusive_min_period, &self.block_sizes) {
+            (Some(min_period), _) if exclusive => {
+                AudioBufferSizeInfo::FixedList(exclusive_block_sizes(min_period, sample_rate))
+            }
+            (_, Some(range)) => AudioBufferSizeInfo::Range(range.clone()),
+            _ => AudioBufferSizeInfo::Unknown,
+        }
+    }
+}
+
+/// The largest block size to offer in exclusive mode.
+const MAX_EXCLUSIVE_BLOCK_SIZE: u32 = 4096;
+
+/// Returns the block sizes that can be used in exclusive mode at the given
+/// sample rate, which are the multiples of the minimum device period (up to
+/// `MAX_EXCLUSIVE_BLOCK_SIZE`, or just the minimum period if that is larger).
+fn exclusive_block_sizes(min_period: Duration, sample_rate: u32) -> Vec<u32> {
+    let min_frames = (min_period.as_nanos() * u128::from(sample_rate) / 1_000_000_000) as u32;
+    if min_frames == 0 {
+        return Vec::new();
+    }
+
+    (1..)
+        .map(|i| min_frames * i)
+        .take_while(|frames| *frames <= MAX_EXCLUSIVE_BLOCK_SIZE.max(min_frames))
+        .collect()
+}
+
+/// The name to use for a channel when the device does not provide one.
+///
+/// The channel index starts from 0, but the returned name starts from 1 (i.e.
+/// "Output 1").
+#[allow(dead_code)] // Only used by some backends.
+pub(crate) fn fallback_channel_name(is_input: bool, channel: usize) -> String {
+    if is_input {
+        format!("Input {}", channel + 1)
+    } else {
+        format!("Output {}", channel + 1)
+    }
+}
+
 #[non_exhaustive]
 #[derive(Debug, Clone, PartialEq)]
 /// The channel layout of the audio ports
@@ -384,8 +814,37 @@ pub enum ChannelLayout {
     // TODO: More channel layouts
 }
 
+#[derive(Debug, Clone, PartialEq)]
+/// The available sample rates of an audio device
+pub enum SampleRateOptions {
+    /// Only these exact sample rates can be used.
+    List(Vec<u32>),
+
+    /// Any sample rate within this range can be used, including ones that
+    /// are not listed in `common`.
+    Range {
+        /// The minimum sample rate that can be used (inclusive)
+        min: u32,
+        /// The maximum sample rate that can be used (inclusive)
+        max: u32,
+        /// The common sample rates within this range (i.e. 44100 and 48000),
+        /// which can be used to populate a list in a settings GUI.
+        common: Vec<u32>,
+    },
+}
+
+impl SampleRateOptions {
+    /// Returns `true` if the given sample rate can be used.
+    pub fn contains(&self, sample_rate: u32) -> bool {
+        match self {
+            SampleRateOptions::List(list) => list.contains(&sample_rate),
+            SampleRateOptions::Range { min, max, .. } => sample_rate >= *min && sample_rate <= *max,
+        }
+    }
+}
+
 /// The range of possible block sizes for an audio device.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct BlockSizeRange {
     /// The minimum buffer/block size that can be used (inclusive)
     pub min: u32,
@@ -397,6 +856,153 @@ pub struct BlockSizeRange {
     pub default: u32,
 }
 
+impl BlockSizeRange {
+    /// Returns the block size in this range that is closest to the given
+    /// target latency in milliseconds.
+    ///
+    /// If `power_of_two` is `true`, then this will snap to the nearest power
+    /// of two within this range (if there is one). Pass
+    /// `Backend::prefers_power_of_two_block_sizes()` to do this only when the
+    /// backend works best with these sizes.
+    ///
+    /// If the sample rate is unknown (`0`) or `target_ms` is not a valid
+    /// number, then this will return the default block size.
+    pub fn block_size_for_latency(
+        &self,
+        sample_rate: u32,
+        target_ms: f64,
+        power_of_two: bool,
+    ) -> u32 {
+        if sample_rate == 0 || !target_ms.is_finite() {
+            return self.default;
+        }
+
+        let frames = (target_ms / 1_000.0 * f64::from(sample_rate)).round() as u32;
+        let frames = frames.max(self.min).min(self.max);
+
+        if !power_of_two {
+            return frames;
+        }
+
+        self.snap_to_power_of_two(frames)
+    }
+
+    /// Returns the block size in this range that is closest to the default
+    /// block size for the given usage profile (see `default_buffer_size_for()`).
+    ///
+    /// This will snap to the nearest power of two within this range (if there
+    /// is one).
+    pub fn block_size_for_profile(&self, profile: UsageProfile) -> u32 {
+        let frames = default_buffer_size_for(profile).max(self.min).min(self.max);
+
+        self.snap_to_power_of_two(frames)
+    }
+
+    /// Snap the given block size (which must already be within this range) to
+    /// the nearest power of two within this range.
+    fn snap_to_power_of_two(&self, frames: u32) -> u32 {
+        if frames == 0 || frames.is_power_of_two() {
+            return frames;
+        }
+
+        let lower = 1u32 << (31 - frames.leading_zeros());
+        let upper = lower.checked_mul(2);
+
+        let lower = Some(lower).filter(|s| *s >= self.min);
+        let upper = upper.filter(|s| *s <= self.max);
+
+        match (lower, upper) {
+            (Some(lower), Some(upper)) => {
+                if frames - lower <= upper - frames {
+                    lower
+                } else {
+                    upper
+                }
+            }
+            (Some(lower), None) => lower,
+            (None, Some(upper)) => upper,
+            (None, None) => frames,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The block/buffer sizes that a device accepts (see
+/// `AudioDeviceConfigOptions::buffer_size_info()`)
+pub enum AudioBufferSizeInfo {
+    /// Any block size within this range can be used.
+    Range(BlockSizeRange),
+    /// Only the block sizes in this list can be used (i.e. WASAPI in exclusive
+    /// mode).
+    FixedList(Vec<u32>),
+    /// The supported block sizes are not known.
+    Unknown,
+}
+
+/// Returns the supported block size that is closest to the given target
+/// latency in milliseconds, so a settings GUI can offer a latency slider
+/// instead of a list of raw frame counts.
+///
+/// * With `AudioBufferSizeInfo::Range`, the block size is clamped into the
+///   range. To also round it to a power of two when the backend prefers it
+///   (see `Backend::prefers_power_of_two_block_sizes()`), use
+///   `BlockSizeRange::block_size_for_latency()` instead.
+/// * With `AudioBufferSizeInfo::FixedList`, the closest entry in the list is
+///   returned. If two entries are equally close, then the smaller one is used.
+/// * With `AudioBufferSizeInfo::Unknown`, the exact number of frames is
+///   returned.
+///
+/// This returns `None` if the sample rate is unknown (`0`), if `target_ms` is
+/// not a valid number, or if there is no block size to pick from (an empty
+/// fixed list).
+pub fn buffer_size_for_latency(
+    sample_rate: u32,
+    target_ms: f64,
+    sizes: &AudioBufferSizeInfo,
+) -> Option<u32> {
+    if sample_rate == 0 || !target_ms.is_finite() {
+        return None;
+    }
+
+    // `as` saturates, so negative latencies become `0`.
+    let frames = (target_ms / 1_000.0 * f64::from(sample_rate)).round() as u32;
+
+    match sizes {
+        AudioBufferSizeInfo::Range(range) => Some(frames.max(range.min).min(range.max)),
+        AudioBufferSizeInfo::FixedList(list) => {
+            list.iter().copied().min_by_key(|size| (size.abs_diff(frames), *size))
+        }
+        AudioBufferSizeInfo::Unknown => Some(frames.max(1)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What a stream will be used for, used to pick a sensible default block size
+pub enum UsageProfile {
+    /// Live monitoring and playing virtual instruments, where latency matters
+    /// more than the risk of dropouts.
+    LowLatency,
+
+    /// A balance between latency and the risk of dropouts.
+    Balanced,
+
+    /// Playback-only applications (i.e. media players), where latency matters
+    /// little and dropouts should be avoided.
+    Stable,
+}
+
+/// Returns a sensible default block size for the given usage profile.
+///
+/// Use `BlockSizeRange::block_size_for_profile()` to get a block size that is
+/// supported by a specific device.
+pub fn default_buffer_size_for(profile: UsageProfile) -> u32 {
+    match profile {
+        UsageProfile::LowLatency => 128,
+        UsageProfile::Balanced => 512,
+        UsageProfile::Stable => 1024,
+    }
+}
+
 #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
 #[derive(Debug, Clone)]
 /// Information and configuration options for the "monolithic" system-wide
@@ -502,3 +1108,349 @@ impl Default for MidiControlScheme {
         MidiControlScheme::Midi1
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_size_for_latency_range() {
+        let range = BlockSizeRange { min: 64, max: 2048, default: 512 };
+        let sizes = AudioBufferSizeInfo::Range(range.clone());
+
+        // 10ms at 48kHz is 480 frames.
+        assert_eq!(buffer_size_for_latency(48_000, 10.0, &sizes), Some(480));
+
+        // Clamped into the range.
+        assert_eq!(buffer_size_for_latency(48_000, 0.1, &sizes), Some(64));
+        assert_eq!(buffer_size_for_latency(48_000, 1_000.0, &sizes), Some(2048));
+
+        assert_eq!(buffer_size_for_latency(0, 10.0, &sizes), None);
+        assert_eq!(buffer_size_for_latency(48_000, f64::NAN, &sizes), None);
+
+        // The range method rounds to a power of two when the backend prefers it,
+        // and falls back to the default block size.
+        assert_eq!(range.block_size_for_latency(48_000, 10.0, true), 512);
+        assert_eq!(range.block_size_for_latency(0, 10.0, true), 512);
+    }
+
+    #[test]
+    fn buffer_size_for_latency_fixed_list() {
+        let sizes = AudioBufferSizeInfo::FixedList(vec![144, 288, 432, 576]);
+
+        assert_eq!(buffer_size_for_latency(48_000, 10.0, &sizes), Some(432));
+
+        // Outside of the list.
+        assert_eq!(buffer_size_for_latency(48_000, 0.0, &sizes), Some(144));
+        assert_eq!(buffer_size_for_latency(48_000, 1_000.0, &sizes), Some(576));
+
+        // 360 frames is exactly between 288 and 432, so the smaller one is used.
+        assert_eq!(buffer_size_for_latency(48_000, 7.5, &sizes), Some(288));
+
+        assert_eq!(buffer_size_for_latency(0, 10.0, &sizes), None);
+        assert_eq!(
+            buffer_size_for_latency(48_000, 10.0, &AudioBufferSizeInfo::FixedList(Vec::new())),
+            None
+        );
+    }
+
+    #[test]
+    fn buffer_size_for_latency_unknown() {
+        let sizes = AudioBufferSizeInfo::Unknown;
+
+        assert_eq!(buffer_size_for_latency(48_000, 10.0, &sizes), Some(480));
+        assert_eq!(buffer_size_for_latency(44_100, 0.0, &sizes), Some(1));
+        assert_eq!(buffer_size_for_latency(0, 10.0, &sizes), None);
+        assert_eq!(buffer_size_for_latency(48_000, f64::INFINITY, &sizes), None);
+    }
+
+    fn ids(names: &[&str]) -> Vec<DeviceID> {
+        names.iter().map(|name| DeviceID { name: String::from(*name), identifier: None }).collect()
+    }
+
+    #[test]
+    fn virtual_device_names() {
+        let is_virtual = |name: &str| {
+            default_device_score(&DeviceID { name: String::from(name), identifier: None }) < 0
+        };
+
+        assert!(is_virtual("null"));
+        assert!(is_virtual("Null Output"));
+        assert!(is_virtual("dmix:CARD=PCH,DEV=0"));
+        assert!(is_virtual("Monitor of Built-in Audio Analog Stereo"));
+        assert!(is_virtual("CABLE Input (VB-Audio Virtual Cable)"));
+        assert!(is_virtual("Loopback Audio"));
+
+        assert!(!is_virtual("Speakers (Realtek High Definition Audio)"));
+        assert!(!is_virtual("Studio Monitors (Focusrite USB Audio)"));
+        assert!(!is_virtual("Monitor Speakers"));
+        assert!(!is_virtual("USB Cable Adapter"));
+        assert!(!is_virtual("Scarlett 2i2 USB"));
+    }
+
+    #[test]
+    fn preferred_default_device_avoids_virtual_devices() {
+        let options = AudioDeviceOptions::SingleDeviceOnly {
+            options: ids(&["null", "CABLE Output (VB-Audio Virtual Cable)", "Studio Monitors"]),
+            default_device: None,
+        };
+        assert_eq!(options.preferred_default_device(), Some((2, DefaultDeviceReason::BestGuess)));
+
+        let options = AudioDeviceOptions::SingleDeviceOnly {
+            options: ids(&["null", "dmix"]),
+            default_device: None,
+        };
+        assert_eq!(
+            options.preferred_default_device(),
+            Some((0, DefaultDeviceReason::OnlyVirtualDevices))
+        );
+
+        // The system default always wins.
+        let options = AudioDeviceOptions::SingleDeviceOnly {
+            options: ids(&["Speakers", "null"]),
+            default_device: Some(1),
+        };
+        assert_eq!(
+            options.preferred_default_device(),
+            Some((1, DefaultDeviceReason::SystemDefault))
+        );
+
+        let options =
+            AudioDeviceOptions::SingleDeviceOnly { options: Vec::new(), default_device: None };
+        assert_eq!(options.preferred_default_device(), None);
+    }
+
+    #[test]
+    fn preferred_default_device_prefers_duplex_devices() {
+        let options = AudioDeviceOptions::LinkedInOutDevice {
+            in_devices: ids(&["Monitor of HDMI", "USB Interface"]),
+            out_devices: ids(&["HDMI", "Monitor of HDMI", "USB Interface"]),
+            default_in_device: None,
+            default_out_device: None,
+        };
+        assert_eq!(options.preferred_default_device(), Some((2, DefaultDeviceReason::BestGuess)));
+
+        // Without a duplex device, the first real device is used.
+        let options = AudioDeviceOptions::LinkedInOutDevice {
+            in_devices: Vec::new(),
+            out_devices: ids(&["null", "HDMI", "USB Interface"]),
+            default_in_device: None,
+            default_out_device: None,
+        };
+        assert_eq!(options.preferred_default_device(), Some((1, DefaultDeviceReason::BestGuess)));
+    }
+
+    fn names(devices: &[DeviceID]) -> Vec<&str> {
+        devices.iter().map(|id| id.name.as_str()).collect()
+    }
+
+    fn mixed_devices() -> AudioDeviceOptions {
+        AudioDeviceOptions::SingleDeviceOnly {
+            options: ids(&[
+                "null",
+                "Speakers (Realtek High Definition Audio)",
+                "default",
+                "CABLE Input (VB-Audio Virtual Cable)",
+                "Scarlett 2i2 USB",
+                "PipeWire Sound Server",
+                "Studio Monitors",
+            ]),
+            default_device: Some(4),
+        }
+    }
+
+    #[test]
+    fn filter_keeps_everything_by_default() {
+        let mut options = mixed_devices();
+        EnumerationFilter::new().apply(&mut options);
+
+        let AudioDeviceOptions::SingleDeviceOnly { options, default_device } = options else {
+            panic!("expected a single device list");
+        };
+        assert_eq!(options.len(), 7);
+        assert_eq!(options[4].name, "Scarlett 2i2 USB");
+        assert_eq!(default_device, Some(4));
+    }
+
+    #[test]
+    fn filter_hide_virtual_devices() {
+        let mut options = mixed_devices();
+        EnumerationFilter::hide_virtual_devices().apply(&mut options);
+
+        let AudioDeviceOptions::SingleDeviceOnly { options, default_device } = options else {
+            panic!("expected a single device list");
+        };
+        assert_eq!(
+            names(&options),
+            [
+                "Speakers (Realtek High Definition Audio)",
+                "default",
+                "Scarlett 2i2 USB",
+                "PipeWire Sound Server",
+                "Studio Monitors",
+            ]
+        );
+        assert_eq!(default_device, Some(2));
+    }
+
+    #[test]
+    fn filter_physical_only() {
+        let mut options = mixed_devices();
+        EnumerationFilter::physical_only().apply(&mut options);
+
+        let AudioDeviceOptions::SingleDeviceOnly { options, default_device } = options else {
+            panic!("expected a single device list");
+        };
+        assert_eq!(
+            names(&options),
+            ["Speakers (Realtek High Definition Audio)", "Scarlett 2i2 USB", "Studio Monitors"]
+        );
+        assert_eq!(default_device, Some(1));
+    }
+
+    #[test]
+    fn filter_drops_a_filtered_out_default() {
+        let mut options = AudioDeviceOptions::SingleDeviceOnly {
+            options: ids(&["Speakers", "null"]),
+            default_device: Some(1),
+        };
+        EnumerationFilter::hide_virtual_devices().apply(&mut options);
+
+        let AudioDeviceOptions::SingleDeviceOnly { options, default_device } = options else {
+            panic!("expected a single device list");
+        };
+        assert_eq!(names(&options), ["Speakers"]);
+        assert_eq!(default_device, None);
+    }
+
+    #[test]
+    fn filter_sort() {
+        let mut options = AudioDeviceOptions::LinkedInOutDevice {
+            in_devices: ids(&["b", "c", "a"]),
+            out_devices: ids(&["z", "y", "x"]),
+            default_in_device: Some(0),
+            default_out_device: Some(2),
+        };
+        EnumerationFilter::new().with_sort(|a, b| a.name.cmp(&b.name)).apply(&mut options);
+
+        let AudioDeviceOptions::LinkedInOutDevice {
+            in_devices,
+            out_devices,
+            default_in_device,
+            default_out_device,
+        } = options
+        else {
+            panic!("expected linked in/out device lists");
+        };
+        assert_eq!(names(&in_devices), ["a", "b", "c"]);
+        assert_eq!(names(&out_devices), ["x", "y", "z"]);
+        assert_eq!(default_in_device, Some(1));
+        assert_eq!(default_out_device, Some(0));
+    }
+
+    fn device_options(
+        block_sizes: Option<BlockSizeRange>,
+        exclusive_min_period: Option<Duration>,
+    ) -> AudioDeviceConfigOptions {
+        AudioDeviceConfigOptions {
+            sample_rates: None,
+            native_sample_rate: Some(48_000),
+            can_take_exclusive_access: exclusive_min_period.is_some(),
+            block_sizes,
+            exclusive_min_period,
+            num_in_channels: 0,
+            num_out_channels: 2,
+            in_channel_names: Vec::new(),
+            out_channel_names: vec![String::from("Output 1"), String::from("Output 2")],
+            in_channel_layout: ChannelLayout::Unspecified,
+            out_channel_layout: ChannelLayout::Stereo,
+            supports_direct_monitoring: false,
+            in_jack_is_unpopulated: false,
+            out_jack_is_unpopulated: false,
+        }
+    }
+
+    fn backend_options(
+        backend: Backend,
+        device_options: Option<AudioDeviceOptions>,
+    ) -> AudioBackendOptions {
+        AudioBackendOptions {
+            backend,
+            version: None,
+            status: BackendStatus::Running,
+            device_options,
+        }
+    }
+
+    #[test]
+    fn exclusive_block_sizes_3ms_min_period() {
+        let min_period = Duration::from_millis(3);
+
+        let sizes = exclusive_block_sizes(min_period, 48_000);
+        assert_eq!(sizes.len(), 28);
+        assert_eq!(sizes[..4], [144, 288, 432, 576]);
+        assert_eq!(sizes.last(), Some(&4032));
+
+        // The number of frames in a period depends on the sample rate.
+        assert_eq!(exclusive_block_sizes(min_period, 44_100)[..3], [132, 264, 396]);
+        assert_eq!(exclusive_block_sizes(min_period, 96_000)[..3], [288, 576, 864]);
+
+        // A period longer than the largest block size is still offered.
+        assert_eq!(exclusive_block_sizes(Duration::from_millis(100), 48_000), [4800]);
+
+        assert!(exclusive_block_sizes(Duration::ZERO, 48_000).is_empty());
+        assert!(exclusive_block_sizes(min_period, 0).is_empty());
+    }
+
+    #[test]
+    fn buffer_size_info() {
+        let range = BlockSizeRange { min: 480, max: 480, default: 480 };
+        let device = device_options(Some(range.clone()), Some(Duration::from_millis(3)));
+
+        assert_eq!(device.buffer_size_info(false, 48_000), AudioBufferSizeInfo::Range(range));
+        assert_eq!(
+            device.buffer_size_info(true, 96_000),
+            AudioBufferSizeInfo::FixedList(exclusive_block_sizes(Duration::from_millis(3), 96_000))
+        );
+        assert!(device.buffer_size_is_configurable());
+
+        let device = device_options(None, None);
+        assert_eq!(device.buffer_size_info(true, 48_000), AudioBufferSizeInfo::Unknown);
+        assert!(!device.buffer_size_is_configurable());
+    }
+
+    #[test]
+    fn supports_fixed_buffer_size() {
+        let devices = AudioDeviceOptions::SingleDeviceOnly {
+            options: ids(&["Speakers"]),
+            default_device: None,
+        };
+        assert!(!backend_options(Backend::Wasapi, Some(devices)).supports_fixed_buffer_size());
+        assert!(!backend_options(Backend::Wasapi, None).supports_fixed_buffer_size());
+
+        #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+        assert!(backend_options(Backend::Jack, Some(AudioDeviceOptions::JackSystemWideDevice))
+            .supports_fixed_buffer_size());
+    }
+
+    #[test]
+    fn buffer_size_is_configurable() {
+        // `AudioBufferSizeInfo::Range`
+        let device =
+            device_options(Some(BlockSizeRange { min: 64, max: 2048, default: 512 }), None);
+        assert!(device.buffer_size_is_configurable());
+        let device =
+            device_options(Some(BlockSizeRange { min: 480, max: 480, default: 480 }), None);
+        assert!(!device.buffer_size_is_configurable());
+
+        // `AudioBufferSizeInfo::FixedList`
+        let device = device_options(None, Some(Duration::from_millis(3)));
+        assert!(device.buffer_size_is_configurable());
+        let device = device_options(None, Some(Duration::from_millis(100)));
+        assert!(!device.buffer_size_is_configurable());
+
+        // `AudioBufferSizeInfo::Unknown`
+        let device = device_options(None, None);
+        assert!(!device.buffer_size_is_configurable());
+    }
+}
@@ -1,3 +1,4 @@
+use std::any::Any;
 use std::error::Error;
 use std::fmt;
 
@@ -6,27 +7,133 @@ use crate::{Backend, DeviceID};
 #[cfg(feature = "midi")]
 use crate::MAX_MIDI_MSG_SIZE;
 
+#[non_exhaustive]
 #[derive(Debug)]
 /// An error that caused the stream to stop.
+///
+/// Every backend sends this through `StreamMsg::Error` when the stream stops
+/// because of a runtime failure. Xruns that the backend recovers from are only
+/// counted (see `StreamHandle::glitch_counts()`), and being denied realtime
+/// priority is sent as `StreamMsg::RealtimePriorityDenied` unless
+/// `RunOptions::require_realtime_priority` is `true`.
+///
+/// # Migrating from the "server" names
+///
+/// `AudioServerShutdown` was renamed to `AudioBackendShutdown`, and
+/// `AudioServerChangedSamplerate` was renamed to
+/// `AudioBackendChangedSampleRate`. Enum variants can't have aliases, so the
+/// old names are kept as deprecated constructors, but `match` arms have to use
+/// the new names. This enum is `#[non_exhaustive]`, so a `match` on it also
+/// needs a wildcard arm.
 pub enum StreamError {
-    AudioServerShutdown { msg: Option<String> },
-    AudioServerChangedSamplerate(u32),
+    /// The audio backend (i.e. the Jack server) was shut down.
+    AudioBackendShutdown {
+        msg: Option<String>,
+    },
+    /// The audio backend (i.e. the Jack server) changed its sample rate.
+    AudioBackendChangedSampleRate(u32),
+    /// The audio device stopped working (i.e. it was unplugged), and it could
+    /// not be reconnected. `msg` is the error reported by the backend.
+    AudioDeviceDisconnected {
+        id: DeviceID,
+        msg: Option<String>,
+    },
+    /// The `process()` method of the process handler panicked. The stream
+    /// was stopped and the device was released.
+    ProcessPanicked {
+        msg: Option<String>,
+    },
+    /// The backend stopped the stream after an xrun it could not recover from
+    /// (i.e. the Jack server removed the client because it took too long to
+    /// process).
+    Xrun {
+        msg: Option<String>,
+    },
+    /// The audio thread could not be given realtime priority, and
+    /// `RunOptions::require_realtime_priority` is `true`. This contains the
+    /// reason.
+    RealtimePriorityDenied(String),
     PlatformSpecific(String),
     // TODO
 }
+impl StreamError {
+    /// Creates a `StreamError::AudioBackendShutdown` error.
+    #[deprecated(note = "renamed to `StreamError::AudioBackendShutdown`")]
+    #[allow(non_snake_case)]
+    pub fn AudioServerShutdown(msg: Option<String>) -> Self {
+        StreamError::AudioBackendShutdown { msg }
+    }
+
+    /// Creates a `StreamError::AudioBackendChangedSampleRate` error.
+    #[deprecated(note = "renamed to `StreamError::AudioBackendChangedSampleRate`")]
+    #[allow(non_snake_case)]
+    pub fn AudioServerChangedSamplerate(sample_rate: u32) -> Self {
+        StreamError::AudioBackendChangedSampleRate(sample_rate)
+    }
+
+    pub(crate) fn from_panic(payload: Box<dyn Any + Send>) -> Self {
+        let msg = if let Some(msg) = payload.downcast_ref::<&str>() {
+            Some(String::from(*msg))
+        } else {
+            payload.downcast_ref::<String>().cloned()
+        };
+
+        StreamError::ProcessPanicked { msg }
+    }
+}
 impl Error for StreamError {}
 impl fmt::Display for StreamError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            StreamError::AudioServerShutdown { msg } => {
+            StreamError::AudioBackendShutdown { msg } => {
+                if let Some(msg) = msg {
+                    write!(f, "Fatal stream error: the audio backend was shut down: {}", msg)
+                } else {
+                    write!(f, "Fatal stream error: the audio backend was shut down")
+                }
+            }
+            StreamError::AudioBackendChangedSampleRate(sr) => {
+                write!(
+                    f,
+                    "Fatal stream error: the audio backend changed its sample rate to: {}",
+                    sr
+                )
+            }
+            StreamError::AudioDeviceDisconnected { id, msg } => {
+                if let Some(msg) = msg {
+                    write!(
+                        f,
+                        "Fatal stream error: the audio device {} was disconnected: {}",
+                        &id.name, msg
+                    )
+                } else {
+                    write!(f, "Fatal stream error: the audio device {} was disconnected", &id.name)
+                }
+            }
+            StreamError::ProcessPanicked { msg } => {
+                if let Some(msg) = msg {
+                    write!(f, "Fatal stream error: the process handler panicked: {}", msg)
+                } else {
+                    write!(f, "Fatal stream error: the process handler panicked")
+                }
+            }
+            StreamError::Xrun { msg } => {
                 if let Some(msg) = msg {
-                    write!(f, "Fatal stream error: the audio server was shut down: {}", msg)
+                    write!(
+                        f,
+                        "Fatal stream error: the stream could not recover from an xrun: {}",
+                        msg
+                    )
                 } else {
-                    write!(f, "Fatal stream error: the audio server was shut down")
+                    write!(f, "Fatal stream error: the stream could not recover from an xrun")
                 }
             }
-            StreamError::AudioServerChangedSamplerate(sr) => {
-                write!(f, "Fatal stream error: the audio server changed its sample rate to: {}", sr)
+            StreamError::RealtimePriorityDenied(e) => {
+                write!(
+                    f,
+                    "Fatal stream error: the audio thread was denied realtime priority: {}",
+                    e
+                )
             }
             StreamError::PlatformSpecific(e) => {
                 write!(f, "Fatal stream error: {}", e)
@@ -47,6 +154,11 @@ pub enum RunConfigError {
     CouldNotUseBlockSize(u32),
     ConfigHasNoStereoOutput,
     AutoNoStereoOutputFound,
+    /// An audio device was found, but it only has inputs (i.e. a capture-only
+    /// USB microphone).
+    AutoDeviceIsInputOnly,
+    /// No audio device with inputs or outputs is available.
+    AutoNoAudioDeviceFound,
     CouldNotUseExclusive,
 
     #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
@@ -54,6 +166,10 @@ pub enum RunConfigError {
     #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
     JackNotEnabledForPlatform,
 
+    #[cfg(target_os = "windows")]
+    /// COM could not be initialized on the thread, even after retrying.
+    ComInitFailed(String),
+
     #[cfg(feature = "midi")]
     MidiBackendNotFound(Backend),
     #[cfg(feature = "midi")]
@@ -101,6 +217,12 @@ impl fmt::Display for RunConfigError {
             RunConfigError::AutoNoStereoOutputFound => {
                 write!(f, "Failed to run config: Could not find an audio device with at-least 2 output ports")
             }
+            RunConfigError::AutoDeviceIsInputOnly => {
+                write!(f, "Failed to run config: The available audio device is capture-only and has no output ports")
+            }
+            RunConfigError::AutoNoAudioDeviceFound => {
+                write!(f, "Failed to run config: No audio device is available")
+            }
 
             RunConfigError::CouldNotUseExclusive => {
                 write!(f, "Failed to run config: Could not run audio device in exclusive mode")
@@ -115,6 +237,11 @@ impl fmt::Display for RunConfigError {
                 write!(f, "Failed to run config: Jack on this platform is not enabled by this application")
             }
 
+            #[cfg(target_os = "windows")]
+            RunConfigError::ComInitFailed(e) => {
+                write!(f, "Failed to run config: Failed to initialize COM: {}", e)
+            }
+
             #[cfg(feature = "midi")]
             RunConfigError::MidiBackendNotFound(b) => {
                 write!(f, "Failed to run config: The MIDI backend {:?} was not found", b)
@@ -133,6 +260,98 @@ impl fmt::Display for RunConfigError {
     }
 }
 
+#[derive(Debug)]
+/// An error while measuring the round-trip latency with
+/// `measure_roundtrip_latency()`.
+pub enum MeasureLatencyError {
+    /// The stream could not be run.
+    RunConfigError(RunConfigError),
+    /// The stream stopped while measuring.
+    StreamError(StreamError),
+    /// The stream does not have both an audio input and an audio output.
+    NoLoopbackChannels,
+    /// The impulse was not heard on the input. Check that the first output is
+    /// connected to the first input.
+    NoImpulseDetected,
+    /// The stream did not process enough audio in time.
+    TimedOut,
+}
+impl Error for MeasureLatencyError {}
+impl fmt::Display for MeasureLatencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MeasureLatencyError::RunConfigError(e) => {
+                write!(f, "Failed to measure latency: {}", e)
+            }
+            MeasureLatencyError::StreamError(e) => {
+                write!(f, "Failed to measure latency: {}", e)
+            }
+            MeasureLatencyError::NoLoopbackChannels => {
+                write!(f, "Failed to measure latency: The stream needs at-least 1 audio input and 1 audio output")
+            }
+            MeasureLatencyError::NoImpulseDetected => {
+                write!(f, "Failed to measure latency: The impulse was not detected on the first input. Is the first output connected to it?")
+            }
+            MeasureLatencyError::TimedOut => {
+                write!(f, "Failed to measure latency: Timed out while waiting for the stream")
+            }
+        }
+    }
+}
+impl From<RunConfigError> for MeasureLatencyError {
+    fn from(e: RunConfigError) -> Self {
+        MeasureLatencyError::RunConfigError(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A contradiction found by `ConfigBuilder::build()`.
+pub enum ConfigError {
+    /// The sample rate is not valid.
+    InvalidSampleRate(u32),
+    /// The block size is not valid.
+    InvalidBlockSize(u32),
+    /// Jack ports were given, but a different backend was selected.
+    JackDeviceWithOtherBackend(Backend),
+    /// A linked in/out device config has neither an input or an output device.
+    LinkedInOutHasNoDevices,
+    /// The system-wide device was selected, but the selected backend does not
+    /// have one.
+    NoSystemWideDevice(Backend),
+    /// An output bus with this ID has no ports.
+    EmptyOutBus(String),
+    /// More than one output bus has this ID.
+    DuplicateOutBus(String),
+}
+impl Error for ConfigError {}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidSampleRate(s) => {
+                write!(f, "Invalid config: {} is not a valid sample rate", s)
+            }
+            ConfigError::InvalidBlockSize(b) => {
+                write!(f, "Invalid config: {} is not a valid block/buffer size", b)
+            }
+            ConfigError::JackDeviceWithOtherBackend(b) => {
+                write!(f, "Invalid config: Jack ports were given for the audio backend {:?}", b)
+            }
+            ConfigError::LinkedInOutHasNoDevices => {
+                write!(f, "Invalid config: Linked in/out device has no input or output device")
+            }
+            ConfigError::NoSystemWideDevice(b) => {
+                write!(f, "Invalid config: The audio backend {:?} has no system-wide device", b)
+            }
+            ConfigError::EmptyOutBus(id) => {
+                write!(f, "Invalid config: The output bus {} has no ports", id)
+            }
+            ConfigError::DuplicateOutBus(id) => {
+                write!(f, "Invalid config: More than one output bus has the ID {}", id)
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ChangeBlockSizeError {
     NotSupportedByBackend, // TODO: more errors?
@@ -148,6 +367,40 @@ impl fmt::Display for ChangeBlockSizeError {
     }
 }
 
+#[derive(Debug, Clone)]
+pub enum SetDirectMonitoringError {
+    /// The backend or the device does not support hardware direct monitoring.
+    /// Use `StreamHandle::set_monitor()` to monitor in software instead.
+    NotSupportedByBackend,
+}
+impl Error for SetDirectMonitoringError {}
+impl fmt::Display for SetDirectMonitoringError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SetDirectMonitoringError::NotSupportedByBackend => {
+                write!(f, "Failed to set direct monitoring: Not supported on this backend")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum HardwareInputGainError {
+    /// The backend or the device does not expose a hardware input gain
+    /// control. Apply the gain in software instead.
+    NotSupportedByBackend,
+}
+impl Error for HardwareInputGainError {}
+impl fmt::Display for HardwareInputGainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HardwareInputGainError::NotSupportedByBackend => {
+                write!(f, "Failed to access hardware input gain: Not supported on this backend")
+            }
+        }
+    }
+}
+
 #[cfg(feature = "midi")]
 #[derive(Debug, Clone)]
 pub enum ChangeMidiPortsError {
@@ -214,3 +467,105 @@ impl std::fmt::Display for MidiBufferPushError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_config_error_auto_device_display() {
+        assert_eq!(
+            RunConfigError::AutoDeviceIsInputOnly.to_string(),
+            "Failed to run config: The available audio device is capture-only and has no output ports"
+        );
+        assert_eq!(
+            RunConfigError::AutoNoAudioDeviceFound.to_string(),
+            "Failed to run config: No audio device is available"
+        );
+        assert_eq!(
+            RunConfigError::AutoNoStereoOutputFound.to_string(),
+            "Failed to run config: Could not find an audio device with at-least 2 output ports"
+        );
+    }
+
+    #[test]
+    fn stream_error_display() {
+        let id = DeviceID { name: String::from("Speakers"), identifier: None };
+
+        let cases = [
+            (
+                StreamError::AudioBackendShutdown { msg: None },
+                "Fatal stream error: the audio backend was shut down",
+            ),
+            (
+                StreamError::AudioBackendChangedSampleRate(48000),
+                "Fatal stream error: the audio backend changed its sample rate to: 48000",
+            ),
+            (
+                StreamError::AudioDeviceDisconnected { id: id.clone(), msg: None },
+                "Fatal stream error: the audio device Speakers was disconnected",
+            ),
+            (
+                StreamError::AudioDeviceDisconnected { id, msg: Some(String::from("unplugged")) },
+                "Fatal stream error: the audio device Speakers was disconnected: unplugged",
+            ),
+            (
+                StreamError::ProcessPanicked { msg: Some(String::from("oops")) },
+                "Fatal stream error: the process handler panicked: oops",
+            ),
+            (
+                StreamError::Xrun { msg: None },
+                "Fatal stream error: the stream could not recover from an xrun",
+            ),
+            (
+                StreamError::Xrun { msg: Some(String::from("zombified")) },
+                "Fatal stream error: the stream could not recover from an xrun: zombified",
+            ),
+            (
+                StreamError::RealtimePriorityDenied(String::from("rtkit is not running")),
+                "Fatal stream error: the audio thread was denied realtime priority: rtkit is not running",
+            ),
+            (StreamError::PlatformSpecific(String::from("E_FAIL")), "Fatal stream error: E_FAIL"),
+        ];
+
+        for (e, expected) in cases {
+            assert_eq!(e.to_string(), expected);
+        }
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn stream_error_deprecated_server_names() {
+        let e = StreamError::AudioServerShutdown(Some(String::from("server quit")));
+        assert!(
+            matches!(&e, StreamError::AudioBackendShutdown { msg: Some(msg) } if msg == "server quit")
+        );
+        assert_eq!(
+            e.to_string(),
+            "Fatal stream error: the audio backend was shut down: server quit"
+        );
+
+        let e = StreamError::AudioServerChangedSamplerate(44100);
+        assert!(matches!(e, StreamError::AudioBackendChangedSampleRate(44100)));
+        assert_eq!(
+            e.to_string(),
+            "Fatal stream error: the audio backend changed its sample rate to: 44100"
+        );
+    }
+
+    #[test]
+    fn stream_error_from_panic() {
+        let e = StreamError::from_panic(Box::new("static message"));
+        assert!(
+            matches!(e, StreamError::ProcessPanicked { msg: Some(msg) } if msg == "static message")
+        );
+
+        let e = StreamError::from_panic(Box::new(String::from("formatted message")));
+        assert!(
+            matches!(e, StreamError::ProcessPanicked { msg: Some(msg) } if msg == "formatted message")
+        );
+
+        let e = StreamError::from_panic(Box::new(42));
+        assert!(matches!(e, StreamError::ProcessPanicked { msg: None }));
+    }
+}
@@ -0,0 +1,50 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// The number of glitches that have occurred in a running stream
+pub struct GlitchCounts {
+    /// The number of times the input device delivered data that could not be
+    /// read in time, so some input was lost
+    pub input_overruns: u64,
+
+    /// The number of times the output device ran out of data to play, so some
+    /// silence or stale audio was output
+    ///
+    /// The Jack server does not report whether an xrun happened on the input
+    /// or the output, so with Jack every xrun is counted here. In WASAPI
+    /// exclusive event-driven mode underruns can't be detected, so this stays
+    /// at zero.
+    pub output_underruns: u64,
+}
+
+/// The glitch counters shared between the audio thread and the stream handle.
+/// The audio thread only ever updates atomic counters.
+pub(crate) struct SharedGlitchCounts {
+    input_overruns: AtomicU64,
+    output_underruns: AtomicU64,
+}
+
+impl SharedGlitchCounts {
+    pub fn new() -> Self {
+        Self { input_overruns: AtomicU64::new(0), output_underruns: AtomicU64::new(0) }
+    }
+
+    /// Record a single input overrun. This is realtime-safe.
+    #[allow(dead_code)]
+    pub fn record_input_overrun(&self) {
+        self.input_overruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a single output underrun. This is realtime-safe.
+    pub fn record_output_underrun(&self) {
+        self.output_underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns a snapshot of the current counts.
+    pub fn snapshot(&self) -> GlitchCounts {
+        GlitchCounts {
+            input_overruns: self.input_overruns.load(Ordering::Relaxed),
+            output_underruns: self.output_underruns.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -15,6 +15,7 @@ pub fn enumerate_audio_backend() -> AudioBackendOptions {
 
             AudioBackendOptions {
                 backend: Backend::Jack,
+                // TODO: The Jack bindings don't expose `jack_get_version_string()` yet.
                 version: None,
                 status: BackendStatus::Running,
                 device_options: Some(AudioDeviceOptions::JackSystemWideDevice),
@@ -75,7 +76,7 @@ pub fn enumerate_audio_device() -> Result<JackAudioDeviceOptions, JackEnumeratio
                 }
             }
             // Find index of default out right port.
-            let mut default_out_port_right = 1.min(system_audio_out_ports.len() - 1); // Fallback to second available port if stereo, first if mono.
+            let mut default_out_port_right = 1.min(system_audio_out_ports.len().saturating_sub(1)); // Fallback to second available port if stereo, first if mono.
             for (i, port) in system_audio_out_ports.iter().enumerate() {
                 if port == "system:playback_2" {
                     default_out_port_right = i;
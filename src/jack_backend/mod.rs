@@ -5,6 +5,8 @@ mod run;
 
 use notification_handler::JackNotificationHandler;
 use process_handler::JackProcessHandler;
+#[cfg(feature = "midi")]
+use process_handler::MidiShutdown;
 
 pub use enumeration::*;
 pub use run::*;
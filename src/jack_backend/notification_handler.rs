@@ -1,18 +1,26 @@
+use std::sync::{Arc, Mutex};
+
 use ringbuf::Producer;
 
 use crate::error::StreamError;
+use crate::glitch_counts::SharedGlitchCounts;
 use crate::StreamMsg;
 
 use super::push_stream_msg;
 
 pub struct JackNotificationHandler {
-    to_stream_handle_tx: Producer<StreamMsg>,
+    to_stream_handle_tx: Arc<Mutex<Producer<StreamMsg>>>,
+    glitch_counts: Arc<SharedGlitchCounts>,
     sample_rate: u32,
 }
 
 impl JackNotificationHandler {
-    pub fn new(to_stream_handle_tx: Producer<StreamMsg>, sample_rate: u32) -> Self {
-        Self { to_stream_handle_tx, sample_rate }
+    pub fn new(
+        to_stream_handle_tx: Arc<Mutex<Producer<StreamMsg>>>,
+        glitch_counts: Arc<SharedGlitchCounts>,
+        sample_rate: u32,
+    ) -> Self {
+        Self { to_stream_handle_tx, glitch_counts, sample_rate }
     }
 }
 
@@ -26,10 +34,14 @@ impl jack::NotificationHandler for JackNotificationHandler {
 
         log::error!("{}", msg);
 
-        push_stream_msg(
-            &mut self.to_stream_handle_tx,
-            StreamMsg::Error(StreamError::AudioServerShutdown { msg: Some(msg) }),
-        );
+        // The server removes ("zombifies") a client that takes too long to process.
+        let e = if status.contains(jack::ClientStatus::CLIENT_ZOMBIE) {
+            StreamError::Xrun { msg: Some(msg) }
+        } else {
+            StreamError::AudioBackendShutdown { msg: Some(msg) }
+        };
+
+        push_stream_msg(&self.to_stream_handle_tx, StreamMsg::Error(e));
     }
 
     fn freewheel(&mut self, _: &jack::Client, is_enabled: bool) {
@@ -43,8 +55,8 @@ impl jack::NotificationHandler for JackNotificationHandler {
             log::error!("JACK: sample rate changed to {}", srate);
 
             push_stream_msg(
-                &mut self.to_stream_handle_tx,
-                StreamMsg::Error(StreamError::AudioServerChangedSamplerate(srate)),
+                &self.to_stream_handle_tx,
+                StreamMsg::Error(StreamError::AudioBackendChangedSampleRate(srate)),
             );
 
             return jack::Control::Quit;
@@ -102,6 +114,10 @@ impl jack::NotificationHandler for JackNotificationHandler {
 
     fn xrun(&mut self, _: &jack::Client) -> jack::Control {
         //log::warn!("JACK: xrun occurred");
+
+        // Jack doesn't report whether this was an input or output xrun.
+        self.glitch_counts.record_output_underrun();
+
         jack::Control::Continue
     }
 }
@@ -1,10 +1,39 @@
-use crate::{ProcessHandler, ProcessInfo, StreamInfo};
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
 
+use ringbuf::{Consumer, Producer};
+
+use crate::audio_thread::mark_audio_thread;
+use crate::block_size_stats::SharedBlockSizeStats;
+use crate::clip_detector::ClipDetector;
+use crate::error::StreamError;
+use crate::monitor_mix::SharedMonitorMix;
+use crate::overload_detector::OverloadDetector;
+use crate::{ProcessHandler, ProcessInfo, RunOptions, StreamInfo, StreamMsg};
+
+#[cfg(all(target_os = "linux", feature = "rtkit"))]
+use crate::rtkit::RtkitRequest;
+
+#[cfg(feature = "midi")]
+use crate::{error::MidiBufferPushError, MidiBuffer, MidiBufferPool};
 #[cfg(feature = "midi")]
-use crate::{error::MidiBufferPushError, MidiBuffer};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(feature = "midi")]
+#[derive(Default)]
+/// Used to silence the MIDI outputs before the Jack client is deactivated.
+pub struct MidiShutdown {
+    /// Set by the stream handle when the stream is dropped.
+    pub stop_requested: AtomicBool,
+    /// Set by the process thread once "all notes off" has been written to every
+    /// MIDI output port.
+    pub flushed: AtomicBool,
+}
 
-pub struct JackProcessHandler<P: ProcessHandler> {
+pub struct JackProcessHandler<P: ProcessHandler<M>, M: Send + 'static> {
     process_handler: P,
+    user_msg_rx: Consumer<M>,
+    to_stream_handle_tx: Arc<Mutex<Producer<StreamMsg>>>,
 
     audio_in_ports: Vec<jack::Port<jack::AudioIn>>,
     audio_out_ports: Vec<jack::Port<jack::AudioOut>>,
@@ -12,6 +41,11 @@ pub struct JackProcessHandler<P: ProcessHandler> {
     audio_in_buffers: Vec<Vec<f32>>,
     audio_out_buffers: Vec<Vec<f32>>,
 
+    // The index of the port that each output buffer is mixed into.
+    audio_out_port_map: Vec<usize>,
+    audio_out_mix_buffers: Vec<Vec<f32>>,
+    scratch_buffers: Vec<Vec<f32>>,
+
     #[cfg(feature = "midi")]
     midi_in_ports: Vec<jack::Port<jack::MidiIn>>,
     #[cfg(feature = "midi")]
@@ -21,50 +55,130 @@ pub struct JackProcessHandler<P: ProcessHandler> {
     midi_in_buffers: Vec<MidiBuffer>,
     #[cfg(feature = "midi")]
     midi_out_buffers: Vec<MidiBuffer>,
+    #[cfg(feature = "midi")]
+    midi_shutdown: Arc<MidiShutdown>,
+    #[cfg(feature = "midi")]
+    midi_buffer_pool: Option<MidiBufferPool>,
+    #[cfg(feature = "midi")]
+    midi_note_offs_first: bool,
+    // The number of input events dropped on each MIDI input port that have not
+    // been reported yet.
+    #[cfg(feature = "midi")]
+    midi_in_num_dropped: Vec<usize>,
 
     audio_buffer_size: usize,
+    output_frame: u64,
+    is_first_block: bool,
+    // The stream info to send in `StreamMsg::Started`. This is `None` once it
+    // has been sent. It is boxed up front so that sending it doesn't allocate.
+    started_msg_info: Option<Box<StreamInfo>>,
+    input_delay_frames: Option<u32>,
     check_for_silence: bool,
+    zero_outputs: bool,
     silent_audio_in_flags: Vec<bool>,
+    clip_detector: Option<ClipDetector>,
+    overload_detector: Option<OverloadDetector>,
+    block_size_stats: Option<Arc<SharedBlockSizeStats>>,
+    monitor_mix: Option<Arc<SharedMonitorMix>>,
+    catch_process_panics: bool,
+    // The error to send after the process handler panicked. The stream outputs
+    // silence until this has been sent, since the audio thread can't wait for the
+    // message channel to be free.
+    panic_msg: Option<StreamMsg>,
+    #[cfg(all(target_os = "linux", feature = "rtkit"))]
+    rtkit_request: Arc<RtkitRequest>,
+    #[cfg(all(target_os = "linux", feature = "rtkit"))]
+    rtkit_reported: bool,
 }
 
-impl<P: ProcessHandler> JackProcessHandler<P> {
+impl<P: ProcessHandler<M>, M: Send + 'static> JackProcessHandler<P, M> {
     pub fn new(
         process_handler: P,
+        user_msg_rx: Consumer<M>,
+        to_stream_handle_tx: Arc<Mutex<Producer<StreamMsg>>>,
         audio_in_ports: Vec<jack::Port<jack::AudioIn>>,
         audio_out_ports: Vec<jack::Port<jack::AudioOut>>,
+        audio_out_port_map: Vec<usize>,
         #[cfg(feature = "midi")] midi_in_ports: Vec<jack::Port<jack::MidiIn>>,
         #[cfg(feature = "midi")] midi_out_ports: Vec<jack::Port<jack::MidiOut>>,
+        #[cfg(feature = "midi")] midi_shutdown: Arc<MidiShutdown>,
+        block_size_stats: Option<Arc<SharedBlockSizeStats>>,
+        monitor_mix: Option<Arc<SharedMonitorMix>>,
+        #[cfg(all(target_os = "linux", feature = "rtkit"))] rtkit_request: Arc<RtkitRequest>,
         stream_info: &StreamInfo,
+        options: &RunOptions,
     ) -> Self {
         let audio_buffer_size = stream_info.buffer_size.max_buffer_size() as usize;
 
         let audio_in_buffers =
             (0..audio_in_ports.len()).map(|_| Vec::with_capacity(audio_buffer_size)).collect();
         let audio_out_buffers =
+            (0..audio_out_port_map.len()).map(|_| Vec::with_capacity(audio_buffer_size)).collect();
+        let audio_out_mix_buffers =
             (0..audio_out_ports.len()).map(|_| Vec::with_capacity(audio_buffer_size)).collect();
 
+        let scratch_buffers = (0..options.num_scratch_buffers)
+            .map(|_| Vec::with_capacity(audio_buffer_size))
+            .collect();
+
         let silent_audio_in_flags = vec![false; audio_in_ports.len()];
 
+        let clip_detector = if options.detect_clipping {
+            // Clipping is detected on the mixed signal of each port, and is reported as the
+            // first output buffer that is mixed into that port.
+            let channel_indices = (0..audio_out_ports.len())
+                .map(|port_i| {
+                    audio_out_port_map.iter().position(|&p| p == port_i).unwrap_or(port_i)
+                })
+                .collect();
+
+            Some(ClipDetector::with_channel_indices(channel_indices, stream_info.sample_rate))
+        } else {
+            None
+        };
+
+        let overload_detector = if options.detect_process_overload {
+            Some(OverloadDetector::new(
+                stream_info.sample_rate,
+                options.process_overload_threshold,
+                options.process_overload_blocks,
+            ))
+        } else {
+            None
+        };
+
         #[cfg(feature = "midi")]
         let (midi_in_buffers, midi_out_buffers) = {
             if let Some(midi_info) = &stream_info.midi_info {
                 let midi_buffer_size = midi_info.midi_buffer_size;
+                let new_buffer = || match &options.midi_buffer_pool {
+                    Some(pool) => pool.take(midi_buffer_size),
+                    None => MidiBuffer::new(midi_buffer_size),
+                };
 
                 (
-                    (0..midi_in_ports.len()).map(|_| MidiBuffer::new(midi_buffer_size)).collect(),
-                    (0..midi_out_ports.len()).map(|_| MidiBuffer::new(midi_buffer_size)).collect(),
+                    (0..midi_in_ports.len()).map(|_| new_buffer()).collect(),
+                    (0..midi_out_ports.len()).map(|_| new_buffer()).collect(),
                 )
             } else {
                 (Vec::new(), Vec::new())
             }
         };
 
+        #[cfg(feature = "midi")]
+        let midi_in_num_dropped = vec![0; midi_in_ports.len()];
+
         Self {
             process_handler,
+            user_msg_rx,
+            to_stream_handle_tx,
             audio_in_ports,
             audio_out_ports,
             audio_in_buffers,
             audio_out_buffers,
+            audio_out_port_map,
+            audio_out_mix_buffers,
+            scratch_buffers,
             #[cfg(feature = "midi")]
             midi_in_ports,
             #[cfg(feature = "midi")]
@@ -73,15 +187,122 @@ impl<P: ProcessHandler> JackProcessHandler<P> {
             midi_in_buffers,
             #[cfg(feature = "midi")]
             midi_out_buffers,
+            #[cfg(feature = "midi")]
+            midi_shutdown,
+            #[cfg(feature = "midi")]
+            midi_buffer_pool: options.midi_buffer_pool.clone(),
+            #[cfg(feature = "midi")]
+            midi_note_offs_first: options.midi_note_offs_first,
+            #[cfg(feature = "midi")]
+            midi_in_num_dropped,
             audio_buffer_size: audio_buffer_size as usize,
+            output_frame: 0,
+            is_first_block: true,
+            started_msg_info: Some(Box::new(stream_info.clone())),
+            input_delay_frames: stream_info.round_trip_latency(),
             check_for_silence: stream_info.checking_for_silent_inputs,
+            zero_outputs: options.zero_unwritten_outputs,
             silent_audio_in_flags,
+            clip_detector,
+            overload_detector,
+            block_size_stats,
+            monitor_mix,
+            catch_process_panics: options.catch_process_panics,
+            panic_msg: None,
+            #[cfg(all(target_os = "linux", feature = "rtkit"))]
+            rtkit_request,
+            #[cfg(all(target_os = "linux", feature = "rtkit"))]
+            rtkit_reported: false,
+        }
+    }
+
+    #[cfg(feature = "midi")]
+    /// Output silence, and send "all notes off" on every channel of every MIDI
+    /// output port so that no notes are left hanging once the client is
+    /// deactivated.
+    fn process_shutdown(&mut self, ps: &jack::ProcessScope) {
+        for port in self.audio_out_ports.iter_mut() {
+            port.as_mut_slice(ps).fill(0.0);
+        }
+
+        if self.midi_shutdown.flushed.load(Ordering::Acquire) {
+            return;
+        }
+
+        for port in self.midi_out_ports.iter_mut() {
+            let mut port_writer = port.writer(ps);
+
+            for channel in 0..16u8 {
+                // Control change 123: All Notes Off
+                if let Err(e) =
+                    port_writer.write(&jack::RawMidi { time: 0, bytes: &[0xB0 | channel, 123, 0] })
+                {
+                    log::error!("Could not send all notes off to Jack MIDI output: {}", e);
+                }
+            }
+        }
+
+        self.midi_shutdown.flushed.store(true, Ordering::Release);
+    }
+
+    /// Output silence instead of whatever was left in the port buffers, and try
+    /// to send the error from a process handler panic. The stream is stopped once
+    /// the error has been sent.
+    fn process_after_panic(&mut self, ps: &jack::ProcessScope) -> jack::Control {
+        for port in self.audio_out_ports.iter_mut() {
+            port.as_mut_slice(ps).fill(0.0);
+        }
+
+        // Never block the audio thread. If the message channel is currently in use
+        // by the notification thread then just try again on the next cycle.
+        if let Ok(mut to_stream_handle_tx) = self.to_stream_handle_tx.try_lock() {
+            if let Some(msg) = self.panic_msg.take() {
+                if let Err(msg) = to_stream_handle_tx.push(msg) {
+                    self.panic_msg = Some(msg);
+                }
+            }
+        }
+
+        if self.panic_msg.is_none() {
+            jack::Control::Quit
+        } else {
+            jack::Control::Continue
         }
     }
 }
 
-impl<P: ProcessHandler> jack::ProcessHandler for JackProcessHandler<P> {
+impl<P: ProcessHandler<M>, M: Send + 'static> jack::ProcessHandler for JackProcessHandler<P, M> {
     fn process(&mut self, _: &jack::Client, ps: &jack::ProcessScope) -> jack::Control {
+        // Jack doesn't guarantee that every cycle runs on the same thread, so this is
+        // set every cycle (which is cheap).
+        mark_audio_thread();
+
+        #[cfg(all(target_os = "linux", feature = "rtkit"))]
+        {
+            if !self.rtkit_reported {
+                self.rtkit_request.report_current_thread();
+                self.rtkit_reported = true;
+            }
+
+            // The error has already been sent by the rtkit thread.
+            if self.rtkit_request.stop_requested() {
+                for port in self.audio_out_ports.iter_mut() {
+                    port.as_mut_slice(ps).fill(0.0);
+                }
+                return jack::Control::Quit;
+            }
+        }
+
+        #[cfg(feature = "midi")]
+        if self.midi_shutdown.stop_requested.load(Ordering::Acquire) {
+            self.process_shutdown(ps);
+            return jack::Control::Continue;
+        }
+
+        if self.panic_msg.is_some() {
+            return self.process_after_panic(ps);
+        }
+
         let mut frames: usize = 0;
 
         // Copy audio inputs
@@ -115,20 +336,28 @@ impl<P: ProcessHandler> jack::ProcessHandler for JackProcessHandler<P> {
             // Check outputs for number of frames instead.
             if let Some(out_port) = self.audio_out_ports.first_mut() {
                 frames = out_port.as_mut_slice(ps).len();
+            } else {
+                // This client has no audio ports at all.
+                frames = ps.n_frames() as usize;
             }
         }
 
         // Clear audio outputs.
         for buffer in self.audio_out_buffers.iter_mut() {
-            buffer.clear();
+            if self.zero_outputs {
+                buffer.clear();
+            }
             buffer.resize(frames, 0.0);
         }
 
         #[cfg(feature = "midi")]
         {
             // Collect MIDI inputs
-            for (midi_buffer, port) in
-                self.midi_in_buffers.iter_mut().zip(self.midi_in_ports.iter())
+            for ((midi_buffer, port), num_dropped) in self
+                .midi_in_buffers
+                .iter_mut()
+                .zip(self.midi_in_ports.iter())
+                .zip(self.midi_in_num_dropped.iter_mut())
             {
                 midi_buffer.clear();
 
@@ -136,7 +365,7 @@ impl<P: ProcessHandler> jack::ProcessHandler for JackProcessHandler<P> {
                     if let Err(e) = midi_buffer.push_raw(event.time, event.bytes) {
                         match e {
                             MidiBufferPushError::BufferFull => {
-                                log::error!("Midi event dropped because buffer is full!");
+                                *num_dropped += 1;
                             }
                             MidiBufferPushError::EventTooLong(_) => {
                                 log::debug!(
@@ -147,6 +376,25 @@ impl<P: ProcessHandler> jack::ProcessHandler for JackProcessHandler<P> {
                         }
                     }
                 }
+
+                if self.midi_note_offs_first {
+                    midi_buffer.sort_note_offs_first();
+                }
+            }
+
+            if self.midi_in_num_dropped.iter().any(|n| *n > 0) {
+                // Never block the audio thread. If the message channel is currently in use
+                // by the notification thread then just try again on the next cycle.
+                if let Ok(mut to_stream_handle_tx) = self.to_stream_handle_tx.try_lock() {
+                    for (port, num_dropped) in self.midi_in_num_dropped.iter_mut().enumerate() {
+                        if *num_dropped > 0 {
+                            let msg = StreamMsg::MidiEventsDropped { port, count: *num_dropped };
+                            if to_stream_handle_tx.push(msg).is_ok() {
+                                *num_dropped = 0;
+                            }
+                        }
+                    }
+                }
             }
 
             // Clear MIDI outputs
@@ -170,19 +418,106 @@ impl<P: ProcessHandler> jack::ProcessHandler for JackProcessHandler<P> {
             }
         }
 
-        self.process_handler.process(ProcessInfo {
+        let proc_info = ProcessInfo {
             audio_inputs: &self.audio_in_buffers,
             audio_outputs: &mut self.audio_out_buffers,
             frames,
+            output_frame: self.output_frame,
+            is_first_block: self.is_first_block,
+            input_delay_frames: self.input_delay_frames,
             silent_audio_inputs: &self.silent_audio_in_flags,
+            raw_audio_output: None,
             #[cfg(feature = "midi")]
             midi_inputs: &self.midi_in_buffers,
             #[cfg(feature = "midi")]
             midi_outputs: &mut self.midi_out_buffers,
-        });
+            user_msg_rx: &mut self.user_msg_rx,
+            scratch_buffers: &mut self.scratch_buffers,
+        };
+
+        if let Some(overload_detector) = &mut self.overload_detector {
+            overload_detector.begin();
+        }
+
+        if self.catch_process_panics {
+            let process_handler = &mut self.process_handler;
+            if let Err(payload) =
+                std::panic::catch_unwind(AssertUnwindSafe(|| process_handler.process(proc_info)))
+            {
+                log::error!("The process handler panicked. Stopping the Jack stream...");
+
+                self.panic_msg = Some(StreamMsg::Error(StreamError::from_panic(payload)));
+                return self.process_after_panic(ps);
+            }
+        } else {
+            self.process_handler.process(proc_info);
+        }
+
+        if let Some(monitor_mix) = &self.monitor_mix {
+            monitor_mix.apply(&self.audio_in_buffers, &mut self.audio_out_buffers, frames);
+        }
+
+        if let Some(overload_detector) = &mut self.overload_detector {
+            overload_detector.end(frames);
+
+            if overload_detector.is_report_due() {
+                // Never block the audio thread. If the message channel is currently in use
+                // by the notification thread then just try again on the next cycle.
+                if let Ok(mut to_stream_handle_tx) = self.to_stream_handle_tx.try_lock() {
+                    overload_detector.report(&mut to_stream_handle_tx);
+                }
+            }
+        }
+
+        self.output_frame += frames as u64;
+        self.is_first_block = false;
+
+        if self.started_msg_info.is_some() {
+            // Never block the audio thread. If the message channel is currently in use
+            // by the notification thread then just try again on the next cycle.
+            if let Ok(mut to_stream_handle_tx) = self.to_stream_handle_tx.try_lock() {
+                if let Some(stream_info) = self.started_msg_info.take() {
+                    if let Err(e) = to_stream_handle_tx.push(StreamMsg::Started(stream_info)) {
+                        log::error!(
+                            "Failed to send stream message {:?}: message buffer is full!",
+                            e
+                        );
+                    }
+                }
+            }
+        }
+
+        if let Some(block_size_stats) = &self.block_size_stats {
+            block_size_stats.record(frames);
+        }
+
+        // Sum the output buffers into the buffers of the ports they are mapped to.
+        for buffer in self.audio_out_mix_buffers.iter_mut() {
+            buffer.clear();
+            buffer.resize(frames, 0.0);
+        }
+        for (buffer, &port_i) in self.audio_out_buffers.iter().zip(self.audio_out_port_map.iter()) {
+            let mix_buffer = &mut self.audio_out_mix_buffers[port_i];
+            for (mix_smp, smp) in mix_buffer.iter_mut().zip(buffer.iter()) {
+                *mix_smp += *smp;
+            }
+        }
+
+        if let Some(clip_detector) = &mut self.clip_detector {
+            clip_detector.process(&self.audio_out_mix_buffers, frames);
+
+            if clip_detector.is_report_due() {
+                // Never block the audio thread. If the message channel is currently in use
+                // by the notification thread then just try again on the next cycle.
+                if let Ok(mut to_stream_handle_tx) = self.to_stream_handle_tx.try_lock() {
+                    clip_detector.report(&mut to_stream_handle_tx);
+                }
+            }
+        }
 
         // Copy processed data to audio outputs
-        for (buffer, port) in self.audio_out_buffers.iter().zip(self.audio_out_ports.iter_mut()) {
+        for (buffer, port) in self.audio_out_mix_buffers.iter().zip(self.audio_out_ports.iter_mut())
+        {
             let port_buffer = port.as_mut_slice(ps);
 
             // Sanity check
@@ -220,3 +555,14 @@ impl<P: ProcessHandler> jack::ProcessHandler for JackProcessHandler<P> {
         jack::Control::Continue
     }
 }
+
+#[cfg(feature = "midi")]
+impl<P: ProcessHandler<M>, M: Send + 'static> Drop for JackProcessHandler<P, M> {
+    fn drop(&mut self) {
+        // This is dropped once the client is deactivated, so it is safe to lock
+        // the pool here.
+        if let Some(pool) = &self.midi_buffer_pool {
+            pool.give_back(self.midi_in_buffers.drain(..).chain(self.midi_out_buffers.drain(..)));
+        }
+    }
+}
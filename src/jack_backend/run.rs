@@ -1,10 +1,19 @@
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "midi")]
+use std::sync::atomic::Ordering;
+
 use ringbuf::Producer;
 
+use crate::block_size_stats::SharedBlockSizeStats;
 use crate::error::{ChangeBlockSizeError, RunConfigError};
+use crate::glitch_counts::SharedGlitchCounts;
+use crate::monitor_mix::SharedMonitorMix;
 use crate::{
-    AudioBufferStreamInfo, AudioDeviceConfig, AudioDeviceStreamInfo, AutoOption, Backend,
-    ChannelLayout, DeviceID, PlatformStreamHandle, ProcessHandler, RainoutConfig, RunOptions,
-    StreamHandle, StreamInfo, StreamMsg,
+    validate_backend_device_compatibility, AudioBufferStreamInfo, AudioDeviceConfig,
+    AudioDeviceStreamInfo, AutoOption, Backend, ChannelLayout, ConfigWarning, DeviceID,
+    PlatformStreamHandle, PortOpenResult, ProcessHandler, RainoutConfig, RunOptions, StreamHandle,
+    StreamInfo, StreamMsg, TeardownGuard, ThreadScheduling,
 };
 
 #[cfg(feature = "midi")]
@@ -15,6 +24,9 @@ use crate::{
 
 use super::{JackNotificationHandler, JackProcessHandler, DUMMY_CLIENT_NAME};
 
+#[cfg(feature = "midi")]
+use super::MidiShutdown;
+
 const DEFAULT_CLIENT_NAME: &'static str = env!("CARGO_PKG_NAME");
 
 pub fn estimated_sample_rate_and_latency(
@@ -39,23 +51,78 @@ pub fn estimated_sample_rate_and_latency(
     }
 }
 
-pub fn run<P: ProcessHandler>(
+pub fn run<P: ProcessHandler<M>, M: Send + 'static>(
     config: &RainoutConfig,
     options: &RunOptions,
     mut process_handler: P,
-) -> Result<StreamHandle<P>, RunConfigError> {
+) -> Result<StreamHandle<P, M>, RunConfigError> {
+    validate_backend_device_compatibility(Backend::Jack, &config.audio_device)?;
+
     // --- Create Jack client -----------------------------------------------------------------------
 
     log::debug!("Creating Jack client...");
 
     let client_name =
         options.use_application_name.clone().unwrap_or(String::from(DEFAULT_CLIENT_NAME));
-    let client_name_path = client_name.clone() + ":";
 
     log::debug!("Registering Jack client with name {}", &client_name);
 
     let (client, _status) = jack::Client::new(&client_name, jack::ClientOptions::empty())?;
 
+    // Jack gives the client a unique name if another client (i.e. another stream
+    // in this same application) is already using the requested name, so the
+    // ports must be referenced by the name that was actually assigned.
+    let client_name_path = String::from(client.name()) + ":";
+    if client.name() != client_name {
+        log::debug!("Jack client was renamed to {}", client.name());
+    }
+
+    // --- Apply requested sample rate and block size -----------------------------------------------
+
+    let mut config_warnings = Vec::new();
+
+    if let AutoOption::Use(sample_rate) = config.sample_rate {
+        // The sample rate is set by the Jack server and cannot be changed by a client.
+        let actual = client.sample_rate() as u32;
+        if sample_rate != actual {
+            log::warn!(
+                "Could not use sample rate {}, the Jack server is running at {}",
+                sample_rate,
+                actual
+            );
+            config_warnings
+                .push(ConfigWarning::SampleRateNotUsed { requested: sample_rate, actual });
+        }
+    }
+
+    if let AutoOption::Use(block_size) = config.block_size {
+        if block_size != client.buffer_size() {
+            if options.jack_set_server_buffer_size {
+                // Note this changes the buffer size of the whole Jack server.
+                if let Err(e) = client.set_buffer_size(block_size) {
+                    let actual = client.buffer_size();
+                    log::warn!(
+                        "Could not set the Jack buffer size to {}, using {} instead: {}",
+                        block_size,
+                        actual,
+                        e
+                    );
+                    config_warnings
+                        .push(ConfigWarning::BlockSizeNotUsed { requested: block_size, actual });
+                }
+            } else {
+                let actual = client.buffer_size();
+                log::warn!(
+                    "Could not use block size {}, the Jack server is running with {}",
+                    block_size,
+                    actual
+                );
+                config_warnings
+                    .push(ConfigWarning::BlockSizeNotUsed { requested: block_size, actual });
+            }
+        }
+    }
+
     // --- Find system audio ports ------------------------------------------------------------------
 
     let system_audio_in_ports: Vec<String> =
@@ -67,6 +134,7 @@ pub fn run<P: ProcessHandler>(
 
     let (use_audio_in_ports, use_audio_out_ports) = match &config.audio_device {
         AudioDeviceConfig::Jack { in_ports, out_ports } => (in_ports.clone(), out_ports.clone()),
+        // Jack only has the single system-wide device, so use its default ports.
         _ => {
             let mut use_in_ports: Vec<String> = Vec::new();
             let mut use_out_ports: Vec<String> = Vec::new();
@@ -87,27 +155,35 @@ pub fn run<P: ProcessHandler>(
             }
 
             if options.must_have_stereo_output && system_audio_out_ports.len() < 2 {
+                if system_audio_out_ports.is_empty() {
+                    if system_audio_in_ports.is_empty() {
+                        return Err(RunConfigError::AutoNoAudioDeviceFound);
+                    }
+                    return Err(RunConfigError::AutoDeviceIsInputOnly);
+                }
                 return Err(RunConfigError::AutoNoStereoOutputFound);
             }
 
-            // Find index of default out left port.
-            let mut default_out_port_left = 0; // Fallback to first available port.
-            for (i, port) in system_audio_out_ports.iter().enumerate() {
-                if port == "system:playback_1" {
-                    default_out_port_left = i;
-                    break;
+            // If there are no system output ports (and stereo output isn't required),
+            // then this will be a capture-only client.
+            if !system_audio_out_ports.is_empty() {
+                // Find index of default out left port.
+                let mut default_out_port_left = 0; // Fallback to first available port.
+                for (i, port) in system_audio_out_ports.iter().enumerate() {
+                    if port == "system:playback_1" {
+                        default_out_port_left = i;
+                        break;
+                    }
                 }
-            }
-            // Find index of default out right port.
-            let mut default_out_port_right = 1.min(system_audio_out_ports.len() - 1); // Fallback to second available port if stereo, first if mono.
-            for (i, port) in system_audio_out_ports.iter().enumerate() {
-                if port == "system:playback_2" {
-                    default_out_port_right = i;
-                    break;
+                // Find index of default out right port.
+                let mut default_out_port_right = 1.min(system_audio_out_ports.len() - 1); // Fallback to second available port if stereo, first if mono.
+                for (i, port) in system_audio_out_ports.iter().enumerate() {
+                    if port == "system:playback_2" {
+                        default_out_port_right = i;
+                        break;
+                    }
                 }
-            }
 
-            if !system_audio_out_ports.is_empty() {
                 if system_audio_out_ports.len() == 1
                     || default_out_port_left == default_out_port_right
                 {
@@ -135,6 +211,8 @@ pub fn run<P: ProcessHandler>(
     let mut client_audio_out_connected_to =
         Vec::<Option<String>>::with_capacity(use_audio_out_ports.len());
     let mut audio_out_ports_info = Vec::<(String, bool)>::with_capacity(use_audio_out_ports.len());
+    // The index of the client port that each output buffer is mixed into.
+    let mut audio_out_port_map = Vec::<usize>::with_capacity(use_audio_out_ports.len());
 
     for (i, port) in use_audio_in_ports.iter().enumerate() {
         if !system_audio_in_ports.contains(port) {
@@ -155,7 +233,18 @@ pub fn run<P: ProcessHandler>(
         client_audio_in_port_names.push(client_name_path.clone() + &client_port_name);
     }
 
-    for (i, port) in use_audio_out_ports.iter().enumerate() {
+    for port in use_audio_out_ports.iter() {
+        // If this system port is already used by another output, then mix into
+        // that same client port instead of registering a new one.
+        if let Some(client_port_i) = client_audio_out_connected_to
+            .iter()
+            .position(|connected_to| connected_to.as_ref() == Some(port))
+        {
+            audio_out_ports_info.push((port.clone(), true));
+            audio_out_port_map.push(client_port_i);
+            continue;
+        }
+
         if !system_audio_out_ports.contains(port) {
             if !options.empty_buffers_for_failed_ports {
                 return Err(RunConfigError::JackAudioPortNotFound(port.clone()));
@@ -167,9 +256,10 @@ pub fn run<P: ProcessHandler>(
             audio_out_ports_info.push((port.clone(), true));
         }
 
-        let client_port_name = format!("out_{}", i + 1);
+        let client_port_name = format!("out_{}", client_audio_out_ports.len() + 1);
         let client_port = client.register_port(&client_port_name, jack::AudioOut::default())?;
 
+        audio_out_port_map.push(client_audio_out_ports.len());
         client_audio_out_ports.push(client_port);
         client_audio_out_port_names.push(client_name_path.clone() + &client_port_name);
     }
@@ -189,7 +279,7 @@ pub fn run<P: ProcessHandler>(
 
     #[cfg(feature = "midi")]
     let (client_midi_in_ports, client_midi_out_ports, midi_port_info) = {
-        if let Some(midi_config) = &config.midi_config {
+        if let Some(midi_config) = config.midi_config.as_ref().filter(|_| options.enable_midi) {
             let use_jack_midi = match &midi_config.midi_backend {
                 AutoOption::Auto => true,
                 AutoOption::Use(b) => {
@@ -390,10 +480,24 @@ pub fn run<P: ProcessHandler>(
         None
     };
 
+    // The latency of the system ports that the client ports will be connected to.
+    let input_latency = client_audio_in_connected_to
+        .iter()
+        .flatten()
+        .filter_map(|name| client.port_by_name(name))
+        .map(|port| port.get_latency_range(jack::LatencyType::Capture).1)
+        .max();
+    let output_latency = client_audio_out_connected_to
+        .iter()
+        .flatten()
+        .filter_map(|name| client.port_by_name(name))
+        .map(|port| port.get_latency_range(jack::LatencyType::Playback).1)
+        .max();
+
     let num_in_channels = audio_in_ports_info.len() as u32;
     let num_out_channels = audio_out_ports_info.len() as u32;
 
-    let stream_info = StreamInfo {
+    let mut stream_info = StreamInfo {
         audio_backend: Backend::Jack,
         audio_backend_version: None,
         audio_device: AudioDeviceStreamInfo::Jack {
@@ -404,10 +508,18 @@ pub fn run<P: ProcessHandler>(
         num_out_channels,
         in_channel_layout: ChannelLayout::Unspecified,
         out_channel_layout: ChannelLayout::Unspecified,
+        input_is_loopback: false,
         sample_rate,
         buffer_size: AudioBufferStreamInfo::FixedSized(client.buffer_size() as u32),
+        // Jack does not expose how many periods the server's driver uses.
+        buffer_config: None,
         estimated_latency: None,
+        input_latency,
+        output_latency,
+        resampler_latency: Some(0), // Jack never resamples.
+        safety_latency: Some(0),    // Any extra buffering is included in the port latencies.
         checking_for_silent_inputs: options.check_for_silent_inputs,
+        native_format: None, // Jack always uses `f32` buffers.
         #[cfg(feature = "midi")]
         midi_info,
     };
@@ -415,32 +527,94 @@ pub fn run<P: ProcessHandler>(
     // Pass stream info to client for initialization.
     process_handler.init(&stream_info);
 
+    let (user_msg_tx, user_msg_rx) =
+        ringbuf::RingBuffer::<M>::new(options.user_msg_buffer_size).split();
+
+    let (to_stream_handle_tx, from_audio_thread_rx) =
+        ringbuf::RingBuffer::new(options.msg_buffer_size).split();
+    let to_stream_handle_tx = Arc::new(Mutex::new(to_stream_handle_tx));
+
+    let block_size_stats = if options.collect_block_size_stats {
+        Some(Arc::new(SharedBlockSizeStats::new()))
+    } else {
+        None
+    };
+    for warning in config_warnings {
+        push_stream_msg(&to_stream_handle_tx, StreamMsg::ConfigWarning(warning));
+    }
+
+    #[cfg(feature = "midi")]
+    let midi_shutdown = Arc::new(MidiShutdown::default());
+
+    let monitor_mix = if options.enable_monitor_mix {
+        Some(Arc::new(SharedMonitorMix::new(
+            stream_info.num_in_channels as usize,
+            stream_info.num_out_channels as usize,
+        )))
+    } else {
+        None
+    };
+
+    #[cfg(all(target_os = "linux", feature = "rtkit"))]
+    let rtkit_request = Arc::new(crate::rtkit::RtkitRequest::new());
+
     let process = JackProcessHandler::new(
         process_handler,
+        user_msg_rx,
+        Arc::clone(&to_stream_handle_tx),
         client_audio_in_ports,
         client_audio_out_ports,
+        audio_out_port_map.clone(),
         #[cfg(feature = "midi")]
         client_midi_in_ports,
         #[cfg(feature = "midi")]
         client_midi_out_ports,
+        #[cfg(feature = "midi")]
+        Arc::clone(&midi_shutdown),
+        block_size_stats.clone(),
+        monitor_mix.clone(),
+        #[cfg(all(target_os = "linux", feature = "rtkit"))]
+        Arc::clone(&rtkit_request),
         &stream_info,
+        options,
     );
 
     // --- Spawn Jack stream -----------------------------------------------------------------------
 
-    let (to_stream_handle_tx, from_audio_thread_rx) =
-        ringbuf::RingBuffer::new(options.msg_buffer_size).split();
-
     log::debug!("Activating Jack client...");
 
+    let glitch_counts = Arc::new(SharedGlitchCounts::new());
+
     // Activate the client, which starts the processing.
-    let async_client = client
-        .activate_async(JackNotificationHandler::new(to_stream_handle_tx, sample_rate), process)?;
+    let async_client = client.activate_async(
+        JackNotificationHandler::new(
+            Arc::clone(&to_stream_handle_tx),
+            Arc::clone(&glitch_counts),
+            sample_rate,
+        ),
+        process,
+    )?;
+
+    // Jack usually gives the process thread realtime priority itself, so this only
+    // does anything if it couldn't.
+    #[cfg(all(target_os = "linux", feature = "rtkit"))]
+    {
+        let to_stream_handle_tx = Arc::clone(&to_stream_handle_tx);
+        crate::rtkit::spawn_request(rtkit_request, options.require_realtime_priority, move |msg| {
+            push_stream_msg(&to_stream_handle_tx, msg)
+        });
+    }
 
     // --- Connect system audio ports to client ports ----------------------------------------------
 
-    for (in_port, system_in_port) in
-        client_audio_in_port_names.iter().zip(client_audio_in_connected_to.iter())
+    // Whether or not each client port was successfully connected to its system port.
+    let mut audio_in_opened = vec![false; client_audio_in_port_names.len()];
+    let mut audio_out_opened = vec![false; client_audio_out_port_names.len()];
+
+    for ((in_port, system_in_port), opened) in client_audio_in_port_names
+        .iter()
+        .zip(client_audio_in_connected_to.iter())
+        .zip(audio_in_opened.iter_mut())
     {
         if let Some(system_in_port) = &system_in_port {
             if let Err(e) = async_client.as_client().connect_ports_by_name(system_in_port, in_port)
@@ -454,11 +628,15 @@ pub fn run<P: ProcessHandler>(
                 if !options.empty_buffers_for_failed_ports {
                     return Err(RunConfigError::JackAudioPortNotFound(system_in_port.clone()));
                 }
+            } else {
+                *opened = true;
             }
         }
     }
-    for (out_port, system_out_port) in
-        client_audio_out_port_names.iter().zip(client_audio_out_connected_to.iter())
+    for ((out_port, system_out_port), opened) in client_audio_out_port_names
+        .iter()
+        .zip(client_audio_out_connected_to.iter())
+        .zip(audio_out_opened.iter_mut())
     {
         if let Some(system_out_port) = &system_out_port {
             if let Err(e) =
@@ -473,10 +651,47 @@ pub fn run<P: ProcessHandler>(
                 if !options.empty_buffers_for_failed_ports {
                     return Err(RunConfigError::JackAudioPortNotFound(system_out_port.clone()));
                 }
+            } else {
+                *opened = true;
             }
         }
     }
 
+    // Report the ports that failed to open, and update the stream info to match.
+    if let AudioDeviceStreamInfo::Jack { in_ports, out_ports } = &mut stream_info.audio_device {
+        let mut results = Vec::with_capacity(in_ports.len() + out_ports.len());
+
+        for (index, ((port_name, connected), opened)) in
+            in_ports.iter_mut().zip(audio_in_opened.iter()).enumerate()
+        {
+            *connected = *opened;
+            results.push(PortOpenResult {
+                is_input: true,
+                index,
+                port_name: port_name.clone(),
+                opened: *opened,
+            });
+        }
+        for (index, ((port_name, connected), client_port_i)) in
+            out_ports.iter_mut().zip(audio_out_port_map.iter()).enumerate()
+        {
+            *connected = audio_out_opened[*client_port_i];
+            results.push(PortOpenResult {
+                is_input: false,
+                index,
+                port_name: port_name.clone(),
+                opened: *connected,
+            });
+        }
+
+        if results.iter().any(|r| !r.opened) {
+            push_stream_msg(
+                &to_stream_handle_tx,
+                StreamMsg::ConfigWarning(ConfigWarning::PortsFailedToOpen(results)),
+            );
+        }
+    }
+
     // --- Connect system MIDI ports to client ports -----------------------------------------------
 
     #[cfg(feature = "midi")]
@@ -534,25 +749,119 @@ pub fn run<P: ProcessHandler>(
     }
 
     Ok(StreamHandle {
-        platform_handle: Box::new(JackStreamHandle { stream_info, async_client }),
+        platform_handle: Box::new(JackStreamHandle {
+            stream_info,
+            async_client,
+            #[cfg(feature = "midi")]
+            midi_shutdown,
+            _teardown: options.on_teardown.clone().map(TeardownGuard),
+        }),
         messages: from_audio_thread_rx,
+        user_messages: user_msg_tx,
+        block_size_stats,
+        glitch_counts,
+        monitor_mix,
     })
 }
 
-pub(crate) fn push_stream_msg(to_stream_handle_tx: &mut Producer<StreamMsg>, msg: StreamMsg) {
-    if let Err(e) = to_stream_handle_tx.push(msg) {
-        log::error!("Failed to send stream message {:?}: message buffer is full!", e);
+/// Send a message to the stream handle from a non-realtime thread.
+///
+/// This blocks while another thread is sending a message, so it must never be
+/// called from the Jack process thread. The process thread uses `try_lock()`
+/// instead.
+pub(crate) fn push_stream_msg(to_stream_handle_tx: &Mutex<Producer<StreamMsg>>, msg: StreamMsg) {
+    match to_stream_handle_tx.lock() {
+        Ok(mut to_stream_handle_tx) => {
+            if let Err(e) = to_stream_handle_tx.push(msg) {
+                log::error!("Failed to send stream message {:?}: message buffer is full!", e);
+            }
+        }
+        Err(_) => {
+            log::error!("Failed to send stream message {:?}: message buffer is poisoned!", msg);
+        }
     }
 }
 
-pub struct JackStreamHandle<P: ProcessHandler> {
+/// The stream is shut down in this order when the handle is dropped:
+///
+/// 1. Stop calling the process handler. The block that is currently being
+///    processed is finished first.
+/// 2. Send "all notes off" to every MIDI output port (MIDI is written by the
+///    same Jack process thread, so there is no separate MIDI thread to join).
+/// 3. Deactivate the client, which joins the Jack process thread.
+/// 4. Close the client, releasing its ports.
+/// 5. Call `RunOptions::on_teardown`.
+///
+/// Steps 3 and 4 happen when `async_client` is dropped.
+pub struct JackStreamHandle<P: ProcessHandler<M>, M: Send + 'static> {
     stream_info: StreamInfo,
-    async_client: jack::AsyncClient<JackNotificationHandler, JackProcessHandler<P>>,
+    async_client: jack::AsyncClient<JackNotificationHandler, JackProcessHandler<P, M>>,
+    #[cfg(feature = "midi")]
+    midi_shutdown: Arc<MidiShutdown>,
+    // This must be declared after `async_client` so that it is dropped after the
+    // client is closed.
+    _teardown: Option<TeardownGuard>,
+}
+
+#[cfg(feature = "midi")]
+impl<P: ProcessHandler<M>, M: Send + 'static> Drop for JackStreamHandle<P, M> {
+    fn drop(&mut self) {
+        // How long to wait for the process thread to flush the MIDI outputs. This
+        // is only reached if the process thread has already stopped.
+        const MIDI_FLUSH_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+
+        let has_midi_outputs =
+            self.stream_info.midi_info.as_ref().map(|m| !m.out_ports.is_empty()).unwrap_or(false);
+
+        self.midi_shutdown.stop_requested.store(true, Ordering::Release);
+
+        if has_midi_outputs {
+            let start = std::time::Instant::now();
+            while !self.midi_shutdown.flushed.load(Ordering::Acquire) {
+                if start.elapsed() > MIDI_FLUSH_TIMEOUT {
+                    log::warn!("Timed out while sending all notes off to Jack MIDI outputs");
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+        }
+    }
 }
 
-impl<P: ProcessHandler> PlatformStreamHandle<P> for JackStreamHandle<P> {
-    fn stream_info(&self) -> &StreamInfo {
-        &self.stream_info
+impl<P: ProcessHandler<M>, M: Send + 'static> PlatformStreamHandle<P, M>
+    for JackStreamHandle<P, M>
+{
+    fn stream_info(&self) -> StreamInfo {
+        self.stream_info.clone()
+    }
+
+    fn thread_scheduling(&self) -> Option<ThreadScheduling> {
+        let raw_client = self.async_client.as_client().raw();
+
+        // Jack creates the process thread itself, so ask the server what it
+        // actually granted to this client.
+        let (is_realtime, priority) = unsafe {
+            (
+                jack::jack_sys::jack_is_realtime(raw_client) != 0,
+                jack::jack_sys::jack_client_real_time_priority(raw_client),
+            )
+        };
+
+        // A priority of `-1` means that the thread is not running with realtime
+        // priority.
+        let rt_granted = is_realtime && priority >= 0;
+
+        #[cfg(not(target_os = "windows"))]
+        let policy = if rt_granted { "SCHED_FIFO" } else { "SCHED_OTHER" };
+        #[cfg(target_os = "windows")]
+        let policy =
+            if rt_granted { "THREAD_PRIORITY_TIME_CRITICAL" } else { "THREAD_PRIORITY_NORMAL" };
+
+        Some(ThreadScheduling {
+            policy: String::from(policy),
+            priority: if rt_granted { priority } else { 0 },
+            rt_granted,
+        })
     }
 
     fn change_jack_audio_ports(
@@ -584,6 +893,11 @@ impl<P: ProcessHandler> PlatformStreamHandle<P> for JackStreamHandle<P> {
     fn can_change_midi_ports(&self) -> bool {
         true
     }
+
+    #[cfg(feature = "raw-backend-handle")]
+    fn raw_backend_handle(&self) -> Option<crate::RawBackendHandle<'_>> {
+        Some(crate::RawBackendHandle::Jack(self.async_client.as_client()))
+    }
 }
 
 impl From<jack::Error> for RunConfigError {
@@ -0,0 +1,268 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::error::MeasureLatencyError;
+use crate::{run, ProcessHandler, ProcessInfo, RainoutConfig, RunOptions, StreamInfo, StreamMsg};
+
+/// How long to wait before sending the impulse, in seconds. This gives the
+/// device time to settle after starting.
+const SETTLE_SECS: u32 = 1;
+/// How long to listen for the impulse after it was sent, in seconds. This is
+/// the maximum round-trip latency that can be measured.
+const LISTEN_SECS: u32 = 1;
+/// The minimum amplitude of the returned impulse. Anything quieter is treated
+/// as noise.
+const DETECTION_THRESHOLD: f32 = 0.05;
+
+/// Measure the actual round-trip latency of a configuration in frames by
+/// sending an impulse through a physical loopback connection.
+///
+/// **This requires the first output channel of the device to be physically
+/// connected to its first input channel** (i.e. with a patch cable from the
+/// output jack to the input jack). The impulse is played out loud, so turn
+/// down or disconnect any speakers first.
+///
+/// A single-sample impulse is played on output channel 0 about one second
+/// after the stream starts, and input channel 0 is searched for the loudest
+/// sample within the following second (which is what cross-correlating the
+/// input with a single impulse reduces to). The returned latency is the
+/// number of frames between the output and input frame of the impulse, which
+/// includes every buffer, converter, and cable along the way.
+///
+/// This is usually more accurate than `StreamInfo::round_trip_latency()`,
+/// which relies on the latencies reported by the driver.
+pub fn measure_roundtrip_latency(
+    config: &RainoutConfig,
+    options: &RunOptions,
+) -> Result<u32, MeasureLatencyError> {
+    let shared = Arc::new(SharedMeasurement::new());
+
+    let handler = LoopbackHandler {
+        shared: Arc::clone(&shared),
+        impulse_frame: 0,
+        end_frame: 0,
+        peak_value: 0.0,
+        peak_frame: 0,
+    };
+
+    let mut stream_handle = run(config, options, handler)?;
+
+    let stream_info = stream_handle.stream_info();
+    if stream_info.num_in_channels == 0 || stream_info.num_out_channels == 0 {
+        return Err(MeasureLatencyError::NoLoopbackChannels);
+    }
+
+    // Allow for a generous amount of slack in case the stream is slow to start.
+    let timeout = Duration::from_secs(u64::from(SETTLE_SECS + LISTEN_SECS) + 5);
+    let start = Instant::now();
+
+    while !shared.done.load(Ordering::Acquire) {
+        while let Some(msg) = stream_handle.messages.pop() {
+            if let StreamMsg::Error(e) = msg {
+                return Err(MeasureLatencyError::StreamError(e));
+            }
+        }
+
+        if start.elapsed() > timeout {
+            return Err(MeasureLatencyError::TimedOut);
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    // The stream is closed when `stream_handle` is dropped.
+    drop(stream_handle);
+
+    let peak_value = f32::from_bits(shared.peak_value.load(Ordering::Acquire));
+    if peak_value < DETECTION_THRESHOLD {
+        return Err(MeasureLatencyError::NoImpulseDetected);
+    }
+
+    Ok(shared.latency_frames.load(Ordering::Acquire) as u32)
+}
+
+/// The result of the measurement, written by the audio thread.
+struct SharedMeasurement {
+    done: AtomicBool,
+    latency_frames: AtomicU64,
+    // The bits of the `f32` amplitude of the loudest input sample.
+    peak_value: AtomicU32,
+}
+
+impl SharedMeasurement {
+    fn new() -> Self {
+        Self {
+            done: AtomicBool::new(false),
+            latency_frames: AtomicU64::new(0),
+            peak_value: AtomicU32::new(0),
+        }
+    }
+}
+
+/// Sends an impulse on the first output and listens for it on the first
+/// input.
+struct LoopbackHandler {
+    shared: Arc<SharedMeasurement>,
+    impulse_frame: u64,
+    end_frame: u64,
+    peak_value: f32,
+    peak_frame: u64,
+}
+
+impl ProcessHandler for LoopbackHandler {
+    fn init(&mut self, stream_info: &StreamInfo) {
+        let sample_rate = u64::from(stream_info.sample_rate);
+
+        self.impulse_frame = sample_rate * u64::from(SETTLE_SECS);
+        self.end_frame = self.impulse_frame + sample_rate * u64::from(LISTEN_SECS);
+    }
+
+    fn stream_changed(&mut self, stream_info: &StreamInfo) {
+        // The timing is no longer valid, so start over.
+        self.peak_value = 0.0;
+        self.peak_frame = 0;
+        self.init(stream_info);
+    }
+
+    fn process<'a>(&mut self, proc_info: ProcessInfo<'a>) {
+        let block_start = proc_info.output_frame;
+        let frames = proc_info.frames as u64;
+
+        if let Some(out) = proc_info.audio_outputs.first_mut() {
+            out[0..proc_info.frames].fill(0.0);
+
+            if self.impulse_frame >= block_start && self.impulse_frame < block_start + frames {
+                out[(self.impulse_frame - block_start) as usize] = 1.0;
+            }
+        }
+
+        if self.shared.done.load(Ordering::Relaxed) {
+            return;
+        }
+
+        if let Some(input) = proc_info.audio_inputs.first() {
+            for (i, smp) in input[0..proc_info.frames].iter().enumerate() {
+                let frame = block_start + i as u64;
+                if frame >= self.impulse_frame && smp.abs() > self.peak_value {
+                    self.peak_value = smp.abs();
+                    self.peak_frame = frame;
+                }
+            }
+        }
+
+        if block_start + frames >= self.end_frame {
+            self.shared
+                .latency_frames
+                .store(self.peak_frame.saturating_sub(self.impulse_frame), Ordering::Relaxed);
+            self.shared.peak_value.store(self.peak_value.to_bits(), Ordering::Relaxed);
+            self.shared.done.store(true, Ordering::Release);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK_SIZE: usize = 64;
+
+    /// Runs the handler as if output channel 0 was connected to input channel 0
+    /// through a cable with the given latency (in frames) and gain, with
+    /// `noise` added to the input at the given frames. Returns the measured
+    /// latency and the amplitude of the detected impulse.
+    fn run_loopback(latency: usize, gain: f32, noise: &[(usize, f32)]) -> (u64, f32) {
+        let shared = Arc::new(SharedMeasurement::new());
+        let mut handler = LoopbackHandler {
+            shared: Arc::clone(&shared),
+            impulse_frame: 1_000,
+            end_frame: 2_000,
+            peak_value: 0.0,
+            peak_frame: 0,
+        };
+
+        let (_, mut user_msg_rx) = ringbuf::RingBuffer::<()>::new(1).split();
+        let mut played: Vec<f32> = Vec::new();
+        let mut output_frame = 0;
+
+        while !shared.done.load(Ordering::Acquire) {
+            let input: Vec<f32> = (output_frame..output_frame + BLOCK_SIZE)
+                .map(|frame| {
+                    let looped = frame
+                        .checked_sub(latency)
+                        .and_then(|f| played.get(f))
+                        .map(|smp| smp * gain)
+                        .unwrap_or(0.0);
+                    let noise: f32 =
+                        noise.iter().filter(|(f, _)| *f == frame).map(|(_, n)| n).sum();
+                    looped + noise
+                })
+                .collect();
+            let audio_inputs = [input];
+            let mut audio_outputs = [vec![0.5; BLOCK_SIZE], vec![0.5; BLOCK_SIZE]];
+
+            handler.process(ProcessInfo {
+                audio_inputs: &audio_inputs,
+                audio_outputs: &mut audio_outputs,
+                frames: BLOCK_SIZE,
+                output_frame: output_frame as u64,
+                is_first_block: output_frame == 0,
+                input_delay_frames: None,
+                silent_audio_inputs: &[false],
+                raw_audio_output: None,
+                #[cfg(feature = "midi")]
+                midi_inputs: &[],
+                #[cfg(feature = "midi")]
+                midi_outputs: &mut [],
+                user_msg_rx: &mut user_msg_rx,
+                scratch_buffers: &mut [],
+            });
+
+            // Only the first output channel is used, and it is otherwise silent.
+            assert_eq!(audio_outputs[1], vec![0.5; BLOCK_SIZE]);
+            played.extend_from_slice(&audio_outputs[0]);
+            output_frame += BLOCK_SIZE;
+
+            assert!(output_frame < 10_000, "the measurement never finished");
+        }
+
+        // Exactly one impulse was played, at the impulse frame.
+        assert_eq!(played.iter().filter(|smp| **smp != 0.0).count(), 1);
+        assert_eq!(played[1_000], 1.0);
+
+        (
+            shared.latency_frames.load(Ordering::Acquire),
+            f32::from_bits(shared.peak_value.load(Ordering::Acquire)),
+        )
+    }
+
+    #[test]
+    fn loopback_impulse_is_detected() {
+        // The round trip always takes at least one block.
+        for latency in [64, 65, 127, 128, 317, 999] {
+            assert_eq!(run_loopback(latency, 1.0, &[]), (latency as u64, 1.0));
+        }
+    }
+
+    #[test]
+    fn quiet_loopback_impulse_is_detected() {
+        let (latency, peak) = run_loopback(200, -0.25, &[(1_100, 0.01), (1_300, -0.02)]);
+        assert_eq!(latency, 200);
+        assert_eq!(peak, 0.25);
+        assert!(peak >= DETECTION_THRESHOLD);
+    }
+
+    #[test]
+    fn clicks_before_the_impulse_are_ignored() {
+        // i.e. a click from the device starting up.
+        let (latency, peak) = run_loopback(200, 0.5, &[(10, 1.0), (999, 1.0)]);
+        assert_eq!(latency, 200);
+        assert_eq!(peak, 0.5);
+    }
+
+    #[test]
+    fn missing_loopback_connection() {
+        let (_, peak) = run_loopback(0, 0.0, &[(1_500, 0.01)]);
+        assert!(peak < DETECTION_THRESHOLD);
+    }
+}
@@ -8,7 +8,7 @@ pub(crate) mod jack_backend;
 #[cfg(target_os = "windows")]
 pub(crate) mod wasapi_backend;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "serde-config", derive(serde::Serialize, serde::Deserialize))]
 /// The list of backends supported by rainout
 pub enum Backend {
@@ -32,21 +32,64 @@ impl Backend {
         }
     }
 
+    /// Returns `true` if this backend can pass audio inputs to the process
+    /// handler. For WASAPI, the inputs are a loopback capture of the output
+    /// device (see `RunOptions::wasapi_loopback_input`).
     pub fn supports_audio_inputs(&self) -> bool {
         match self {
             Backend::Jack => true,
             Backend::Pipewire => true,
             Backend::Alsa => true,
             Backend::CoreAudio => true,
-            Backend::Wasapi => false,
+            Backend::Wasapi => true,
+            Backend::Asio => true,
+        }
+    }
+
+    /// Returns `true` if this backend works best with block sizes that are a
+    /// power of two (see `BlockSizeRange::block_size_for_latency()`).
+    pub fn prefers_power_of_two_block_sizes(&self) -> bool {
+        match self {
+            Backend::Jack => false,
+            Backend::Pipewire => false,
+            Backend::Alsa => false,
+            Backend::CoreAudio => false,
+            Backend::Wasapi => true,
             Backend::Asio => true,
         }
     }
+
+    /// Returns `true` if this backend has a single system-wide device instead
+    /// of separate devices to select from (see `AudioDeviceConfig::SystemWide`).
+    pub fn has_system_wide_device(&self) -> bool {
+        match self {
+            Backend::Jack => true,
+            Backend::Pipewire => true,
+            Backend::Alsa => false,
+            Backend::CoreAudio => false,
+            Backend::Wasapi => false,
+            Backend::Asio => false,
+        }
+    }
 }
 
+mod audio_summary;
+mod audio_thread;
+mod block_size_stats;
+mod clip_detector;
+mod config_debouncer;
 mod configuration;
+mod device_cache;
+#[cfg(target_os = "windows")]
+mod dither;
 mod enumeration;
+mod glitch_counts;
+mod latency_measurement;
+mod monitor_mix;
+mod overload_detector;
 mod process_info;
+#[cfg(all(target_os = "linux", feature = "jack-linux", feature = "rtkit"))]
+mod rtkit;
 mod run;
 mod stream_info;
 mod stream_message;
@@ -54,10 +97,20 @@ mod stream_message;
 #[cfg(feature = "midi")]
 mod midi_buffer;
 
+#[cfg(feature = "testing")]
+mod clock;
+
 pub mod error;
 
+pub use audio_summary::*;
+pub use audio_thread::is_audio_thread;
+pub use block_size_stats::BlockSizeStats;
+pub use config_debouncer::*;
 pub use configuration::*;
+pub use device_cache::*;
 pub use enumeration::*;
+pub use glitch_counts::GlitchCounts;
+pub use latency_measurement::measure_roundtrip_latency;
 pub use process_info::*;
 pub use run::*;
 pub use stream_info::*;
@@ -65,3 +118,6 @@ pub use stream_message::*;
 
 #[cfg(feature = "midi")]
 pub use midi_buffer::*;
+
+#[cfg(feature = "testing")]
+pub use clock::*;
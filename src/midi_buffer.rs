@@ -1,4 +1,7 @@
+use std::sync::{Arc, Mutex};
+
 use crate::error::MidiBufferPushError;
+use crate::ProcessInfo;
 
 // TODO: Increase message size to allow more complex midi messages?
 pub const MAX_MIDI_MSG_SIZE: usize = 8;
@@ -42,6 +45,14 @@ impl RawMidi {
     }
 }
 
+fn is_note_off(event: &RawMidi) -> bool {
+    match event.data() {
+        [status, _, velocity, ..] if status & 0xF0 == 0x90 => *velocity == 0,
+        [status, ..] => status & 0xF0 == 0x80,
+        [] => false,
+    }
+}
+
 impl std::fmt::Debug for RawMidi {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -71,10 +82,38 @@ impl MidiBuffer {
         Self { events: Vec::with_capacity(buffer_size), max_len: buffer_size }
     }
 
+    /// The events in this buffer, in the order they were pushed.
+    ///
+    /// Events that share the same frame (i.e. the notes of a chord) keep the
+    /// order they arrived in, unless `RunOptions::midi_note_offs_first` is
+    /// enabled for the input buffers.
     pub fn events(&self) -> &[RawMidi] {
         &self.events
     }
 
+    /// Move every note-off (including a note-on with a velocity of `0`) in
+    /// front of the other events on the same frame, keeping the order of the
+    /// events otherwise.
+    ///
+    /// This is useful for synths that would otherwise cut off a note that is
+    /// re-triggered on the same frame it is released. This never allocates.
+    pub fn sort_note_offs_first(&mut self) {
+        // An insertion sort, since it is stable and doesn't allocate. The events are
+        // almost always already in order.
+        for i in 1..self.events.len() {
+            let mut j = i;
+            while j > 0 {
+                let (a, b) = (&self.events[j - 1], &self.events[j]);
+                if a.delta_frames == b.delta_frames && is_note_off(b) && !is_note_off(a) {
+                    self.events.swap(j - 1, j);
+                    j -= 1;
+                } else {
+                    break;
+                }
+            }
+        }
+    }
+
     pub fn clear(&mut self) {
         self.events.clear()
     }
@@ -142,3 +181,517 @@ impl MidiBuffer {
         self.max_len
     }
 }
+
+/// A pool of MIDI buffers that can be shared between streams.
+///
+/// Pass a clone of the same pool in `RunOptions::midi_buffer_pool` to each call
+/// to `run()`, and a restarted stream (i.e. to change the block size) will reuse
+/// the MIDI buffers of the previous stream instead of allocating new ones. The
+/// buffers are returned to the pool once the stream is dropped.
+#[derive(Debug, Clone, Default)]
+pub struct MidiBufferPool {
+    buffers: Arc<Mutex<Vec<MidiBuffer>>>,
+}
+
+impl MidiBufferPool {
+    /// Create an empty pool. Buffers are allocated by the first stream that
+    /// uses it.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a pool with `count` buffers that can each hold `buffer_size`
+    /// events (see `RunOptions::midi_buffer_size`).
+    pub fn with_buffers(count: usize, buffer_size: usize) -> Self {
+        let buffers = (0..count).map(|_| MidiBuffer::new(buffer_size)).collect();
+        Self { buffers: Arc::new(Mutex::new(buffers)) }
+    }
+
+    /// The number of buffers in the pool that are not currently used by a
+    /// stream.
+    pub fn num_available(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+
+    /// Take a buffer that can hold `buffer_size` events out of the pool,
+    /// allocating a new one only if no pooled buffer is large enough.
+    #[cfg_attr(
+        not(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows")),
+        allow(dead_code)
+    )]
+    pub(crate) fn take(&self, buffer_size: usize) -> MidiBuffer {
+        let mut buffers = self.buffers.lock().unwrap();
+
+        if let Some(i) = buffers.iter().position(|b| b.events.capacity() >= buffer_size) {
+            let mut buffer = buffers.swap_remove(i);
+            buffer.clear();
+            buffer.max_len = buffer_size;
+            buffer
+        } else {
+            MidiBuffer::new(buffer_size)
+        }
+    }
+
+    /// Return buffers to the pool so that the next stream can reuse them.
+    #[cfg_attr(
+        not(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows")),
+        allow(dead_code)
+    )]
+    pub(crate) fn give_back(&self, returned: impl IntoIterator<Item = MidiBuffer>) {
+        self.buffers.lock().unwrap().extend(returned);
+    }
+}
+
+/// Schedules MIDI events at absolute frame positions (which may fall in a
+/// future process cycle), and emits them into a MIDI output buffer at the
+/// correct offset once their process cycle arrives.
+#[derive(Debug)]
+pub struct MidiScheduler {
+    /// The scheduled events, sorted by their absolute frame.
+    events: Vec<(u64, RawMidi)>,
+    max_len: usize,
+    num_dropped: u64,
+}
+
+impl MidiScheduler {
+    /// Create a new scheduler that can hold up to `capacity` pending events.
+    ///
+    /// The memory is allocated up front so that pushing events never allocates.
+    pub fn new(capacity: usize) -> Self {
+        Self { events: Vec::with_capacity(capacity), max_len: capacity, num_dropped: 0 }
+    }
+
+    /// Schedule a MIDI event at the given absolute frame (in the same units as
+    /// `ProcessInfo::output_frame`).
+    ///
+    /// Events may be pushed in any order. Events that are scheduled at the
+    /// same frame will be emitted in the order they were pushed.
+    pub fn push(&mut self, abs_frame: u64, data: &[u8]) -> Result<(), MidiBufferPushError> {
+        if self.events.len() >= self.max_len {
+            return Err(MidiBufferPushError::BufferFull);
+        }
+
+        let event = RawMidi::new(0, data).map_err(MidiBufferPushError::EventTooLong)?;
+
+        let i = self.events.partition_point(|(frame, _)| *frame <= abs_frame);
+        self.events.insert(i, (abs_frame, event));
+
+        Ok(())
+    }
+
+    /// Emit every event that falls within the current process cycle into the
+    /// MIDI output buffer with the given index, and keep the rest for later.
+    ///
+    /// Any events whose frame has already passed are dropped and counted in
+    /// `num_dropped()`.
+    pub fn emit_for_block<M: Send + 'static>(
+        &mut self,
+        proc_info: &mut ProcessInfo<'_, M>,
+        midi_out_port: usize,
+    ) {
+        let block_start = proc_info.output_frame;
+        let block_end = block_start + proc_info.frames as u64;
+
+        let num_past_due = self.events.partition_point(|(frame, _)| *frame < block_start);
+        let num_due = self.events.partition_point(|(frame, _)| *frame < block_end);
+
+        self.num_dropped += num_past_due as u64;
+
+        if let Some(buffer) = proc_info.midi_outputs.get_mut(midi_out_port) {
+            for (frame, event) in self.events[num_past_due..num_due].iter() {
+                let mut event = *event;
+                event.delta_frames = (*frame - block_start) as u32;
+
+                if buffer.push(event).is_err() {
+                    self.num_dropped += 1;
+                }
+            }
+        } else {
+            self.num_dropped += (num_due - num_past_due) as u64;
+        }
+
+        self.events.drain(0..num_due);
+    }
+
+    /// The number of events still waiting to be emitted.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Returns `true` if there are no events waiting to be emitted.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// The total number of events that were dropped because their frame had
+    /// already passed or because the output buffer was full.
+    #[must_use]
+    pub fn num_dropped(&self) -> u64 {
+        self.num_dropped
+    }
+
+    /// Remove all scheduled events.
+    pub fn clear(&mut self) {
+        self.events.clear()
+    }
+}
+
+/// An iterator over the events of multiple MIDI buffers in ascending frame
+/// order, created with `ProcessInfo::merged_midi_input()`.
+///
+/// Events on the same frame are yielded in the order of their buffers, and
+/// then in the order they appear in their buffer.
+///
+/// This does not allocate, at the cost of scanning every event for each event
+/// that is yielded. This is fine for the small number of MIDI events in a
+/// typical process cycle.
+pub struct MergedMidiEvents<'a> {
+    buffers: &'a [MidiBuffer],
+    // The (frame, buffer index, event index) of the last event that was yielded.
+    last: Option<(u32, usize, usize)>,
+}
+
+impl<'a> MergedMidiEvents<'a> {
+    pub(crate) fn new(buffers: &'a [MidiBuffer]) -> Self {
+        Self { buffers, last: None }
+    }
+}
+
+impl<'a> Iterator for MergedMidiEvents<'a> {
+    /// The frame of the event relative to the start of the process cycle, the
+    /// index of the buffer the event came from, and the raw MIDI data.
+    type Item = (u32, usize, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut next: Option<(u32, usize, usize)> = None;
+
+        for (buffer_i, buffer) in self.buffers.iter().enumerate() {
+            for (event_i, event) in buffer.events().iter().enumerate() {
+                let key = (event.delta_frames, buffer_i, event_i);
+
+                if self.last.map(|last| key > last).unwrap_or(true)
+                    && next.map(|next| key < next).unwrap_or(true)
+                {
+                    next = Some(key);
+                }
+            }
+        }
+
+        self.last = next;
+
+        next.map(|(frame, buffer_i, event_i)| {
+            (frame, buffer_i, self.buffers[buffer_i].events()[event_i].data())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `emit_for_block()` for the block starting at `output_frame`, and
+    /// return the `(delta_frames, data)` of each emitted event.
+    fn emit(
+        scheduler: &mut MidiScheduler,
+        output_frame: u64,
+        frames: usize,
+        out_buffer_size: usize,
+    ) -> Vec<(u32, Vec<u8>)> {
+        let (_, mut user_msg_rx) = ringbuf::RingBuffer::<()>::new(1).split();
+        let mut midi_outputs = [MidiBuffer::new(out_buffer_size)];
+
+        let mut proc_info = ProcessInfo {
+            audio_inputs: &[],
+            audio_outputs: &mut [],
+            frames,
+            output_frame,
+            is_first_block: false,
+            input_delay_frames: None,
+            silent_audio_inputs: &[],
+            raw_audio_output: None,
+            midi_inputs: &[],
+            midi_outputs: &mut midi_outputs,
+            user_msg_rx: &mut user_msg_rx,
+            scratch_buffers: &mut [],
+        };
+
+        scheduler.emit_for_block(&mut proc_info, 0);
+
+        midi_outputs[0].events().iter().map(|e| (e.delta_frames, e.data().to_vec())).collect()
+    }
+
+    #[test]
+    fn scheduler_sorts_out_of_order_events() {
+        let mut scheduler = MidiScheduler::new(8);
+        scheduler.push(110, &[0x90, 62, 100]).unwrap();
+        scheduler.push(100, &[0x90, 60, 100]).unwrap();
+        scheduler.push(110, &[0x90, 64, 100]).unwrap();
+        scheduler.push(105, &[0x90, 61, 100]).unwrap();
+
+        assert_eq!(
+            emit(&mut scheduler, 100, 64, 8),
+            vec![
+                (0, vec![0x90, 60, 100]),
+                (5, vec![0x90, 61, 100]),
+                // Events on the same frame keep the order they were pushed in.
+                (10, vec![0x90, 62, 100]),
+                (10, vec![0x90, 64, 100]),
+            ]
+        );
+        assert!(scheduler.is_empty());
+        assert_eq!(scheduler.num_dropped(), 0);
+    }
+
+    #[test]
+    fn scheduler_keeps_future_events() {
+        let mut scheduler = MidiScheduler::new(8);
+        scheduler.push(63, &[0x90, 60, 100]).unwrap();
+        scheduler.push(64, &[0x80, 60, 0]).unwrap();
+        scheduler.push(200, &[0x90, 62, 100]).unwrap();
+
+        assert_eq!(emit(&mut scheduler, 0, 64, 8), vec![(63, vec![0x90, 60, 100])]);
+        assert_eq!(scheduler.len(), 2);
+
+        // The block end is exclusive, so the event at frame 64 is in the next block.
+        assert_eq!(emit(&mut scheduler, 64, 64, 8), vec![(0, vec![0x80, 60, 0])]);
+        assert_eq!(emit(&mut scheduler, 128, 64, 8), vec![]);
+        assert_eq!(emit(&mut scheduler, 192, 64, 8), vec![(8, vec![0x90, 62, 100])]);
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn scheduler_drops_past_due_events() {
+        let mut scheduler = MidiScheduler::new(8);
+        scheduler.push(10, &[0x90, 60, 100]).unwrap();
+        scheduler.push(20, &[0x90, 62, 100]).unwrap();
+        scheduler.push(130, &[0x90, 64, 100]).unwrap();
+
+        assert_eq!(emit(&mut scheduler, 128, 64, 8), vec![(2, vec![0x90, 64, 100])]);
+        assert_eq!(scheduler.num_dropped(), 2);
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn scheduler_capacity() {
+        let mut scheduler = MidiScheduler::new(2);
+        scheduler.push(0, &[0x90, 60, 100]).unwrap();
+        scheduler.push(1, &[0x90, 62, 100]).unwrap();
+
+        assert!(matches!(
+            scheduler.push(2, &[0x90, 64, 100]),
+            Err(MidiBufferPushError::BufferFull)
+        ));
+        assert!(matches!(
+            scheduler.push(0, &[0; MAX_MIDI_MSG_SIZE + 1]),
+            Err(MidiBufferPushError::BufferFull)
+        ));
+        assert_eq!(scheduler.len(), 2);
+
+        scheduler.clear();
+        assert!(matches!(
+            scheduler.push(0, &[0; MAX_MIDI_MSG_SIZE + 1]),
+            Err(MidiBufferPushError::EventTooLong(len)) if len == MAX_MIDI_MSG_SIZE + 1
+        ));
+    }
+
+    #[test]
+    fn scheduler_counts_events_dropped_by_a_full_output_buffer() {
+        let mut scheduler = MidiScheduler::new(8);
+        for frame in 0..3 {
+            scheduler.push(frame, &[0x90, 60, 100]).unwrap();
+        }
+
+        assert_eq!(emit(&mut scheduler, 0, 64, 2).len(), 2);
+        assert_eq!(scheduler.num_dropped(), 1);
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn pool_reuses_returned_buffers() {
+        let pool = MidiBufferPool::new();
+        assert_eq!(pool.num_available(), 0);
+
+        // An empty pool allocates new buffers.
+        let mut buffer = pool.take(16);
+        assert_eq!(buffer.max_len(), 16);
+        buffer.push_raw(0, &[0x90, 60, 100]).unwrap();
+        let ptr = buffer.events().as_ptr();
+
+        // A clone of the pool shares its buffers, i.e. with the next stream.
+        let next_stream_pool = pool.clone();
+        pool.give_back([buffer]);
+        assert_eq!(next_stream_pool.num_available(), 1);
+
+        // The same allocation is reused, and it is cleared first.
+        let buffer = next_stream_pool.take(16);
+        assert_eq!(buffer.events().as_ptr(), ptr);
+        assert!(buffer.is_empty());
+        assert_eq!(pool.num_available(), 0);
+    }
+
+    #[test]
+    fn pool_only_reuses_buffers_that_are_large_enough() {
+        let pool = MidiBufferPool::with_buffers(2, 8);
+        assert_eq!(pool.num_available(), 2);
+
+        // A larger buffer is allocated without taking a pooled one.
+        let large = pool.take(32);
+        assert_eq!(large.max_len(), 32);
+        assert_eq!(pool.num_available(), 2);
+
+        // A smaller buffer reuses a pooled one, and is limited to the size
+        // that was asked for.
+        let mut small = pool.take(4);
+        assert_eq!(pool.num_available(), 1);
+        assert_eq!(small.max_len(), 4);
+        for frame in 0..4 {
+            small.push_raw(frame, &[0x90, 60, 100]).unwrap();
+        }
+        assert!(matches!(
+            small.push_raw(4, &[0x90, 60, 100]),
+            Err(MidiBufferPushError::BufferFull)
+        ));
+
+        pool.give_back([large, small]);
+        assert_eq!(pool.num_available(), 3);
+
+        let buffer = pool.take(32);
+        assert_eq!(buffer.max_len(), 32);
+        assert_eq!(pool.num_available(), 2);
+    }
+
+    /// Returns the `(delta_frames, data)` of each event in `buffer`.
+    fn events(buffer: &MidiBuffer) -> Vec<(u32, Vec<u8>)> {
+        buffer.events().iter().map(|e| (e.delta_frames, e.data().to_vec())).collect()
+    }
+
+    #[test]
+    fn sort_note_offs_first_on_the_same_frame() {
+        let mut buffer = MidiBuffer::new(16);
+        buffer.push_raw(0, &[0x90, 60, 100]).unwrap();
+        // A note is re-triggered on the same frame it is released.
+        buffer.push_raw(10, &[0x90, 60, 100]).unwrap();
+        buffer.push_raw(10, &[0xB0, 64, 127]).unwrap();
+        buffer.push_raw(10, &[0x80, 60, 0]).unwrap();
+        // A note-on with a velocity of 0 is a note-off.
+        buffer.push_raw(10, &[0x91, 62, 0]).unwrap();
+        buffer.push_raw(20, &[0x80, 64, 0]).unwrap();
+
+        buffer.sort_note_offs_first();
+
+        assert_eq!(
+            events(&buffer),
+            vec![
+                (0, vec![0x90, 60, 100]),
+                // The note-offs keep their order, and so do the other events.
+                (10, vec![0x80, 60, 0]),
+                (10, vec![0x91, 62, 0]),
+                (10, vec![0x90, 60, 100]),
+                (10, vec![0xB0, 64, 127]),
+                (20, vec![0x80, 64, 0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn sort_note_offs_first_never_moves_events_across_frames() {
+        let mut buffer = MidiBuffer::new(16);
+        buffer.push_raw(5, &[0x90, 60, 100]).unwrap();
+        buffer.push_raw(6, &[0x80, 60, 0]).unwrap();
+        buffer.push_raw(7, &[0xF8]).unwrap();
+        buffer.push_raw(7, &[]).unwrap();
+        buffer.push_raw(7, &[0x80]).unwrap();
+
+        let before = events(&buffer);
+        buffer.sort_note_offs_first();
+
+        // A truncated note-off still counts as a note-off.
+        assert_eq!(
+            events(&buffer),
+            vec![
+                before[0].clone(),
+                before[1].clone(),
+                before[4].clone(),
+                before[2].clone(),
+                before[3].clone(),
+            ]
+        );
+
+        // Sorting again doesn't change anything.
+        let sorted = events(&buffer);
+        buffer.sort_note_offs_first();
+        assert_eq!(events(&buffer), sorted);
+    }
+
+    fn buffer(events: &[(u32, &[u8])]) -> MidiBuffer {
+        let mut buffer = MidiBuffer::new(16);
+        for (frame, data) in events.iter() {
+            buffer.push_raw(*frame, data).unwrap();
+        }
+        buffer
+    }
+
+    #[test]
+    fn merged_midi_events_are_in_frame_order() {
+        let buffers = [
+            buffer(&[(0, &[0x90, 60, 100]), (32, &[0x80, 60, 0]), (32, &[0x90, 62, 100])]),
+            buffer(&[]),
+            buffer(&[(16, &[0xB0, 1, 64]), (32, &[0xB0, 1, 0]), (63, &[0xF8])]),
+        ];
+
+        assert_eq!(
+            MergedMidiEvents::new(&buffers).collect::<Vec<_>>(),
+            vec![
+                (0, 0, &[0x90, 60, 100][..]),
+                (16, 2, &[0xB0, 1, 64][..]),
+                // Events on the same frame are in the order of their buffers,
+                // and then in the order of their buffer.
+                (32, 0, &[0x80, 60, 0][..]),
+                (32, 0, &[0x90, 62, 100][..]),
+                (32, 2, &[0xB0, 1, 0][..]),
+                (63, 2, &[0xF8][..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn merged_midi_events_sort_unordered_buffers() {
+        let buffers = [
+            buffer(&[(20, &[0x90, 64, 100]), (10, &[0x90, 60, 100])]),
+            buffer(&[(15, &[0x90, 62, 100])]),
+        ];
+
+        let frames: Vec<(u32, usize)> =
+            MergedMidiEvents::new(&buffers).map(|(frame, buffer_i, _)| (frame, buffer_i)).collect();
+        assert_eq!(frames, vec![(10, 0), (15, 1), (20, 0)]);
+    }
+
+    #[test]
+    fn merged_midi_input_of_process_info() {
+        let (_, mut user_msg_rx) = ringbuf::RingBuffer::<()>::new(1).split();
+        let midi_inputs = [buffer(&[(4, &[0x90, 60, 100])]), buffer(&[(2, &[0x80, 60, 0])])];
+
+        let proc_info = ProcessInfo {
+            audio_inputs: &[],
+            audio_outputs: &mut [],
+            frames: 64,
+            output_frame: 0,
+            is_first_block: false,
+            input_delay_frames: None,
+            silent_audio_inputs: &[],
+            raw_audio_output: None,
+            midi_inputs: &midi_inputs,
+            midi_outputs: &mut [],
+            user_msg_rx: &mut user_msg_rx,
+            scratch_buffers: &mut [],
+        };
+
+        assert_eq!(
+            proc_info.merged_midi_input().collect::<Vec<_>>(),
+            vec![(2, 1, &[0x80, 60, 0][..]), (4, 0, &[0x90, 60, 100][..])]
+        );
+        assert_eq!(MergedMidiEvents::new(&[]).next(), None);
+    }
+}
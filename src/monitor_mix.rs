@@ -0,0 +1,156 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// The gain of every input channel into every output channel, shared between
+/// the stream handle and the audio thread.
+///
+/// Each gain is stored as the bits of an `f32` in an atomic, so updates from
+/// the host never block the audio thread.
+pub(crate) struct SharedMonitorMix {
+    num_in_channels: usize,
+    num_out_channels: usize,
+    // Indexed as `[in_channel * num_out_channels + out_channel]`.
+    gains: Vec<AtomicU32>,
+}
+
+impl SharedMonitorMix {
+    /// Create a new monitor mix where every gain is `0.0` (muted).
+    #[cfg_attr(
+        not(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows")),
+        allow(dead_code)
+    )]
+    pub fn new(num_in_channels: usize, num_out_channels: usize) -> Self {
+        Self {
+            num_in_channels,
+            num_out_channels,
+            gains: (0..num_in_channels * num_out_channels)
+                .map(|_| AtomicU32::new(0.0f32.to_bits()))
+                .collect(),
+        }
+    }
+
+    /// Set the gain of the given input channel into the given output channel.
+    ///
+    /// This returns an error if either channel does not exist.
+    pub fn set(&self, in_channel: usize, out_channel: usize, gain: f32) -> Result<(), ()> {
+        if in_channel >= self.num_in_channels || out_channel >= self.num_out_channels {
+            return Err(());
+        }
+
+        let gain = if gain.is_finite() { gain } else { 0.0 };
+        self.gains[in_channel * self.num_out_channels + out_channel]
+            .store(gain.to_bits(), Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Sum the inputs into the outputs with their gains. This is realtime-safe.
+    #[cfg_attr(
+        not(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows")),
+        allow(dead_code)
+    )]
+    pub fn apply(&self, audio_inputs: &[Vec<f32>], audio_outputs: &mut [Vec<f32>], frames: usize) {
+        for (in_i, input) in audio_inputs.iter().enumerate().take(self.num_in_channels) {
+            for (out_i, output) in audio_outputs.iter_mut().enumerate().take(self.num_out_channels)
+            {
+                let gain = f32::from_bits(
+                    self.gains[in_i * self.num_out_channels + out_i].load(Ordering::Relaxed),
+                );
+                if gain == 0.0 {
+                    continue;
+                }
+
+                let frames = frames.min(input.len()).min(output.len());
+                for (out_smp, in_smp) in output[0..frames].iter_mut().zip(input[0..frames].iter()) {
+                    *out_smp += *in_smp * gain;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn monitor_mix_is_muted_by_default() {
+        let mix = SharedMonitorMix::new(2, 2);
+        let inputs = vec![vec![1.0; 4], vec![-1.0; 4]];
+        let mut outputs = vec![vec![0.25; 4], vec![0.0; 4]];
+
+        mix.apply(&inputs, &mut outputs, 4);
+
+        // The process handler's output is left as is.
+        assert_eq!(outputs, vec![vec![0.25; 4], vec![0.0; 4]]);
+    }
+
+    #[test]
+    fn monitor_mix_sums_inputs_with_their_gains() {
+        let mix = SharedMonitorMix::new(2, 2);
+        mix.set(0, 0, 0.5).unwrap();
+        mix.set(1, 0, 0.25).unwrap();
+        mix.set(1, 1, -1.0).unwrap();
+
+        let inputs = vec![vec![1.0, 0.5], vec![0.5, -1.0]];
+        let mut outputs = vec![vec![0.25, 0.25], vec![0.0, 0.0]];
+
+        mix.apply(&inputs, &mut outputs, 2);
+
+        // out_0 = 0.25 + 0.5 * in_0 + 0.25 * in_1
+        // out_1 = -1.0 * in_1
+        assert_eq!(outputs, vec![vec![0.875, 0.25], vec![-0.5, 1.0]]);
+    }
+
+    #[test]
+    fn monitor_mix_only_applies_to_the_given_frames() {
+        let mix = SharedMonitorMix::new(1, 1);
+        mix.set(0, 0, 1.0).unwrap();
+
+        let inputs = vec![vec![1.0; 4]];
+        let mut outputs = vec![vec![0.0; 4]];
+        mix.apply(&inputs, &mut outputs, 2);
+        assert_eq!(outputs, vec![vec![1.0, 1.0, 0.0, 0.0]]);
+
+        // A shorter buffer than `frames` is never indexed out of bounds.
+        let mut outputs = vec![vec![0.0; 1]];
+        mix.apply(&inputs, &mut outputs, 4);
+        assert_eq!(outputs, vec![vec![1.0]]);
+    }
+
+    #[test]
+    fn monitor_mix_gain_can_be_changed() {
+        let mix = SharedMonitorMix::new(1, 1);
+        let inputs = vec![vec![1.0]];
+
+        mix.set(0, 0, 2.0).unwrap();
+        let mut outputs = vec![vec![0.0]];
+        mix.apply(&inputs, &mut outputs, 1);
+        assert_eq!(outputs, vec![vec![2.0]]);
+
+        mix.set(0, 0, 0.0).unwrap();
+        let mut outputs = vec![vec![0.0]];
+        mix.apply(&inputs, &mut outputs, 1);
+        assert_eq!(outputs, vec![vec![0.0]]);
+    }
+
+    #[test]
+    fn monitor_mix_non_finite_gains_are_muted() {
+        let mix = SharedMonitorMix::new(1, 1);
+        let inputs = vec![vec![1.0]];
+
+        for gain in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            mix.set(0, 0, gain).unwrap();
+            let mut outputs = vec![vec![0.5]];
+            mix.apply(&inputs, &mut outputs, 1);
+            assert_eq!(outputs, vec![vec![0.5]]);
+        }
+    }
+
+    #[test]
+    fn monitor_mix_rejects_channels_that_do_not_exist() {
+        let mix = SharedMonitorMix::new(2, 1);
+        assert_eq!(mix.set(2, 0, 1.0), Err(()));
+        assert_eq!(mix.set(0, 1, 1.0), Err(()));
+        assert_eq!(mix.set(1, 0, 1.0), Ok(()));
+    }
+}
@@ -0,0 +1,170 @@
+use ringbuf::Producer;
+use std::time::{Duration, Instant};
+
+use crate::StreamMsg;
+
+/// Measures how long each call to `process()` takes, and reports when the
+/// process handler is too slow for several blocks in a row.
+pub(crate) struct OverloadDetector {
+    secs_per_frame: f64,
+    threshold: f64,
+    num_blocks: u32,
+    overloaded_blocks: u32,
+    reported: bool,
+    process_start: Option<Instant>,
+}
+
+impl OverloadDetector {
+    /// Create a new overload detector.
+    ///
+    /// A block counts as overloaded if `process()` takes longer than
+    /// `threshold` times the duration of that block. An overload is reported
+    /// after `num_blocks` overloaded blocks in a row.
+    pub fn new(sample_rate: u32, threshold: f64, num_blocks: u32) -> Self {
+        Self {
+            secs_per_frame: 1.0 / f64::from(sample_rate.max(1)),
+            threshold,
+            num_blocks: num_blocks.max(1),
+            overloaded_blocks: 0,
+            reported: false,
+            process_start: None,
+        }
+    }
+
+    /// Call this right before calling `process()`.
+    pub fn begin(&mut self) {
+        self.process_start = Some(Instant::now());
+    }
+
+    /// Call this right after `process()` returns.
+    pub fn end(&mut self, frames: usize) {
+        if let Some(start) = self.process_start.take() {
+            self.record(frames, start.elapsed());
+        }
+    }
+
+    /// Record that `process()` took `elapsed` to process `frames` frames.
+    fn record(&mut self, frames: usize, elapsed: Duration) {
+        let deadline =
+            Duration::from_secs_f64(frames as f64 * self.secs_per_frame * self.threshold);
+
+        if elapsed > deadline {
+            self.overloaded_blocks = self.overloaded_blocks.saturating_add(1);
+        } else {
+            // Processing has recovered, so report the next overload again.
+            self.overloaded_blocks = 0;
+            self.reported = false;
+        }
+    }
+
+    /// Returns `true` if the process handler has been overloaded for enough
+    /// blocks in a row, and this overload has not been reported yet.
+    pub fn is_report_due(&self) -> bool {
+        !self.reported && self.overloaded_blocks >= self.num_blocks
+    }
+
+    /// Send a `StreamMsg::ProcessOverload` message.
+    pub fn report(&mut self, to_stream_handle_tx: &mut Producer<StreamMsg>) {
+        if let Err(e) = to_stream_handle_tx.push(StreamMsg::ProcessOverload) {
+            log::error!("Failed to send stream message {:?}: message buffer is full!", e);
+        }
+
+        self.reported = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ringbuf::{Consumer, RingBuffer};
+
+    /// The duration of a 480 frame block at 48 kHz.
+    const BLOCK: Duration = Duration::from_millis(10);
+
+    fn num_overloads(rx: &mut Consumer<StreamMsg>) -> usize {
+        let mut count = 0;
+        while let Some(msg) = rx.pop() {
+            assert!(matches!(msg, StreamMsg::ProcessOverload), "unexpected message {:?}", msg);
+            count += 1;
+        }
+        count
+    }
+
+    /// Records a block that took `elapsed`, and reports it if it is due.
+    fn process_block(
+        detector: &mut OverloadDetector,
+        elapsed: Duration,
+        tx: &mut Producer<StreamMsg>,
+    ) {
+        detector.record(480, elapsed);
+        if detector.is_report_due() {
+            detector.report(tx);
+        }
+    }
+
+    #[test]
+    fn overload_is_reported_after_enough_blocks_in_a_row() {
+        let (mut tx, mut rx) = RingBuffer::<StreamMsg>::new(16).split();
+        let mut detector = OverloadDetector::new(48_000, 0.9, 3);
+
+        // Two slow blocks are not enough.
+        process_block(&mut detector, BLOCK, &mut tx);
+        process_block(&mut detector, BLOCK, &mut tx);
+        assert_eq!(num_overloads(&mut rx), 0);
+
+        // A fast block resets the count.
+        process_block(&mut detector, BLOCK / 2, &mut tx);
+        process_block(&mut detector, BLOCK, &mut tx);
+        process_block(&mut detector, BLOCK, &mut tx);
+        assert_eq!(num_overloads(&mut rx), 0);
+
+        process_block(&mut detector, BLOCK, &mut tx);
+        assert_eq!(num_overloads(&mut rx), 1);
+    }
+
+    #[test]
+    fn overload_is_reported_once_until_processing_recovers() {
+        let (mut tx, mut rx) = RingBuffer::<StreamMsg>::new(16).split();
+        let mut detector = OverloadDetector::new(48_000, 0.9, 2);
+
+        for _ in 0..10 {
+            process_block(&mut detector, BLOCK * 2, &mut tx);
+        }
+        assert_eq!(num_overloads(&mut rx), 1);
+
+        process_block(&mut detector, BLOCK / 10, &mut tx);
+        for _ in 0..10 {
+            process_block(&mut detector, BLOCK * 2, &mut tx);
+        }
+        assert_eq!(num_overloads(&mut rx), 1);
+    }
+
+    #[test]
+    fn overload_threshold_is_relative_to_the_block_duration() {
+        let (mut tx, mut rx) = RingBuffer::<StreamMsg>::new(16).split();
+        let mut detector = OverloadDetector::new(48_000, 0.5, 1);
+
+        // 40% of the block is fine, 60% is not.
+        process_block(&mut detector, BLOCK * 4 / 10, &mut tx);
+        assert_eq!(num_overloads(&mut rx), 0);
+        process_block(&mut detector, BLOCK * 6 / 10, &mut tx);
+        assert_eq!(num_overloads(&mut rx), 1);
+
+        // The same time is fine for a block that is twice as long.
+        let mut detector = OverloadDetector::new(48_000, 0.5, 1);
+        detector.record(960, BLOCK * 6 / 10);
+        assert!(!detector.is_report_due());
+    }
+
+    #[test]
+    fn end_without_begin_is_ignored() {
+        let mut detector = OverloadDetector::new(48_000, 0.5, 1);
+        detector.end(480);
+        assert!(!detector.is_report_due());
+
+        detector.begin();
+        detector.end(480);
+        detector.end(480);
+        assert!(!detector.is_report_due());
+    }
+}
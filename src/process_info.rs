@@ -1,12 +1,20 @@
+use ringbuf::Consumer;
+
 #[cfg(feature = "midi")]
-use crate::MidiBuffer;
+use crate::{MergedMidiEvents, MidiBuffer};
 
 /// The audio and MIDI buffers for this process cycle.
-pub struct ProcessInfo<'a> {
+pub struct ProcessInfo<'a, M: Send + 'static = ()> {
     /// The audio input buffers.
+    ///
+    /// There is one buffer per channel of the device (or per Jack port), in
+    /// the same order as the device's channels. No remapping is done.
     pub audio_inputs: &'a [Vec<f32>],
 
     /// The audio output buffers.
+    ///
+    /// There is one buffer per channel of the device (or per Jack port), in
+    /// the same order as the device's channels. No remapping is done.
     pub audio_outputs: &'a mut [Vec<f32>],
 
     /// The number of audio frames in this process cycle.
@@ -15,6 +23,26 @@ pub struct ProcessInfo<'a> {
     /// `audio_outputs` will have a length of at-least this size.
     pub frames: usize,
 
+    /// The position of the first frame in this process cycle, counted in
+    /// frames since the stream was started.
+    pub output_frame: u64,
+
+    /// This is `true` only for the first call to `process()` after
+    /// `ProcessHandler::init()` or `ProcessHandler::stream_changed()`.
+    ///
+    /// Use this to reset any state that depends on the previous blocks (i.e.
+    /// clearing reverb tails or resetting filters), since there is a
+    /// discontinuity in the audio.
+    pub is_first_block: bool,
+
+    /// The number of frames that the audio inputs in this process cycle are
+    /// delayed relative to the audio outputs (the round-trip latency of the
+    /// stream), if it is known.
+    ///
+    /// This can be used to align recorded audio with the audio that was being
+    /// played back at the time.
+    pub input_delay_frames: Option<u32>,
+
     /// For each audio input buffer in order, this will return true
     /// if every sample in that buffer is `0.0`, false otherwise.
     ///
@@ -24,6 +52,18 @@ pub struct ProcessInfo<'a> {
     /// is false, then these values will always be false.
     pub silent_audio_inputs: &'a [bool],
 
+    /// The device's output buffer for this process cycle, in the device's
+    /// native sample format (see `StreamInfo::native_format`).
+    ///
+    /// This is only `Some` when `RunOptions::raw_passthrough` is enabled and
+    /// the backend supports it. In that case `audio_outputs` is empty, and the
+    /// bytes written here are sent to the device as-is, with no conversion,
+    /// dither, or clip detection.
+    ///
+    /// This buffer is filled with zeros before each call to `process()`, even
+    /// when `RunOptions::zero_unwritten_outputs` is false.
+    pub raw_audio_output: Option<&'a mut [u8]>,
+
     #[cfg(feature = "midi")]
     /// The MIDI input buffers.
     pub midi_inputs: &'a [MidiBuffer],
@@ -31,4 +71,61 @@ pub struct ProcessInfo<'a> {
     #[cfg(feature = "midi")]
     /// The MIDI output buffers.
     pub midi_outputs: &'a mut [MidiBuffer],
+
+    pub(crate) user_msg_rx: &'a mut Consumer<M>,
+    pub(crate) scratch_buffers: &'a mut [Vec<f32>],
+}
+
+impl<'a, M: Send + 'static> ProcessInfo<'a, M> {
+    /// The messages sent from the host through `StreamHandle::user_messages`.
+    ///
+    /// Drain this once per call to `process()` to apply any pending parameter
+    /// changes for this block.
+    pub fn user_messages(&mut self) -> &mut Consumer<M> {
+        self.user_msg_rx
+    }
+
+    /// Returns the number of audio input and output channels as
+    /// `(num_in_channels, num_out_channels)`.
+    ///
+    /// These are independent of each other. When the input and output come
+    /// from different devices (i.e. `AudioDeviceConfig::LinkedInOut` with a
+    /// stereo microphone and an 8 channel output), then these will differ, so
+    /// never index the output buffers with the number of input buffers (or
+    /// vice versa).
+    pub fn channel_counts(&self) -> (usize, usize) {
+        (self.audio_inputs.len(), self.audio_outputs.len())
+    }
+
+    #[cfg(feature = "midi")]
+    /// Iterate over the events of every MIDI input buffer in ascending frame
+    /// order, as `(frame, index of the input buffer, raw MIDI data)`.
+    ///
+    /// Events on the same frame are ordered by the index of their input
+    /// buffer, so the ordering is stable.
+    pub fn merged_midi_input(&self) -> MergedMidiEvents<'a> {
+        MergedMidiEvents::new(self.midi_inputs)
+    }
+
+    /// Returns `channels` zeroed buffers of length `frames` to use for
+    /// intermediate work, without allocating on the audio thread.
+    ///
+    /// At most `RunOptions::num_scratch_buffers` buffers are available, so the
+    /// returned slice may be shorter than `channels`. Do not grow these
+    /// buffers past `frames`, as that may allocate.
+    ///
+    /// The contents of these buffers are not preserved after `process()`
+    /// returns, and each call to this method clears them again.
+    pub fn scratch(&mut self, channels: usize) -> &mut [Vec<f32>] {
+        let channels = channels.min(self.scratch_buffers.len());
+
+        // Since each buffer has a capacity of at-least the maximum block size,
+        // this will never allocate.
+        for buffer in self.scratch_buffers[0..channels].iter_mut() {
+            buffer.clear();
+            buffer.resize(self.frames, 0.0);
+        }
+
+        &mut self.scratch_buffers[0..channels]
+    }
 }
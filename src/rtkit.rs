@@ -0,0 +1,144 @@
+//! Requesting realtime priority for the audio thread from rtkit over D-Bus.
+//!
+//! Unprivileged processes on most desktop Linux distributions are not allowed
+//! to set `SCHED_FIFO` themselves, but they can ask the rtkit daemon to do it
+//! for them.
+
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+use dbus::blocking::Connection;
+
+use crate::error::StreamError;
+use crate::StreamMsg;
+
+const RTKIT_SERVICE: &str = "org.freedesktop.RealtimeKit1";
+const RTKIT_PATH: &str = "/org/freedesktop/RealtimeKit1";
+const DBUS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait for the audio thread to report its thread ID.
+const THREAD_ID_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The thread ID is not known yet.
+const TID_UNKNOWN: i64 = 0;
+/// The audio thread is already running with realtime priority.
+const TID_ALREADY_REALTIME: i64 = -1;
+
+/// Passes the ID of the audio thread to the thread that talks to rtkit.
+pub(crate) struct RtkitRequest {
+    tid: AtomicI64,
+    // Set if realtime priority was denied and `RunOptions::require_realtime_priority`
+    // is `true`, in which case the audio thread should stop.
+    stop: AtomicBool,
+}
+
+impl RtkitRequest {
+    pub fn new() -> Self {
+        Self { tid: AtomicI64::new(TID_UNKNOWN), stop: AtomicBool::new(false) }
+    }
+
+    /// Returns `true` if the audio thread should stop because it could not be
+    /// given realtime priority. This is realtime safe.
+    pub fn stop_requested(&self) -> bool {
+        self.stop.load(Ordering::Acquire)
+    }
+
+    /// Report the current thread as the audio thread. This is realtime safe.
+    pub fn report_current_thread(&self) {
+        // Safety: These calls have no preconditions.
+        let (policy, tid) =
+            unsafe { (libc::sched_getscheduler(0), libc::syscall(libc::SYS_gettid)) };
+
+        // `c_long` is only 32 bits on some targets.
+        #[allow(clippy::useless_conversion)]
+        let tid = if policy == libc::SCHED_FIFO || policy == libc::SCHED_RR {
+            TID_ALREADY_REALTIME
+        } else {
+            i64::from(tid)
+        };
+
+        self.tid.store(tid, Ordering::Release);
+    }
+}
+
+/// Spawn a thread that waits for the audio thread to report itself, and then
+/// asks rtkit to make it realtime if it isn't already.
+///
+/// The outcome is sent with `send_msg`. If `require` is `true` and rtkit refuses,
+/// then the audio thread is asked to stop and a `StreamError` is sent instead.
+pub(crate) fn spawn_request(
+    request: Arc<RtkitRequest>,
+    require: bool,
+    send_msg: impl FnOnce(StreamMsg) + Send + 'static,
+) {
+    let res = std::thread::Builder::new().name(String::from("rainout-rtkit")).spawn(move || {
+        let start = std::time::Instant::now();
+        let tid = loop {
+            let tid = request.tid.load(Ordering::Acquire);
+            if tid != TID_UNKNOWN {
+                break tid;
+            }
+            if start.elapsed() > THREAD_ID_TIMEOUT {
+                log::debug!("The audio thread did not start in time to request realtime priority");
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        };
+
+        if tid == TID_ALREADY_REALTIME {
+            return;
+        }
+
+        match make_thread_realtime(tid as u64) {
+            Ok(priority) => {
+                log::info!("rtkit granted realtime priority {} to the audio thread", priority);
+                send_msg(StreamMsg::RealtimePriorityGranted { priority });
+            }
+            Err(e) if require => {
+                log::error!("rtkit denied realtime priority for the audio thread: {}", e);
+                request.stop.store(true, Ordering::Release);
+                send_msg(StreamMsg::Error(StreamError::RealtimePriorityDenied(e)));
+            }
+            Err(e) => {
+                log::warn!("rtkit denied realtime priority for the audio thread: {}", e);
+                send_msg(StreamMsg::RealtimePriorityDenied(e));
+            }
+        }
+    });
+
+    if let Err(e) = res {
+        log::error!("Failed to spawn rtkit thread: {}", e);
+    }
+}
+
+/// Ask rtkit to give the thread with the given ID the highest realtime priority
+/// it allows. Returns the priority that was granted.
+fn make_thread_realtime(tid: u64) -> Result<u32, String> {
+    let conn = Connection::new_system().map_err(|e| format!("{}", e))?;
+    let proxy = conn.with_proxy(RTKIT_SERVICE, RTKIT_PATH, DBUS_TIMEOUT);
+
+    let max_priority: i32 =
+        proxy.get(RTKIT_SERVICE, "MaxRealtimePriority").map_err(|e| format!("{}", e))?;
+    let max_rttime_usec: i64 =
+        proxy.get(RTKIT_SERVICE, "RTTimeUSecMax").map_err(|e| format!("{}", e))?;
+
+    // rtkit refuses any process that doesn't limit how much CPU time its realtime
+    // threads can use without blocking.
+    let limit = libc::rlimit {
+        rlim_cur: max_rttime_usec as libc::rlim_t,
+        rlim_max: max_rttime_usec as libc::rlim_t,
+    };
+    // Safety: `limit` is a valid pointer for the duration of this call.
+    if unsafe { libc::setrlimit(libc::RLIMIT_RTTIME, &limit) } != 0 {
+        return Err(format!("Failed to set RLIMIT_RTTIME: {}", std::io::Error::last_os_error()));
+    }
+
+    let priority = max_priority.max(1) as u32;
+    let _: () = proxy
+        .method_call(RTKIT_SERVICE, "MakeThreadRealtime", (tid, priority))
+        .map_err(|e| format!("{}", e))?;
+
+    Ok(priority)
+}
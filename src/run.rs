@@ -1,9 +1,20 @@
-use crate::error::{ChangeBlockSizeError, RunConfigError};
-use crate::{AutoOption, Backend, ProcessInfo, RainoutConfig, StreamInfo, StreamMsg};
-use ringbuf::Consumer;
+use std::sync::{Arc, Mutex};
+
+use crate::block_size_stats::SharedBlockSizeStats;
+use crate::error::{
+    ChangeBlockSizeError, HardwareInputGainError, RunConfigError, SetDirectMonitoringError,
+};
+use crate::glitch_counts::SharedGlitchCounts;
+use crate::monitor_mix::SharedMonitorMix;
+use crate::{
+    available_audio_backends, enumerate_audio_backend, AudioDeviceConfig, AudioDeviceOptions,
+    AutoOption, Backend, BackendStatus, BlockSizeStats, GlitchCounts, LatencyBreakdown,
+    ProcessInfo, RainoutConfig, StreamInfo, StreamMsg, ThreadScheduling,
+};
+use ringbuf::{Consumer, Producer};
 
 #[cfg(feature = "midi")]
-use crate::{error::ChangeMidiPortsError, MidiPortConfig};
+use crate::{error::ChangeMidiPortsError, MidiBufferPool, MidiPortConfig};
 
 fn auto_audio_backend() -> Backend {
     #[cfg(all(target_os = "linux", feature = "jack-linux"))]
@@ -16,6 +27,27 @@ fn auto_audio_backend() -> Backend {
     return Backend::Asio;
 }
 
+/// The available audio backends, in the order they should be tried.
+fn preferred_audio_backends(options: &RunOptions) -> Vec<Backend> {
+    let available = available_audio_backends();
+
+    let mut backends: Vec<Backend> = Vec::with_capacity(available.len());
+    if let Some(preference) = &options.backend_preference {
+        for backend in preference.iter() {
+            if available.contains(backend) && !backends.contains(backend) {
+                backends.push(*backend);
+            }
+        }
+    }
+    for backend in available.iter() {
+        if !backends.contains(backend) {
+            backends.push(*backend);
+        }
+    }
+
+    backends
+}
+
 #[cfg(feature = "midi")]
 fn auto_midi_backend() -> Backend {
     #[cfg(all(target_os = "linux", feature = "jack-linux"))]
@@ -82,17 +114,76 @@ pub fn estimated_sample_rate_and_latency(
 }
 
 /// A processor for a stream.
-pub trait ProcessHandler: 'static + Send {
+///
+/// `M` is the type of message the host can send to the process handler
+/// through `StreamHandle::user_messages` (i.e. sample-accurate parameter
+/// changes). This defaults to `()` for handlers that don't need it.
+///
+/// # Threads
+///
+/// The process handler is moved to the audio thread when the stream is run,
+/// which is why it must be `Send + 'static`. Its methods are never called
+/// concurrently, so it doesn't need to be `Sync`.
+///
+/// * `init()` is called exactly once, before the stream starts processing. It
+///   is never called on a realtime thread.
+/// * `stream_changed()` is only called while the stream is not processing (i.e.
+///   while the device is being reopened), so it is safe to allocate in it.
+///   `process()` will not be called again until it has returned.
+/// * `process()` is always called on the realtime audio thread.
+///
+/// A handler that holds something that can't be sent to another thread (i.e.
+/// an `Rc`) is rejected at compile time:
+///
+/// ```compile_fail
+/// use std::rc::Rc;
+///
+/// use rainout::{ProcessHandler, ProcessInfo, StreamInfo};
+///
+/// struct MyHandler {
+///     gain: Rc<f32>,
+/// }
+///
+/// impl ProcessHandler for MyHandler {
+///     fn init(&mut self, _stream_info: &StreamInfo) {}
+///     fn stream_changed(&mut self, _stream_info: &StreamInfo) {}
+///     fn process<'a>(&mut self, proc_info: ProcessInfo<'a>) {
+///         for out in proc_info.audio_outputs.iter_mut() {
+///             out.iter_mut().for_each(|smp| *smp *= *self.gain);
+///         }
+///     }
+/// }
+/// ```
+pub trait ProcessHandler<M: Send + 'static = ()>: 'static + Send {
     /// Initialize/allocate any buffers here. This will only be called once on
-    /// creation.
+    /// creation, before the first call to `process()`.
     fn init(&mut self, stream_info: &StreamInfo);
 
     /// This gets called if the user made a change to the configuration that does not
     /// require restarting the audio thread.
+    ///
+    /// This is never called while a block is being processed, so buffers can be
+    /// (re)allocated here.
     fn stream_changed(&mut self, stream_info: &StreamInfo);
 
     /// Process the current buffers. This will always be called on a realtime thread.
-    fn process<'a>(&mut self, proc_info: ProcessInfo<'a>);
+    ///
+    /// # Running slower than realtime
+    ///
+    /// If this takes longer than the duration of the block, then the device will
+    /// underrun and the output will glitch. rainout never drops or replaces a
+    /// late block: whatever this method wrote to the output buffers is always
+    /// sent to the device, and the next block is processed as soon as the device
+    /// asks for it. The stream keeps running, and `StreamMsg::ProcessOverload`
+    /// is sent if this keeps happening and `RunOptions::detect_process_overload`
+    /// is enabled.
+    ///
+    /// The backend never waits indefinitely for the device. If the device stops
+    /// asking for data altogether (i.e. because this method blocked for so long
+    /// that the Jack server dropped the client, or because a WASAPI device did
+    /// not signal for more than a second), then the stream is stopped and a
+    /// `StreamMsg::Error` is sent.
+    fn process<'a>(&mut self, proc_info: ProcessInfo<'a, M>);
 }
 
 #[derive(Debug, Clone)]
@@ -116,9 +207,43 @@ pub struct RunOptions {
     #[cfg(feature = "midi")]
     /// The maximum number of events a MIDI buffer can hold.
     ///
+    /// If more events than this arrive in a single process cycle, then the
+    /// extra events are dropped (and an error is logged), and
+    /// `MidiBuffer::push()` returns `MidiBufferPushError::BufferFull`.
+    ///
     /// By default this is set to `1024`.
     pub midi_buffer_size: u32,
 
+    #[cfg(feature = "midi")]
+    /// If `Some`, then the stream takes its MIDI buffers from this pool and
+    /// returns them when it is dropped, so that restarting a stream does not
+    /// allocate new MIDI buffers.
+    ///
+    /// By default this is set to `None`.
+    pub midi_buffer_pool: Option<MidiBufferPool>,
+
+    #[cfg(feature = "midi")]
+    /// If `true`, then note-offs in the MIDI input buffers are moved in front
+    /// of the other events on the same frame (see
+    /// `MidiBuffer::sort_note_offs_first()`).
+    ///
+    /// Otherwise events on the same frame are delivered in the order they
+    /// arrived in.
+    ///
+    /// By default this is set to `false`.
+    pub midi_note_offs_first: bool,
+
+    #[cfg(feature = "midi")]
+    /// If `false`, then no MIDI ports will be opened, even if
+    /// `RainoutConfig::midi_config` lists MIDI devices. The MIDI buffers in
+    /// `ProcessInfo` will always be empty and `StreamInfo::midi_info` will be
+    /// `None`.
+    ///
+    /// This is useful for running an audio-only stream with less overhead.
+    ///
+    /// By default this is set to `true`.
+    pub enable_midi: bool,
+
     /// If true, then the backend will mark every input audio buffer that is
     /// silent (all `0.0`s) before each call to `process()`.
     ///
@@ -128,6 +253,95 @@ pub struct RunOptions {
     /// By default this is set to `false`.
     pub check_for_silent_inputs: bool,
 
+    /// If true, then every output buffer will be filled with silence before each
+    /// call to `process()`, so any output channels that the process handler does
+    /// not write to will output silence.
+    ///
+    /// If false, then output buffers will still contain whatever the process
+    /// handler wrote to them in the previous cycle. This saves clearing every
+    /// output buffer each cycle, but the process handler must then write to
+    /// every output buffer itself.
+    ///
+    /// This does not apply to `ProcessInfo::raw_audio_output`, which is always
+    /// filled with zeros.
+    ///
+    /// By default this is set to `true`.
+    pub zero_unwritten_outputs: bool,
+
+    /// If true, then the backend will count every output sample that falls
+    /// outside the range `[-1.0, 1.0]` after each call to `process()`, and
+    /// report it with a `StreamMsg::OutputClipped` message.
+    ///
+    /// By default this is set to `false`.
+    pub detect_clipping: bool,
+
+    /// If true, then the backend will measure how long each call to
+    /// `process()` takes, and send a `StreamMsg::ProcessOverload` message if
+    /// it is consistently too slow.
+    ///
+    /// By default this is set to `false`.
+    pub detect_process_overload: bool,
+
+    /// The fraction of a block's duration that `process()` may take before
+    /// the block counts as overloaded.
+    ///
+    /// This is only relevant if `detect_process_overload` is `true`.
+    ///
+    /// By default this is set to `0.8`.
+    pub process_overload_threshold: f64,
+
+    /// The number of overloaded blocks in a row before a
+    /// `StreamMsg::ProcessOverload` message is sent.
+    ///
+    /// This is only relevant if `detect_process_overload` is `true`.
+    ///
+    /// By default this is set to `8`.
+    pub process_overload_blocks: u32,
+
+    /// If `true`, then a panic inside of `ProcessHandler::process()` will be
+    /// caught. The stream will then be stopped cleanly and a
+    /// `StreamError::ProcessPanicked` error will be sent to the stream handle.
+    ///
+    /// If `false`, then the panic will unwind through the audio thread.
+    ///
+    /// By default this is set to `true`.
+    pub catch_process_panics: bool,
+
+    /// If `true`, then the stream is stopped with a
+    /// `StreamError::RealtimePriorityDenied` error if the audio thread could
+    /// not be given realtime priority. If `false`, then the stream keeps
+    /// running and `StreamMsg::RealtimePriorityDenied` is sent instead.
+    ///
+    /// This currently only has an effect on Linux with the `rtkit` feature.
+    ///
+    /// By default this is set to `false`.
+    pub require_realtime_priority: bool,
+
+    /// If `true`, then the backend will try to reopen the audio device if it is
+    /// lost while the stream is running (i.e. a USB interface was briefly
+    /// unplugged), and then resume the stream with the same process handler.
+    ///
+    /// A `StreamMsg::AudioDeviceDisconnected` message is sent when the device is
+    /// lost, and a `StreamMsg::AudioDeviceReconnected` message is sent once it has
+    /// been reopened. If the stream parameters changed, then
+    /// `ProcessHandler::stream_changed()` will be called before processing
+    /// resumes. If the device could not be reopened after `reconnect_attempts`
+    /// attempts, then a `StreamMsg::Error` is sent and the stream is closed.
+    ///
+    /// This is currently only supported by the WASAPI backend. With Jack, the
+    /// server handles devices, so losing the server always closes the stream.
+    ///
+    /// By default this is set to `false`.
+    pub auto_reconnect: bool,
+
+    /// The number of times to try to reopen a lost audio device (about one
+    /// second apart) before giving up.
+    ///
+    /// This is only relevant if `auto_reconnect` is `true`.
+    ///
+    /// By default this is set to `5`.
+    pub reconnect_attempts: u32,
+
     /// If `true`, then the system will return an error if it was not able to
     /// connect to a device with at-least two output channels. It will also try
     /// to avoid automatically connecting to devices with mono outputs.
@@ -152,6 +366,138 @@ pub struct RunOptions {
     ///
     /// By default this is set to `512`.
     pub msg_buffer_size: usize,
+
+    /// The size of the stream handle to audio thread user message buffer.
+    ///
+    /// By default this is set to `512`.
+    pub user_msg_buffer_size: usize,
+
+    /// If `true`, then statistics about the block sizes passed to the process
+    /// method will be collected, which can be read with
+    /// `StreamHandle::block_size_stats()`.
+    ///
+    /// This is mostly useful for backends with variable block sizes (i.e.
+    /// WASAPI in shared mode) to see how much the block size jitters.
+    ///
+    /// By default this is set to `false`.
+    pub collect_block_size_stats: bool,
+
+    /// If `true`, then the inputs can be monitored directly on the outputs
+    /// with `StreamHandle::set_monitor()` (i.e. to hear a microphone while
+    /// recording), without having to do it in the process handler.
+    ///
+    /// The monitored inputs are summed into the outputs after `process()` has
+    /// returned. Every input starts muted.
+    ///
+    /// This is currently only supported by Jack. The only WASAPI inputs are the
+    /// loopback capture of the output device (see `wasapi_loopback_input`),
+    /// which would feed back into itself if it were monitored.
+    ///
+    /// By default this is set to `false`.
+    pub enable_monitor_mix: bool,
+
+    /// The number of scratch buffers available to the process handler through
+    /// `ProcessInfo::scratch()`.
+    ///
+    /// These are allocated once when the stream is started with enough
+    /// capacity for the maximum block size.
+    ///
+    /// By default this is set to `4`.
+    pub num_scratch_buffers: usize,
+
+    /// If `true`, then a block size that was explicitly requested in the
+    /// config is applied to the Jack server when it is running with a
+    /// different one.
+    ///
+    /// **Warning**: This changes the buffer size of the whole Jack server, which
+    /// affects every other Jack client. Only enable this if the user explicitly
+    /// asked for it (i.e. in a settings GUI). If this is `false`, then the
+    /// stream runs with the server's buffer size and a
+    /// `ConfigWarning::BlockSizeNotUsed` is sent instead.
+    ///
+    /// This is only relevant for Jack.
+    ///
+    /// By default this is set to `false`.
+    pub jack_set_server_buffer_size: bool,
+
+    /// How the WASAPI audio thread waits for the device to need more data.
+    ///
+    /// This is only relevant for WASAPI on Windows.
+    ///
+    /// By default this is set to `WasapiMode::EventDriven`.
+    pub wasapi_mode: WasapiMode,
+
+    /// The number of extra device periods to add to the WASAPI buffer in
+    /// shared mode. The buffer is sized to `period * (1 + wasapi_extra_periods)`.
+    ///
+    /// Raising this can fix crackling on systems where the audio thread is
+    /// sometimes late, at the cost of higher latency. The resulting buffer is
+    /// reported in `StreamInfo::buffer_config`.
+    ///
+    /// This is only relevant for WASAPI on Windows in shared mode.
+    ///
+    /// By default this is set to `0`.
+    pub wasapi_extra_periods: u32,
+
+    /// If `true`, then the audio that is being played on the WASAPI output
+    /// device (by every application, including this stream) is captured and
+    /// passed to `process()` as the audio inputs. This is a loopback of the
+    /// output device, not a physical input, and is useful for recording or
+    /// streaming the system's output.
+    ///
+    /// This is only relevant for WASAPI on Windows, and is only available in
+    /// shared mode. `StreamInfo::input_is_loopback` is `true` if the loopback
+    /// capture was started.
+    ///
+    /// By default this is set to `false`.
+    pub wasapi_loopback_input: bool,
+
+    /// The dither to add when the output samples have to be converted to
+    /// 16 bit integers for the device.
+    ///
+    /// This is currently only relevant for WASAPI on Windows, and only for
+    /// devices that use 16 bit integer samples.
+    ///
+    /// By default this is set to `DitherMode::Tpdf`.
+    pub dither: DitherMode,
+
+    /// If `Some`, then the audio backends are tried in this order instead of
+    /// the default order of `available_audio_backends()` (i.e. to honor a
+    /// user's saved preference for Jack over WASAPI).
+    ///
+    /// This is used to pick the backend when `RainoutConfig::audio_backend` is
+    /// `AutoOption::Auto`, and to order the results of `startable_configs()`.
+    /// Backends that are not available on this platform are skipped, and any
+    /// available backends that are not listed are tried last.
+    ///
+    /// By default this is set to `None`.
+    pub backend_preference: Option<Vec<Backend>>,
+
+    /// If `true`, then the process handler writes the device's output buffer
+    /// directly in its native sample format through
+    /// `ProcessInfo::raw_audio_output`, instead of writing to `f32` buffers.
+    /// This allows for bit-perfect playback. The layout of the buffer is given
+    /// by `StreamInfo::native_format`.
+    ///
+    /// This is currently only supported by WASAPI, and is ignored by other
+    /// backends. Combine it with `RainoutConfig::take_exclusive_access`, since
+    /// Windows mixes (and converts) every shared mode stream.
+    ///
+    /// By default this is set to `false`.
+    pub raw_passthrough: bool,
+
+    /// A callback that is called exactly once after the stream has been
+    /// completely torn down (its threads have been joined and the device has
+    /// been released). This is called whether the stream was dropped or it
+    /// stopped because of an error.
+    ///
+    /// This is called from the thread that does the final cleanup. For Jack
+    /// this is the thread that drops the `StreamHandle`, and for WASAPI this
+    /// is the stream's own thread. It is not called if `run()` returns an
+    /// error.
+    ///
+    /// By default this is set to `None`.
+    pub on_teardown: Option<TeardownCallback>,
 }
 
 impl Default for RunOptions {
@@ -163,16 +509,120 @@ impl Default for RunOptions {
 
             #[cfg(feature = "midi")]
             midi_buffer_size: 1024,
+            #[cfg(feature = "midi")]
+            midi_buffer_pool: None,
+            #[cfg(feature = "midi")]
+            midi_note_offs_first: false,
+            #[cfg(feature = "midi")]
+            enable_midi: true,
 
             check_for_silent_inputs: false,
+            zero_unwritten_outputs: true,
+            detect_clipping: false,
+            detect_process_overload: false,
+            process_overload_threshold: 0.8,
+            process_overload_blocks: 8,
+            catch_process_panics: true,
+            require_realtime_priority: false,
+            auto_reconnect: false,
+            reconnect_attempts: 5,
             must_have_stereo_output: true,
             empty_buffers_for_failed_ports: false,
             max_buffer_size: 1024,
             msg_buffer_size: 512,
+            user_msg_buffer_size: 512,
+            collect_block_size_stats: false,
+            enable_monitor_mix: false,
+            num_scratch_buffers: 4,
+            jack_set_server_buffer_size: false,
+            wasapi_mode: WasapiMode::EventDriven,
+            wasapi_extra_periods: 0,
+            wasapi_loopback_input: false,
+            dither: DitherMode::Tpdf,
+            backend_preference: None,
+            raw_passthrough: false,
+            on_teardown: None,
         }
     }
 }
 
+type TeardownFn = Box<dyn FnOnce() + Send>;
+
+#[derive(Clone)]
+/// A callback for `RunOptions::on_teardown`.
+///
+/// Clones of this share the same callback, so it is only ever called once.
+/// Create a new one for each stream.
+pub struct TeardownCallback(Arc<Mutex<Option<TeardownFn>>>);
+
+impl TeardownCallback {
+    /// Create a new teardown callback that calls `f` once the stream has been
+    /// torn down.
+    pub fn new(f: impl FnOnce() + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(Some(Box::new(f)))))
+    }
+
+    pub(crate) fn call(&self) {
+        let f = match self.0.lock() {
+            Ok(mut f) => f.take(),
+            Err(e) => e.into_inner().take(),
+        };
+
+        if let Some(f) = f {
+            f();
+        }
+    }
+}
+
+impl std::fmt::Debug for TeardownCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TeardownCallback")
+    }
+}
+
+/// Calls the teardown callback when dropped. Backends place this so that it is
+/// dropped after the device has been released.
+pub(crate) struct TeardownGuard(pub TeardownCallback);
+
+impl Drop for TeardownGuard {
+    fn drop(&mut self) {
+        self.0.call();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// How the WASAPI audio thread waits for the device to need more data.
+pub enum WasapiMode {
+    /// Wait for the device to signal that it needs more data.
+    ///
+    /// If an event handle can't be registered for the device, then the client
+    /// is recreated and the stream falls back to `WasapiMode::TimerDriven`
+    /// (see `ConfigWarning::WasapiFellBackToTimerDriven`).
+    EventDriven,
+
+    /// Sleep for half of the device period, then poll the device for how
+    /// much data it needs.
+    ///
+    /// Some devices and virtual drivers only work reliably in this mode.
+    TimerDriven,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// The dither to add when converting output samples to a lower bit depth.
+pub enum DitherMode {
+    /// Don't add any dither. The samples are simply rounded, which adds
+    /// distortion to quiet signals.
+    None,
+
+    /// Add triangular (TPDF) dither noise. This fully decorrelates the
+    /// rounding error from the signal.
+    Tpdf,
+
+    /// Add rectangular dither noise. This is a bit quieter than TPDF dither,
+    /// but the level of the noise still depends on the signal.
+    Rectangular,
+}
+
 /// Run the given configuration in an audio thread.
 ///
 /// * `config`: The configuration to use.
@@ -182,20 +632,30 @@ impl Default for RunOptions {
 ///
 /// If an error is returned, then it means the config failed to run and no audio
 /// thread was spawned.
-pub fn run<P: ProcessHandler>(
+///
+/// This can be called multiple times to run several independent streams at the
+/// same time (i.e. a low-latency monitoring stream and a separate playback stream
+/// on another device). Each stream has its own audio thread and message buffers.
+/// With Jack, each stream is a separate Jack client.
+pub fn run<P: ProcessHandler<M>, M: Send + 'static>(
     config: &RainoutConfig,
     options: &RunOptions,
     process_handler: P,
-) -> Result<StreamHandle<P>, RunConfigError> {
-    let use_audio_backend = auto_audio_backend();
+) -> Result<StreamHandle<P, M>, RunConfigError> {
+    let use_audio_backend = match config.audio_backend {
+        AutoOption::Use(b) => b,
+        AutoOption::Auto => {
+            preferred_audio_backends(options).first().copied().unwrap_or_else(auto_audio_backend)
+        }
+    };
 
     #[cfg(feature = "midi")]
     let use_midi_backend = match &config.midi_config {
-        Some(midi_config) => match midi_config.midi_backend {
+        Some(midi_config) if options.enable_midi => match midi_config.midi_backend {
             AutoOption::Use(b) => Some(b),
             AutoOption::Auto => Some(auto_midi_backend()),
         },
-        None => None,
+        _ => None,
     };
 
     let spawn_separate_midi_thread = if let Some(midi_backend) = use_midi_backend {
@@ -249,25 +709,286 @@ pub fn run<P: ProcessHandler>(
     }
 }
 
+/// Returns an error if the device config can't be used with the given backend
+/// (i.e. Jack ports with WASAPI, or a system-wide device with a backend that
+/// has none). Every backend calls this at the top of its `run()`.
+pub(crate) fn validate_backend_device_compatibility(
+    backend: Backend,
+    audio_device: &AudioDeviceConfig,
+) -> Result<(), RunConfigError> {
+    let unsupported = match audio_device {
+        AudioDeviceConfig::Single(_) | AudioDeviceConfig::Auto => None,
+        // Jack treats this as its system-wide device.
+        AudioDeviceConfig::LinkedInOut { .. } => {
+            if matches!(backend, Backend::Wasapi | Backend::Alsa | Backend::Asio) {
+                Some("linked in/out")
+            } else {
+                None
+            }
+        }
+        AudioDeviceConfig::SystemWide => {
+            if backend.has_system_wide_device() {
+                None
+            } else {
+                Some("system-wide")
+            }
+        }
+        #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+        AudioDeviceConfig::Jack { .. } => {
+            if backend == Backend::Jack {
+                None
+            } else {
+                Some("Jack")
+            }
+        }
+    };
+
+    if let Some(device_type) = unsupported {
+        return Err(RunConfigError::MalformedConfig(format!(
+            "The {:?} backend does not support {} device configs",
+            backend, device_type
+        )));
+    }
+
+    Ok(())
+}
+
+/// Check that the given configuration will actually run by opening the
+/// device/client, reading back the actual configuration of the stream, and
+/// then immediately closing it again.
+///
+/// This is useful for confirming a configuration in a settings GUI before
+/// committing to it, since it will catch errors such as the device being busy
+/// or being unable to take exclusive access of the device.
+///
+/// If the configuration is valid, then the actual configuration of the stream
+/// is returned. This may differ from the given configuration.
+pub fn validate_config(
+    config: &RainoutConfig,
+    options: &RunOptions,
+) -> Result<StreamInfo, RunConfigError> {
+    let stream_handle = run(config, options, DryRunProcessHandler)?;
+
+    // The stream is closed when `stream_handle` is dropped.
+    Ok(stream_handle.stream_info())
+}
+
+/// Returns a default configuration for every audio device that is known to
+/// start right now, ordered by estimated latency (lowest first).
+///
+/// If `RunOptions::backend_preference` is set, then the configs are ordered by
+/// backend first (in the order of preference), and then by estimated latency.
+///
+/// Every backend and device is enumerated, and each configuration is checked
+/// with `validate_config()`. Backends that are not running and devices that
+/// fail to start (i.e. they are busy) are skipped.
+///
+/// Note this opens every available device, so this can take a while.
+pub fn startable_configs(options: &RunOptions) -> Vec<RainoutConfig> {
+    // The configs along with the rank of their backend and their latency.
+    let mut configs: Vec<(RainoutConfig, usize, Option<f64>)> = Vec::new();
+
+    let backends = preferred_audio_backends(options);
+    for (backend_rank, backend) in backends.iter().enumerate() {
+        // Only order by backend if the user asked for it.
+        let backend_rank = if options.backend_preference.is_some() { backend_rank } else { 0 };
+
+        let backend_options = match enumerate_audio_backend(*backend) {
+            Ok(backend_options) => backend_options,
+            Err(()) => continue,
+        };
+
+        match backend_options.status {
+            BackendStatus::Running | BackendStatus::RunningNoDefault => {}
+            _ => continue,
+        }
+
+        let audio_devices: Vec<AudioDeviceConfig> = match &backend_options.device_options {
+            Some(AudioDeviceOptions::SingleDeviceOnly { options, .. }) => {
+                options.iter().map(|id| AudioDeviceConfig::Single(id.clone())).collect()
+            }
+            Some(AudioDeviceOptions::LinkedInOutDevice { out_devices, .. }) => out_devices
+                .iter()
+                .map(|id| AudioDeviceConfig::LinkedInOut { input: None, output: Some(id.clone()) })
+                .collect(),
+            #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+            Some(AudioDeviceOptions::JackSystemWideDevice) => vec![AudioDeviceConfig::SystemWide],
+            _ => vec![AudioDeviceConfig::Auto],
+        };
+
+        for audio_device in audio_devices {
+            let config = RainoutConfig {
+                audio_backend: AutoOption::Use(*backend),
+                audio_device,
+                ..Default::default()
+            };
+
+            match validate_config(&config, options) {
+                Ok(stream_info) => {
+                    // The latency is unknown if the sample rate is unknown.
+                    let latency = stream_info
+                        .output_latency
+                        .or(stream_info.estimated_latency)
+                        .filter(|_| stream_info.sample_rate != 0)
+                        .map(|frames| f64::from(frames) / f64::from(stream_info.sample_rate));
+
+                    configs.push((config, backend_rank, latency));
+                }
+                Err(e) => {
+                    log::debug!("Skipping config {:?}: {}", &config, e);
+                }
+            }
+        }
+    }
+
+    // Configs with an unknown latency are placed last.
+    configs.sort_by(|(_, rank_a, a), (_, rank_b, b)| {
+        rank_a.cmp(rank_b).then_with(|| match (a, b) {
+            (Some(a), Some(b)) => a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => std::cmp::Ordering::Equal,
+        })
+    });
+
+    configs.into_iter().map(|(config, _, _)| config).collect()
+}
+
+/// A process handler that only outputs silence.
+struct DryRunProcessHandler;
+
+impl ProcessHandler for DryRunProcessHandler {
+    fn init(&mut self, _stream_info: &StreamInfo) {}
+
+    fn stream_changed(&mut self, _stream_info: &StreamInfo) {}
+
+    fn process<'a>(&mut self, _proc_info: ProcessInfo<'a>) {}
+}
+
 /// The handle to a running audio/midi stream.
 ///
 // When this gets dropped, the stream (audio thread) will automatically stop. This
 /// is the intended method for stopping a stream.
-pub struct StreamHandle<P: ProcessHandler> {
+pub struct StreamHandle<P: ProcessHandler<M>, M: Send + 'static = ()> {
     /// The message channel that recieves notifications from the audio thread
     /// including any errors that have occurred.
     pub messages: Consumer<StreamMsg>,
 
-    pub(crate) platform_handle: Box<dyn PlatformStreamHandle<P>>,
+    /// The message channel that sends user messages to the process handler.
+    ///
+    /// These are received in `ProcessInfo::user_messages()` at the start of
+    /// the next process cycle. The message type must be `Send`, and ideally
+    /// it should not allocate or deallocate memory when it is dropped since
+    /// that will happen on the realtime thread.
+    pub user_messages: Producer<M>,
+
+    pub(crate) platform_handle: Box<dyn PlatformStreamHandle<P, M>>,
+    pub(crate) block_size_stats: Option<Arc<SharedBlockSizeStats>>,
+    pub(crate) glitch_counts: Arc<SharedGlitchCounts>,
+    pub(crate) monitor_mix: Option<Arc<SharedMonitorMix>>,
 }
 
-impl<P: ProcessHandler> StreamHandle<P> {
+impl<P: ProcessHandler<M>, M: Send + 'static> StreamHandle<P, M> {
     /// Returns the actual configuration of the running stream. This may differ
     /// from the configuration passed into the `run()` method.
-    pub fn stream_info(&self) -> &StreamInfo {
+    ///
+    /// This can change while the stream is running. If the device was lost and
+    /// reopened with a different configuration (see `RunOptions::auto_reconnect`),
+    /// then this returns the new configuration once
+    /// `StreamMsg::AudioDeviceReconnected` has been received.
+    pub fn stream_info(&self) -> StreamInfo {
         self.platform_handle.stream_info()
     }
 
+    /// Returns statistics about the block sizes that have been passed to the
+    /// process method so far.
+    ///
+    /// This will return `None` if `RunOptions::collect_block_size_stats` was
+    /// `false` or if the process method has not been called yet.
+    pub fn block_size_stats(&self) -> Option<BlockSizeStats> {
+        self.block_size_stats.as_ref().and_then(|stats| stats.snapshot())
+    }
+
+    /// Returns the number of input overruns and output underruns (xruns) that
+    /// have occurred since the stream was started.
+    ///
+    /// See `GlitchCounts::output_underruns` for which glitches each backend can
+    /// detect.
+    pub fn glitch_counts(&self) -> GlitchCounts {
+        self.glitch_counts.snapshot()
+    }
+
+    /// Monitor the given input channel on the given output channel with the
+    /// given (linear) gain. Set the gain to `0.0` to stop monitoring it.
+    ///
+    /// This takes effect on the next block, and never blocks the audio thread.
+    ///
+    /// This will return an error if `RunOptions::enable_monitor_mix` was
+    /// `false` (or the backend doesn't support it), or if either channel does
+    /// not exist.
+    pub fn set_monitor(&self, in_channel: usize, out_channel: usize, gain: f32) -> Result<(), ()> {
+        match &self.monitor_mix {
+            Some(monitor_mix) => monitor_mix.set(in_channel, out_channel, gain),
+            None => Err(()),
+        }
+    }
+
+    /// Turn hardware direct monitoring (the device routing its inputs straight
+    /// to its outputs with no added latency) on or off.
+    ///
+    /// This will return an error if the device doesn't support it (see
+    /// `AudioDeviceConfigOptions::supports_direct_monitoring`). No backend
+    /// currently does, so use `set_monitor()` to monitor in software instead.
+    pub fn set_direct_monitoring(&mut self, enabled: bool) -> Result<(), SetDirectMonitoringError> {
+        self.platform_handle.set_direct_monitoring(enabled)
+    }
+
+    /// Set the analog gain of the device's input channel in decibels. The gain
+    /// is clamped to `hardware_input_gain_range()`.
+    ///
+    /// This will return an error if the device does not expose a hardware
+    /// gain control for this channel. No backend currently does, so apply the
+    /// gain in software in `process()` instead.
+    pub fn set_hardware_input_gain(
+        &mut self,
+        channel: usize,
+        db: f32,
+    ) -> Result<(), HardwareInputGainError> {
+        self.platform_handle.set_hardware_input_gain(channel, db)
+    }
+
+    /// Returns the range of the analog gain of the device's input channel in
+    /// decibels as `(min, max)`.
+    ///
+    /// This will return an error if the device does not expose a hardware
+    /// gain control for this channel.
+    pub fn hardware_input_gain_range(
+        &self,
+        channel: usize,
+    ) -> Result<(f32, f32), HardwareInputGainError> {
+        self.platform_handle.hardware_input_gain_range(channel)
+    }
+
+    /// Returns the estimated round-trip latency of the stream in frames (the
+    /// input latency plus the output latency), if it is available.
+    pub fn round_trip_latency_frames(&self) -> Option<u32> {
+        self.platform_handle.stream_info().round_trip_latency()
+    }
+
+    /// Returns the latency of each stage of the stream in frames.
+    pub fn latency_breakdown(&self) -> LatencyBreakdown {
+        self.platform_handle.stream_info().latency_breakdown()
+    }
+
+    /// Returns the scheduling that the OS actually granted to the audio thread.
+    ///
+    /// This can be used to check whether the audio thread is really running with
+    /// realtime priority when debugging dropouts. This will return `None` if the
+    /// backend cannot query this.
+    pub fn thread_scheduling(&self) -> Option<ThreadScheduling> {
+        self.platform_handle.thread_scheduling()
+    }
+
     #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
     /// Change the audio port configuration (when using the Jack backend) while the
     /// audio thread is still running.
@@ -319,12 +1040,44 @@ impl<P: ProcessHandler> StreamHandle<P> {
     pub fn can_change_midi_ports(&self) -> bool {
         self.platform_handle.can_change_midi_ports()
     }
+
+    #[cfg(all(
+        feature = "raw-backend-handle",
+        any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows")
+    ))]
+    /// **Advanced**: Returns the raw handle to the backend that is running this
+    /// stream, or `None` if this backend does not expose one.
+    ///
+    /// This can be used to access backend-specific features that are not exposed
+    /// by rainout. Be careful, since anything done with this handle bypasses
+    /// rainout entirely (i.e. deactivating the Jack client will break the stream).
+    pub fn raw_backend_handle(&self) -> Option<RawBackendHandle<'_>> {
+        self.platform_handle.raw_backend_handle()
+    }
 }
 
-pub(crate) trait PlatformStreamHandle<P: ProcessHandler>: Send {
+#[cfg(all(
+    feature = "raw-backend-handle",
+    any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows")
+))]
+#[non_exhaustive]
+/// A raw handle to the backend that is running a stream.
+pub enum RawBackendHandle<'a> {
+    /// The Jack client of the stream.
+    Jack(&'a jack::Client),
+}
+
+pub(crate) trait PlatformStreamHandle<P: ProcessHandler<M>, M: Send + 'static>:
+    Send
+{
     /// Returns the actual configuration of the running stream. This may differ
     /// from the configuration passed into the `run()` method.
-    fn stream_info(&self) -> &StreamInfo;
+    fn stream_info(&self) -> StreamInfo;
+
+    /// Returns the scheduling that the OS actually granted to the audio thread.
+    fn thread_scheduling(&self) -> Option<ThreadScheduling> {
+        None
+    }
 
     #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
     /// Change the audio port configuration (when using the Jack backend) while the
@@ -365,6 +1118,37 @@ pub(crate) trait PlatformStreamHandle<P: ProcessHandler>: Send {
         Err(ChangeMidiPortsError::NotSupportedByBackend)
     }
 
+    /// Turn hardware direct monitoring on or off.
+    ///
+    /// This will return an error if the device doesn't support it.
+    #[allow(unused_variables)]
+    fn set_direct_monitoring(&mut self, enabled: bool) -> Result<(), SetDirectMonitoringError> {
+        Err(SetDirectMonitoringError::NotSupportedByBackend)
+    }
+
+    /// Set the analog gain of the device's input channel in decibels.
+    ///
+    /// This will return an error if the device doesn't expose a hardware gain
+    /// control.
+    #[allow(unused_variables)]
+    fn set_hardware_input_gain(
+        &mut self,
+        channel: usize,
+        db: f32,
+    ) -> Result<(), HardwareInputGainError> {
+        Err(HardwareInputGainError::NotSupportedByBackend)
+    }
+
+    /// Returns the range of the analog gain of the device's input channel in
+    /// decibels.
+    #[allow(unused_variables)]
+    fn hardware_input_gain_range(
+        &self,
+        channel: usize,
+    ) -> Result<(f32, f32), HardwareInputGainError> {
+        Err(HardwareInputGainError::NotSupportedByBackend)
+    }
+
     // It may be possible to also add `change_sample_rate_config()` here, but
     // I'm not sure how useful this would actually be.
 
@@ -380,4 +1164,124 @@ pub(crate) trait PlatformStreamHandle<P: ProcessHandler>: Send {
     fn can_change_midi_ports(&self) -> bool {
         false
     }
+
+    #[cfg(all(
+        feature = "raw-backend-handle",
+        any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows")
+    ))]
+    /// Returns the raw handle to the backend that is running this stream.
+    fn raw_backend_handle(&self) -> Option<RawBackendHandle<'_>> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DeviceID;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const ALL_BACKENDS: [Backend; 6] = [
+        Backend::Jack,
+        Backend::Pipewire,
+        Backend::Alsa,
+        Backend::CoreAudio,
+        Backend::Wasapi,
+        Backend::Asio,
+    ];
+
+    #[test]
+    fn backend_device_compatibility() {
+        let device = DeviceID { name: String::from("Speakers"), identifier: None };
+
+        // (device config, backends that reject it)
+        #[allow(unused_mut)]
+        let mut cases = vec![
+            (AudioDeviceConfig::Single(device.clone()), vec![]),
+            (AudioDeviceConfig::Auto, vec![]),
+            (
+                AudioDeviceConfig::LinkedInOut { input: Some(device.clone()), output: None },
+                vec![Backend::Alsa, Backend::Wasapi, Backend::Asio],
+            ),
+            (
+                AudioDeviceConfig::SystemWide,
+                vec![Backend::Alsa, Backend::CoreAudio, Backend::Wasapi, Backend::Asio],
+            ),
+        ];
+        #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
+        cases.push((
+            AudioDeviceConfig::Jack { in_ports: vec![], out_ports: vec![String::from("out_1")] },
+            vec![
+                Backend::Pipewire,
+                Backend::Alsa,
+                Backend::CoreAudio,
+                Backend::Wasapi,
+                Backend::Asio,
+            ],
+        ));
+
+        for (audio_device, rejected) in cases.iter() {
+            for backend in ALL_BACKENDS {
+                let res = validate_backend_device_compatibility(backend, audio_device);
+                if rejected.contains(&backend) {
+                    assert!(
+                        matches!(res, Err(RunConfigError::MalformedConfig(_))),
+                        "{:?} should reject {:?}",
+                        backend,
+                        audio_device
+                    );
+                } else {
+                    assert!(res.is_ok(), "{:?} should accept {:?}", backend, audio_device);
+                }
+            }
+        }
+    }
+
+    /// Returns a teardown callback and a counter of how many times it was called.
+    fn counting_callback() -> (TeardownCallback, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let callback_calls = Arc::clone(&calls);
+        let callback = TeardownCallback::new(move || {
+            callback_calls.fetch_add(1, Ordering::SeqCst);
+        });
+        (callback, calls)
+    }
+
+    #[test]
+    fn teardown_callback_is_called_when_the_guard_is_dropped() {
+        let (callback, calls) = counting_callback();
+
+        let guard = TeardownGuard(callback);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        drop(guard);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn teardown_callback_is_called_exactly_once() {
+        let (callback, calls) = counting_callback();
+
+        // i.e. a `RunOptions` that was cloned and used for two streams.
+        let guard_1 = TeardownGuard(callback.clone());
+        let guard_2 = TeardownGuard(callback.clone());
+
+        std::thread::spawn(move || drop(guard_1)).join().unwrap();
+        drop(guard_2);
+        callback.call();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn teardown_callback_is_not_called_without_a_guard() {
+        let (callback, calls) = counting_callback();
+
+        // i.e. `run()` returned an error, so no stream held on to it.
+        let options = RunOptions { on_teardown: Some(callback), ..Default::default() };
+        drop(options.clone());
+        drop(options);
+
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
 }
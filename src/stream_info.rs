@@ -21,14 +21,28 @@ pub struct StreamInfo {
     pub sample_rate: u32,
 
     /// The audio buffer size.
+    ///
+    /// This is the number of frames passed to each call to `process()` (the
+    /// period size), not the total amount of buffering in the device. See
+    /// `buffer_config` for that.
     pub buffer_size: AudioBufferStreamInfo,
 
+    /// The period size and number of periods of the device's buffer, if the
+    /// backend reports them.
+    pub buffer_config: Option<BufferConfig>,
+
     /// The number of audio input channels that will be passed into the
     /// process method.
+    ///
+    /// This may differ from `num_out_channels` (i.e. when the input and output
+    /// are separate devices).
     pub num_in_channels: u32,
 
     /// The number of audio output channels that will be passed into the
     /// process method.
+    ///
+    /// This may differ from `num_in_channels` (i.e. when the input and output
+    /// are separate devices).
     pub num_out_channels: u32,
 
     /// The layout of the audio input channels.
@@ -37,14 +51,45 @@ pub struct StreamInfo {
     /// The layout of the audio output channels.
     pub out_channel_layout: ChannelLayout,
 
+    /// If this is `true`, then the audio inputs are a loopback capture of the
+    /// audio that is being played on the output device (see
+    /// `RunOptions::wasapi_loopback_input`), not a physical input.
+    pub input_is_loopback: bool,
+
     /// The total estimated latency of this stream in frames (if it is available)
     pub estimated_latency: Option<u32>,
 
+    /// The estimated latency of the audio inputs in frames, from the time the
+    /// sound reaches the device to the time it is passed to `process()` (if
+    /// it is available).
+    pub input_latency: Option<u32>,
+
+    /// The estimated latency of the audio outputs in frames, from the time
+    /// the buffers are returned from `process()` to the time the sound leaves
+    /// the device (if it is available).
+    pub output_latency: Option<u32>,
+
+    /// The latency added by resampling between the stream's sample rate and
+    /// the device's sample rate in frames (if it is available).
+    pub resampler_latency: Option<u32>,
+
+    /// The latency added by any extra buffering the backend or device does to
+    /// protect against underruns in frames (if it is available).
+    pub safety_latency: Option<u32>,
+
     /// If this is `true`, then it means that the backend is checking
     /// each audio input buffer for silence before each call to the
     /// `process()` loop and marking the flag in `ProcessInfo`.
     pub checking_for_silent_inputs: bool,
 
+    /// The native sample format of the device's output buffer, if the backend
+    /// writes to a byte buffer.
+    ///
+    /// This is the layout of `ProcessInfo::raw_audio_output` when
+    /// `RunOptions::raw_passthrough` is enabled. This is currently only
+    /// reported by WASAPI. Jack always uses `f32` buffers, so this is `None`.
+    pub native_format: Option<NativeSampleFormat>,
+
     /// The information about the MIDI stream.
     ///
     /// If no MIDI stream is running, this will be `None`.
@@ -52,6 +97,121 @@ pub struct StreamInfo {
     pub midi_info: Option<MidiStreamInfo>,
 }
 
+impl StreamInfo {
+    /// The estimated round-trip latency of this stream in frames (the input
+    /// latency plus the output latency), if it is available.
+    pub fn round_trip_latency(&self) -> Option<u32> {
+        match (self.input_latency, self.output_latency) {
+            (Some(input), Some(output)) => Some(input + output),
+            _ => None,
+        }
+    }
+
+    /// Convert a number of frames at this stream's sample rate to seconds.
+    ///
+    /// This returns `0.0` if the sample rate is unknown (`0`).
+    pub fn frames_to_secs(&self, frames: u64) -> f64 {
+        if self.sample_rate == 0 {
+            return 0.0;
+        }
+
+        frames as f64 / f64::from(self.sample_rate)
+    }
+
+    /// Convert seconds to the nearest number of frames at this stream's
+    /// sample rate. Negative values return `0`.
+    pub fn secs_to_frames(&self, secs: f64) -> u64 {
+        (secs * f64::from(self.sample_rate)).round().max(0.0) as u64
+    }
+
+    /// Convert a number of frames at this stream's sample rate to beats at
+    /// the given tempo (in beats per minute).
+    ///
+    /// This returns `0.0` if the sample rate is unknown (`0`).
+    pub fn frames_to_beats(&self, frames: u64, bpm: f64) -> f64 {
+        if self.sample_rate == 0 {
+            return 0.0;
+        }
+
+        // Multiply before dividing to keep as much precision as possible.
+        frames as f64 * bpm / (60.0 * f64::from(self.sample_rate))
+    }
+
+    /// Convert beats at the given tempo (in beats per minute) to the nearest
+    /// number of frames at this stream's sample rate. Negative values
+    /// return `0`.
+    ///
+    /// This also returns `0` if `bpm` is not a positive number.
+    pub fn beats_to_frames(&self, beats: f64, bpm: f64) -> u64 {
+        if bpm.is_nan() || bpm <= 0.0 {
+            return 0;
+        }
+
+        (beats * 60.0 * f64::from(self.sample_rate) / bpm).round().max(0.0) as u64
+    }
+
+    /// The latency of each stage of this stream.
+    pub fn latency_breakdown(&self) -> LatencyBreakdown {
+        LatencyBreakdown {
+            input_frames: self.input_latency,
+            output_frames: self.output_latency,
+            resampler_frames: self.resampler_latency,
+            safety_frames: self.safety_latency,
+        }
+    }
+}
+
+/// The latency of each stage of a stream in frames.
+///
+/// Each stage is `None` if the backend does not know its latency.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencyBreakdown {
+    /// The latency of the input buffering.
+    pub input_frames: Option<u32>,
+
+    /// The latency of the output buffering.
+    pub output_frames: Option<u32>,
+
+    /// The latency of any resampling.
+    pub resampler_frames: Option<u32>,
+
+    /// The latency of any extra buffering to protect against underruns.
+    pub safety_frames: Option<u32>,
+}
+
+impl LatencyBreakdown {
+    /// The sum of the latency of every stage whose latency is known.
+    pub fn known_total(&self) -> u32 {
+        [self.input_frames, self.output_frames, self.resampler_frames, self.safety_frames]
+            .iter()
+            .flatten()
+            .sum()
+    }
+
+    /// Returns `true` if the latency of every stage is known.
+    pub fn is_complete(&self) -> bool {
+        self.input_frames.is_some()
+            && self.output_frames.is_some()
+            && self.resampler_frames.is_some()
+            && self.safety_frames.is_some()
+    }
+}
+
+/// The scheduling that the OS actually granted to the audio thread of a
+/// running stream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreadScheduling {
+    /// The name of the scheduling policy of the audio thread (i.e.
+    /// `"SCHED_FIFO"` on Linux).
+    pub policy: String,
+
+    /// The priority of the audio thread within its scheduling policy.
+    pub priority: i32,
+
+    /// `true` if the audio thread is running with realtime priority.
+    pub rt_granted: bool,
+}
+
 #[derive(Debug, Clone)]
 pub enum AudioDeviceStreamInfo {
     /// Using a single audio device. This device may be output only, input
@@ -128,6 +288,51 @@ impl AudioBufferStreamInfo {
     }
 }
 
+/// The native sample format of an audio device.
+///
+/// Samples are interleaved and little-endian. Packed 24 bit samples have a
+/// `container_bits` of 24, while 24 bit samples in a 32 bit container have a
+/// `container_bits` of 32 and a `valid_bits` of 24.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde-config", derive(serde::Serialize, serde::Deserialize))]
+pub struct NativeSampleFormat {
+    /// `true` if the samples are floating point, `false` if they are signed
+    /// integers.
+    pub is_float: bool,
+    /// The number of bits each sample takes up in the buffer.
+    pub container_bits: u16,
+    /// The number of bits of each sample that are actually used (i.e. 24 bit
+    /// samples in a 32 bit container). Integer samples are left-aligned in
+    /// their container.
+    pub valid_bits: u16,
+    /// The number of interleaved channels.
+    pub num_channels: u32,
+    /// The number of bytes in a single frame of all channels.
+    pub bytes_per_frame: u32,
+}
+
+/// How the device's buffer is divided into periods.
+///
+/// A "buffer size" can either mean the size of a single period or the size of
+/// the whole buffer. A Jack "buffer size" is the period size for example, but
+/// the device itself buffers `num_periods` of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferConfig {
+    /// The number of frames in each period. This is the number of frames
+    /// processed per interrupt of the device.
+    pub frames_per_period: u32,
+    /// The number of periods in the device's buffer.
+    pub num_periods: u32,
+}
+
+impl BufferConfig {
+    /// The total number of frames in the device's buffer
+    /// (`frames_per_period * num_periods`).
+    pub fn total_frames(&self) -> u32 {
+        self.frames_per_period.saturating_mul(self.num_periods)
+    }
+}
+
 #[cfg(feature = "midi")]
 /// MIDI information about a running stream.
 #[derive(Debug, Clone)]
@@ -147,7 +352,11 @@ pub struct MidiStreamInfo {
     /// appear in this exact same order.
     pub out_ports: Vec<MidiPortStreamInfo>,
 
-    /// The allocated size for each MIDI buffer.
+    /// The maximum number of events that each MIDI buffer can hold in a single
+    /// process cycle. This is the same for every port.
+    ///
+    /// Input events past this are dropped and reported with
+    /// `StreamMsg::MidiEventsDropped`.
     pub midi_buffer_size: usize,
 }
 
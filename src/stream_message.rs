@@ -1,9 +1,19 @@
-use crate::{error::StreamError, DeviceID};
+use crate::{error::StreamError, DeviceID, ExactFormat, StreamInfo};
 
 #[non_exhaustive]
 #[derive(Debug)]
 /// A message sent from the audio thread.
 pub enum StreamMsg {
+    /// The stream has started producing audio. This is sent once after the
+    /// first call to `process()` has completed.
+    ///
+    /// Use this instead of assuming that audio is flowing as soon as `run()`
+    /// returns.
+    ///
+    /// The stream info is boxed to keep every message in the message buffer
+    /// small.
+    Started(Box<StreamInfo>),
+
     /// An audio device was unplugged while the stream was running. Any connected
     /// ports will input/output silence.
     AudioDeviceDisconnected(DeviceID),
@@ -12,6 +22,10 @@ pub enum StreamMsg {
     /// ports will function properly now.
     ///
     /// This will only be sent after an `AudioDeviceDisconnected` event.
+    ///
+    /// The device may have been reopened with a different sample rate, number
+    /// of channels, or buffer size. By the time this is received,
+    /// `StreamHandle::stream_info()` returns the new configuration.
     AudioDeviceReconnected(DeviceID),
 
     #[cfg(feature = "midi")]
@@ -25,10 +39,109 @@ pub enum StreamMsg {
     /// This will only be sent after an `MidiDeviceDisconnected` event.
     MidiDeviceReconnected(DeviceID),
 
+    #[cfg(feature = "midi")]
+    /// More MIDI events arrived on a MIDI input port in a single process cycle
+    /// than its buffer can hold (`MidiStreamInfo::midi_buffer_size`), so the
+    /// extra events were dropped instead of growing the buffer on the audio
+    /// thread.
+    ///
+    /// `port` is the index of the buffer in `ProcessInfo::midi_inputs`, and
+    /// `count` is the number of events dropped since the last report.
+    MidiEventsDropped { port: usize, count: usize },
+
+    /// The output of the process handler went outside the range `[-1.0, 1.0]`
+    /// on the given channel, which may be hard-clipped by the device.
+    ///
+    /// `count` is the number of clipped samples since the last report. This is
+    /// sent at most once per second per channel.
+    ///
+    /// If multiple output channels are mixed into the same system port, then
+    /// clipping is detected on the mixed signal, and `channel` is the first of
+    /// those channels.
+    ///
+    /// This is only sent if `RunOptions::detect_clipping` is `true`.
+    OutputClipped { channel: usize, count: usize },
+
+    /// The process handler has taken too long to process several blocks in a
+    /// row, so the stream is likely to drop out. Increasing the block size may
+    /// fix this.
+    ///
+    /// This is sent once, and is not sent again until processing recovers.
+    ///
+    /// This is only sent if `RunOptions::detect_process_overload` is `true`.
+    ProcessOverload,
+
+    /// rtkit gave the audio thread realtime priority with the given priority.
+    ///
+    /// This is only sent on Linux with the `rtkit` feature, and only if the
+    /// audio thread did not already have realtime priority.
+    RealtimePriorityGranted { priority: u32 },
+
+    /// rtkit refused to give the audio thread realtime priority (i.e. rtkit is
+    /// not running, or the system limits do not allow it), so the audio may
+    /// glitch under load. This contains the reason.
+    ///
+    /// This is only sent on Linux with the `rtkit` feature.
+    RealtimePriorityDenied(String),
+
+    /// The stream is running, but not quite with the requested configuration.
+    ConfigWarning(ConfigWarning),
+
     /// An error that caused the stream to close. Please discard this Stream Handle
     /// channel and prepare to start a new stream.
+    ///
+    /// The audio thread has already stopped by the time this is received.
     Error(StreamError),
 
     /// The audio stream was closed gracefully. Please discard this Stream Handle.
     Closed,
 }
+
+#[derive(Debug, Clone, PartialEq)]
+/// Whether or not a configured port was opened and connected to the system
+/// port it references.
+pub struct PortOpenResult {
+    /// `true` if this is an input port, `false` if this is an output port.
+    pub is_input: bool,
+
+    /// The index of the buffer for this port in `ProcessInfo::audio_inputs`
+    /// or `ProcessInfo::audio_outputs`.
+    pub index: usize,
+
+    /// The name of the system port.
+    pub port_name: String,
+
+    /// Whether or not the port was successfully opened.
+    pub opened: bool,
+}
+
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq)]
+/// A non-fatal problem with the configuration of a running stream.
+pub enum ConfigWarning {
+    /// The WASAPI device failed to run in event-driven mode, so the stream
+    /// fell back to timer-driven mode.
+    WasapiFellBackToTimerDriven,
+
+    /// The device could not use the sample rate that was explicitly requested
+    /// in the config, so the stream is running at `actual` instead.
+    SampleRateNotUsed { requested: u32, actual: u32 },
+
+    /// Some of the configured ports could not be opened. The stream is still
+    /// running with the ports that did open, and the ports that failed will
+    /// input/output silence.
+    ///
+    /// This contains the result of every audio port in the stream, and is only
+    /// sent if `RunOptions::empty_buffers_for_failed_ports` is `true` (otherwise
+    /// the stream fails to run instead).
+    PortsFailedToOpen(Vec<PortOpenResult>),
+
+    /// The device could not use the block size that was explicitly requested
+    /// in the config, so the stream is running with `actual` instead.
+    BlockSizeNotUsed { requested: u32, actual: u32 },
+
+    /// The device no longer supports the format in `RainoutConfig::exact_format`
+    /// (i.e. it was changed in the system settings), so the stream negotiated a
+    /// new format instead. The actual format is in `StreamInfo`.
+    ExactFormatNotUsed(ExactFormat),
+}
@@ -1,9 +1,53 @@
-use std::sync::Once;
+use std::cell::RefCell;
+use std::time::Duration;
 
 use bitflags::bitflags;
 use wasapi::{DeviceCollection, Direction};
 
-static INIT: Once = Once::new();
+/// The number of times to try initializing COM before giving up.
+const COM_INIT_ATTEMPTS: u32 = 3;
+/// The time to wait before the first retry. This is doubled after each retry.
+const COM_INIT_BACKOFF: Duration = Duration::from_millis(10);
+
+thread_local! {
+    /// This is `Some` once COM has been initialized on this thread. COM is
+    /// uninitialized again when the guard is dropped as the thread exits.
+    static COM_GUARD: RefCell<Option<ComGuard>> = RefCell::new(None);
+}
+
+/// `COINIT_MULTITHREADED`
+const COINIT_MULTITHREADED: u32 = 0x0;
+/// `RPC_E_CHANGED_MODE`, returned when the thread was already initialized
+/// with a different concurrency model (i.e. a GUI thread that is STA).
+const RPC_E_CHANGED_MODE: i32 = 0x80010106_u32 as i32;
+
+/// The number of `ComGuard`s that are alive across all threads.
+#[cfg(test)]
+static LIVE_COM_GUARDS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+#[link(name = "ole32")]
+extern "system" {
+    fn CoInitializeEx(pv_reserved: *const std::ffi::c_void, dw_co_init: u32) -> i32;
+    fn CoUninitialize();
+}
+
+/// Uninitializes COM on the current thread when dropped.
+///
+/// Every successful call to `CoInitializeEx` must be paired with a call to
+/// `CoUninitialize` on the same thread, otherwise short-lived threads (i.e.
+/// ones spawned to enumerate devices) would leak their COM apartment.
+struct ComGuard;
+
+impl Drop for ComGuard {
+    fn drop(&mut self) {
+        // Safety: This guard is only created after COM was successfully
+        // initialized on this thread, and it never leaves this thread.
+        unsafe { CoUninitialize() };
+
+        #[cfg(test)]
+        LIVE_COM_GUARDS.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    }
+}
 
 // Defined at https://docs.microsoft.com/en-us/windows/win32/coreaudio/device-state-xxx-constants
 bitflags! {
@@ -16,20 +60,81 @@ bitflags! {
     }
 }
 
+use crate::enumeration::fallback_channel_name;
+use crate::error::RunConfigError;
 use crate::{
     AudioBackendOptions, AudioDeviceConfigOptions, AudioDeviceOptions, Backend, BackendStatus,
-    BlockSizeRange, ChannelLayout, DeviceID,
+    BlockSizeRange, ChannelLayout, DeviceID, SampleRateOptions,
 };
 
-pub(super) fn check_init() {
-    // Initialize this only once.
-    INIT.call_once(|| wasapi::initialize_mta().unwrap());
+/// Initialize COM on the current thread if it has not been initialized already.
+///
+/// COM initialization can transiently fail while devices are changing, so this
+/// will retry a few times with a short backoff before giving up.
+pub(super) fn check_init() -> Result<(), RunConfigError> {
+    // Safety: The reserved parameter must be null.
+    init_com_once(|| unsafe { CoInitializeEx(std::ptr::null(), COINIT_MULTITHREADED) })
+}
+
+/// The retry and once-per-thread logic of `check_init()`, with the call to
+/// `CoInitializeEx` passed in as `init`.
+fn init_com_once(mut init: impl FnMut() -> i32) -> Result<(), RunConfigError> {
+    COM_GUARD.with(|guard| {
+        if guard.borrow().is_some() {
+            return Ok(());
+        }
+
+        let mut backoff = COM_INIT_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            let hr = init();
+
+            // `S_FALSE` means COM was already initialized on this thread, but
+            // it still needs to be paired with a call to `CoUninitialize`.
+            if hr >= 0 {
+                #[cfg(test)]
+                LIVE_COM_GUARDS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+                *guard.borrow_mut() = Some(ComGuard);
+                return Ok(());
+            }
+
+            // Retrying won't change the concurrency model of the thread.
+            if hr == RPC_E_CHANGED_MODE {
+                log::error!(
+                    "Failed to initialize COM: the thread was already initialized as single-threaded (HRESULT {:#010x})",
+                    hr
+                );
+                return Err(RunConfigError::ComInitFailed(format!(
+                    "the thread was already initialized as single-threaded (HRESULT {:#010x})",
+                    hr
+                )));
+            }
+
+            if attempt >= COM_INIT_ATTEMPTS {
+                log::error!("Failed to initialize COM: HRESULT {:#010x}", hr);
+                return Err(RunConfigError::ComInitFailed(format!("HRESULT {:#010x}", hr)));
+            }
+
+            log::warn!("Failed to initialize COM (attempt {}), retrying: HRESULT {:#010x}", attempt, hr);
+            std::thread::sleep(backoff);
+            backoff *= 2;
+            attempt += 1;
+        }
+    })
 }
 
 pub fn enumerate_audio_backend() -> AudioBackendOptions {
     log::debug!("Enumerating WASAPI server...");
 
-    check_init();
+    if check_init().is_err() {
+        return AudioBackendOptions {
+            backend: Backend::Wasapi,
+            version: None,
+            status: BackendStatus::Error,
+            device_options: None,
+        };
+    }
 
     let coll = match DeviceCollection::new(&Direction::Render) {
         Ok(coll) => coll,
@@ -64,17 +169,7 @@ pub fn enumerate_audio_backend() -> AudioBackendOptions {
             Ok(device) => {
                 match device.get_id() {
                     Ok(device_id) => {
-                        let device_name = match device.get_friendlyname() {
-                            Ok(name) => name,
-                            Err(e) => {
-                                log::warn!(
-                                    "Failed to get name of WASAPI device with ID {}: {}",
-                                    &device_id,
-                                    e
-                                );
-                                String::from("unkown device")
-                            }
-                        };
+                        let device_name = device_name(&device, &device_id, i);
 
                         match device.get_state() {
                             Ok(state) => {
@@ -133,11 +228,26 @@ pub fn enumerate_audio_backend() -> AudioBackendOptions {
             device_options: None,
         }
     } else {
+        let default_device = default_device_id().and_then(|default_id| {
+            device_options.iter().position(|d| d.identifier.as_ref() == Some(&default_id))
+        });
+
+        let status = if default_device.is_some() {
+            BackendStatus::Running
+        } else {
+            log::warn!("The system has no default WASAPI output device");
+            BackendStatus::RunningNoDefault
+        };
+
         AudioBackendOptions {
             backend: Backend::Wasapi,
+            // WASAPI is versioned with the OS and does not report a version of its own.
             version: None,
-            status: BackendStatus::Running,
-            device_options: Some(AudioDeviceOptions::SingleDeviceOnly { options: device_options }),
+            status,
+            device_options: Some(AudioDeviceOptions::SingleDeviceOnly {
+                options: device_options,
+                default_device,
+            }),
         }
     }
 }
@@ -145,7 +255,7 @@ pub fn enumerate_audio_backend() -> AudioBackendOptions {
 pub fn enumerate_audio_device(device: &DeviceID) -> Result<AudioDeviceConfigOptions, ()> {
     log::debug!("Enumerating WASAPI device {} ...", &device.name);
 
-    check_init();
+    check_init().map_err(|_| ())?;
 
     let (id, wdevice, jack_unpopulated) = match find_device(device) {
         Some((id, device, jack_unpopulated)) => (id, device, jack_unpopulated),
@@ -169,15 +279,6 @@ pub fn enumerate_audio_device(device: &DeviceID) -> Result<AudioDeviceConfigOpti
         }
     };
 
-    // We only care about channels and sample rate, and not the sample type.
-    // We will always convert to/from `f32` buffers  anyway.
-    let default_sample_type = match default_format.get_subformat() {
-        Ok(s) => s,
-        Err(e) => {
-            log::error!("Failed to get default wave format of WASAPI device {}: {}", &id.name, e);
-            return Err(());
-        }
-    };
     let default_bps = default_format.get_bitspersample();
     let default_vbps = default_format.get_validbitspersample();
     let default_sample_rate = default_format.get_samplespersec();
@@ -193,6 +294,51 @@ pub fn enumerate_audio_device(device: &DeviceID) -> Result<AudioDeviceConfigOpti
     // TODO: Get channel mask from default format.
     let channel_layout = ChannelLayout::Unspecified;
 
+    // TODO: Use the names from the device topology (`IPart::GetName`) once the
+    // `wasapi` crate exposes it. Pro interfaces name their channels there.
+    let out_channel_names =
+        speaker_channel_names(default_format.get_dwchannelmask(), default_num_channels as usize);
+
+    // The options when only the default config in shared mode can be used.
+    let shared_mode_options = AudioDeviceConfigOptions {
+        sample_rates: Some(SampleRateOptions::List(vec![default_sample_rate])),
+        native_sample_rate: Some(default_sample_rate),
+        block_sizes: default_buffer_size.clone(),
+        exclusive_min_period: None,
+
+        num_in_channels: 0,
+        num_out_channels: default_num_channels as usize,
+
+        in_channel_names: Vec::new(),
+        out_channel_names: out_channel_names.clone(),
+
+        in_channel_layout: ChannelLayout::Unspecified,
+        out_channel_layout: channel_layout.clone(),
+
+        can_take_exclusive_access: false,
+
+        supports_direct_monitoring: false,
+
+        in_jack_is_unpopulated: false,
+        out_jack_is_unpopulated: jack_unpopulated,
+    };
+
+    // We only care about channels and sample rate, and not the sample type.
+    // We will always convert to/from `f32` buffers  anyway.
+    let default_sample_type = match default_format.get_subformat() {
+        Ok(s) => s,
+        Err(e) => {
+            // The device is still usable in shared mode since the audio engine
+            // will convert from `f32` buffers for us.
+            log::warn!(
+                "WASAPI device {} has an unrecognized mix format, only shared mode will be available: {}",
+                &id.name,
+                e
+            );
+            return Ok(shared_mode_options);
+        }
+    };
+
     // Check if this device supports running in exclusive mode.
     let supports_exclusive = match audio_client.is_supported(
         &wasapi::WaveFormat::new(
@@ -214,7 +360,10 @@ pub fn enumerate_audio_device(device: &DeviceID) -> Result<AudioDeviceConfigOpti
 
     if supports_exclusive {
         // Search through each common sample rate to see what is supported.
-        const sample_rates: [u32; 7] = [22_050, 44_100, 48_000, 88_200, 96_000, 176_400, 192_000];
+        const sample_rates: [u32; 13] = [
+            8_000, 11_025, 16_000, 22_050, 32_000, 44_100, 48_000, 88_200, 96_000, 176_400,
+            192_000, 352_800, 384_000,
+        ];
         let mut supported_sample_rates = Vec::new();
         for sr in sample_rates.iter() {
             match audio_client.is_supported(
@@ -237,45 +386,100 @@ pub fn enumerate_audio_device(device: &DeviceID) -> Result<AudioDeviceConfigOpti
             }
         }
 
+        // The period is in units of 100 nanoseconds.
+        let exclusive_min_period = match audio_client.get_periods() {
+            Ok((_default_period, min_period)) => {
+                Some(Duration::from_nanos(min_period.max(0) as u64 * 100))
+            }
+            Err(e) => {
+                log::error!("Failed to get device period of WASAPI device {}: {}", &id.name, e);
+                None
+            }
+        };
+
         Ok(AudioDeviceConfigOptions {
-            sample_rates: Some(supported_sample_rates),
+            sample_rates: Some(SampleRateOptions::List(supported_sample_rates)),
+            native_sample_rate: Some(default_sample_rate),
             block_sizes: default_buffer_size,
+            exclusive_min_period,
 
             num_in_channels: 0,
             num_out_channels: default_num_channels as usize,
 
+            in_channel_names: Vec::new(),
+            out_channel_names,
+
             in_channel_layout: ChannelLayout::Unspecified,
             out_channel_layout: channel_layout,
 
             can_take_exclusive_access: true,
 
+            supports_direct_monitoring: false,
+
             in_jack_is_unpopulated: false,
             out_jack_is_unpopulated: jack_unpopulated,
         })
     } else {
         // We must use the default config when running in shared mode.
+        Ok(shared_mode_options)
+    }
+}
 
-        Ok(AudioDeviceConfigOptions {
-            sample_rates: Some(vec![default_sample_rate]),
-            block_sizes: default_buffer_size,
-
-            num_in_channels: 0,
-            num_out_channels: default_num_channels as usize,
-
-            in_channel_layout: ChannelLayout::Unspecified,
-            out_channel_layout: channel_layout,
+/// Returns the ID of the system's default output device (if there is one).
+///
+/// This is the default device for the console role.
+// TODO: Report the default devices for the multimedia and communications roles
+// as well, and let the config request the default device for a given role. This
+// needs a newer version of the `wasapi` crate, since 0.10 only exposes the
+// default device for the console role.
+fn default_device_id() -> Option<String> {
+    match wasapi::get_default_device(&Direction::Render) {
+        Ok(device) => match device.get_id() {
+            Ok(id) => Some(id),
+            Err(e) => {
+                log::error!("Failed to get ID of default WASAPI device: {}", e);
+                None
+            }
+        },
+        Err(e) => {
+            log::debug!("Could not get default WASAPI device: {}", e);
+            None
+        }
+    }
+}
 
-            can_take_exclusive_access: false,
+/// Returns the friendly name of the given device.
+///
+/// If the name could not be retrieved (i.e. it contains characters that
+/// failed to decode), then a name is synthesized from the index of the
+/// device so that it still appears in the list of available devices.
+fn device_name(device: &wasapi::Device, device_id: &str, index: u32) -> String {
+    name_or_fallback(device.get_friendlyname(), device_id, index)
+}
 
-            in_jack_is_unpopulated: false,
-            out_jack_is_unpopulated: jack_unpopulated,
-        })
+/// Returns the name, or the name that is synthesized from the index of the
+/// device if it could not be retrieved.
+fn name_or_fallback<E: std::fmt::Display>(
+    name: Result<String, E>,
+    device_id: &str,
+    index: u32,
+) -> String {
+    match name {
+        Ok(name) => name,
+        Err(e) => {
+            log::warn!("Failed to get name of WASAPI device with ID {}: {}", device_id, e);
+            format!("Unknown Device #{}", index + 1)
+        }
     }
 }
 
+/// Find the given device (see `match_device()` for how it is matched).
 pub(super) fn find_device(device: &DeviceID) -> Option<(DeviceID, wasapi::Device, bool)> {
     log::debug!("Finding WASAPI device {} ...", &device.name);
 
+    // Every active device.
+    let mut candidates: Vec<(DeviceID, wasapi::Device, bool)> = Vec::new();
+
     let coll = match DeviceCollection::new(&Direction::Render) {
         Ok(coll) => coll,
         Err(e) => {
@@ -297,17 +501,7 @@ pub(super) fn find_device(device: &DeviceID) -> Option<(DeviceID, wasapi::Device
             Ok(d) => {
                 match d.get_id() {
                     Ok(device_id) => {
-                        let device_name = match d.get_friendlyname() {
-                            Ok(name) => name,
-                            Err(e) => {
-                                log::warn!(
-                                    "Failed to get name of WASAPI device with ID {}: {}",
-                                    &device_id,
-                                    e
-                                );
-                                String::from("unkown device")
-                            }
-                        };
+                        let device_name = device_name(&d, &device_id, i);
 
                         match d.get_state() {
                             Ok(state) => {
@@ -328,12 +522,10 @@ pub(super) fn find_device(device: &DeviceID) -> Option<(DeviceID, wasapi::Device
                                                 identifier: Some(device_id),
                                             };
 
-                                            if &id == device {
-                                                let jack_unpopulated =
-                                                    state.contains(DeviceState::UNPLUGGED);
+                                            let jack_unpopulated =
+                                                state.contains(DeviceState::UNPLUGGED);
 
-                                                return Some((id, d, jack_unpopulated));
-                                            }
+                                            candidates.push((id, d, jack_unpopulated));
                                         }
                                     }
                                     None => {
@@ -365,5 +557,291 @@ pub(super) fn find_device(device: &DeviceID) -> Option<(DeviceID, wasapi::Device
         }
     }
 
-    None
+    let i = match_device(device, candidates.iter().map(|(id, _, _)| id))?;
+    let found = candidates.swap_remove(i);
+
+    if device.identifier.is_some() && found.0.identifier != device.identifier {
+        log::warn!(
+            "Could not find WASAPI device with ID {:?}, falling back to device with name {}",
+            &device.identifier,
+            &device.name
+        );
+    }
+
+    Some(found)
+}
+
+/// Returns the index of the device in `candidates` that matches `device`.
+///
+/// Devices are matched by their endpoint ID, since names are not unique (i.e.
+/// two identical USB interfaces) and can be localized. The name is only used
+/// if the given device has no ID, or if no device with that ID exists anymore,
+/// in which case the first device with that name is used.
+fn match_device<'a>(
+    device: &DeviceID,
+    mut candidates: impl Iterator<Item = &'a DeviceID> + Clone,
+) -> Option<usize> {
+    if device.identifier.is_some() {
+        if let Some(i) = candidates.clone().position(|id| id.identifier == device.identifier) {
+            return Some(i);
+        }
+    }
+
+    candidates.position(|id| id.name == device.name)
+}
+
+/// The names of the speaker positions in a WASAPI channel mask, in the order
+/// of their bits (`SPEAKER_FRONT_LEFT`, `SPEAKER_FRONT_RIGHT`, ...).
+const SPEAKER_NAMES: [&str; 18] = [
+    "Front Left",
+    "Front Right",
+    "Front Center",
+    "LFE",
+    "Back Left",
+    "Back Right",
+    "Front Left of Center",
+    "Front Right of Center",
+    "Back Center",
+    "Side Left",
+    "Side Right",
+    "Top Center",
+    "Top Front Left",
+    "Top Front Center",
+    "Top Front Right",
+    "Top Back Left",
+    "Top Back Center",
+    "Top Back Right",
+];
+
+/// Name each output channel after its speaker position in the channel mask.
+///
+/// The channels are ordered the same as the set bits in the mask. Channels
+/// that are not in the mask (i.e. when the mask is `0`) are named "Output N".
+fn speaker_channel_names(channel_mask: u32, num_channels: usize) -> Vec<String> {
+    let mut speakers = SPEAKER_NAMES
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| channel_mask & (1 << bit) != 0)
+        .map(|(_, name)| *name);
+
+    (0..num_channels)
+        .map(|ch| match speakers.next() {
+            Some(name) => String::from(name),
+            None => fallback_channel_name(false, ch),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::Ordering;
+    use std::sync::Mutex;
+
+    const S_OK: i32 = 0;
+    const E_FAIL: i32 = 0x80004005_u32 as i32;
+
+    /// Tests that initialize COM hold this so that `LIVE_COM_GUARDS` is not
+    /// changed by other tests running in parallel.
+    static COM_TEST_LOCK: Mutex<()> = Mutex::new(());
+
+    fn lock_com_tests() -> std::sync::MutexGuard<'static, ()> {
+        COM_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    #[test]
+    fn com_is_initialized_once_per_thread() {
+        let _lock = lock_com_tests();
+
+        std::thread::spawn(|| {
+            let mut calls: u32 = 0;
+            assert!(init_com_once(|| {
+                calls += 1;
+                S_OK
+            })
+            .is_ok());
+            assert!(init_com_once(|| {
+                calls += 1;
+                S_OK
+            })
+            .is_ok());
+            assert_eq!(calls, 1);
+        })
+        .join()
+        .unwrap();
+
+        // A new thread needs to initialize COM again.
+        std::thread::spawn(|| {
+            let mut calls: u32 = 0;
+            assert!(init_com_once(|| {
+                calls += 1;
+                S_OK
+            })
+            .is_ok());
+            assert_eq!(calls, 1);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn com_init_retries_transient_failures() {
+        let _lock = lock_com_tests();
+
+        std::thread::spawn(|| {
+            let mut calls: u32 = 0;
+            assert!(init_com_once(|| {
+                calls += 1;
+                if calls < COM_INIT_ATTEMPTS {
+                    E_FAIL
+                } else {
+                    S_OK
+                }
+            })
+            .is_ok());
+            assert_eq!(calls, COM_INIT_ATTEMPTS);
+
+            let mut calls: u32 = 0;
+            assert!(init_com_once(|| {
+                calls += 1;
+                S_OK
+            })
+            .is_ok());
+            assert_eq!(calls, 0);
+        })
+        .join()
+        .unwrap();
+
+        std::thread::spawn(|| {
+            let mut calls: u32 = 0;
+            assert!(matches!(
+                init_com_once(|| {
+                    calls += 1;
+                    E_FAIL
+                }),
+                Err(RunConfigError::ComInitFailed(_))
+            ));
+            assert_eq!(calls, COM_INIT_ATTEMPTS);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn com_init_fails_at_once_on_changed_mode() {
+        let _lock = lock_com_tests();
+
+        std::thread::spawn(|| {
+            let mut calls: u32 = 0;
+            assert!(matches!(
+                init_com_once(|| {
+                    calls += 1;
+                    RPC_E_CHANGED_MODE
+                }),
+                Err(RunConfigError::ComInitFailed(_))
+            ));
+            assert_eq!(calls, 1);
+
+            // No guard was stored, so COM is not uninitialized on this thread.
+            assert!(COM_GUARD.with(|guard| guard.borrow().is_none()));
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn com_is_uninitialized_when_threads_exit() {
+        let _lock = lock_com_tests();
+
+        assert_eq!(LIVE_COM_GUARDS.load(Ordering::SeqCst), 0);
+
+        let threads: Vec<_> = (0..32)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    check_init().unwrap();
+                    check_init().unwrap();
+                    assert!(COM_GUARD.with(|guard| guard.borrow().is_some()));
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(LIVE_COM_GUARDS.load(Ordering::SeqCst), 0);
+    }
+
+    fn id(name: &str, identifier: Option<&str>) -> DeviceID {
+        DeviceID { name: String::from(name), identifier: identifier.map(String::from) }
+    }
+
+    #[test]
+    fn match_device_by_identifier() {
+        // Two identical USB interfaces.
+        let candidates = [
+            id("Speakers", Some("{0.0.0.00000000}.{aaaa}")),
+            id("USB Audio", Some("{0.0.0.00000000}.{bbbb}")),
+            id("USB Audio", Some("{0.0.0.00000000}.{cccc}")),
+        ];
+
+        assert_eq!(
+            match_device(&id("USB Audio", Some("{0.0.0.00000000}.{cccc}")), candidates.iter()),
+            Some(2)
+        );
+        assert_eq!(
+            match_device(&id("USB Audio", Some("{0.0.0.00000000}.{bbbb}")), candidates.iter()),
+            Some(1)
+        );
+
+        // The ID wins over the name, i.e. when the device was renamed.
+        assert_eq!(
+            match_device(&id("USB Audio", Some("{0.0.0.00000000}.{aaaa}")), candidates.iter()),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn match_device_falls_back_to_name() {
+        let candidates = [
+            id("Speakers", Some("{0.0.0.00000000}.{aaaa}")),
+            id("USB Audio", Some("{0.0.0.00000000}.{bbbb}")),
+            id("USB Audio", Some("{0.0.0.00000000}.{cccc}")),
+        ];
+
+        // Without an ID, or with an ID that no longer exists, the first device
+        // with the same name is used.
+        assert_eq!(match_device(&id("USB Audio", None), candidates.iter()), Some(1));
+        assert_eq!(
+            match_device(&id("USB Audio", Some("{0.0.0.00000000}.{dddd}")), candidates.iter()),
+            Some(1)
+        );
+
+        assert_eq!(match_device(&id("Headphones", None), candidates.iter()), None);
+        assert_eq!(
+            match_device(&id("Headphones", Some("{0.0.0.00000000}.{dddd}")), candidates.iter()),
+            None
+        );
+    }
+
+    #[test]
+    fn non_ascii_device_names() {
+        for name in [
+            "Lautsprecher (Realtek® Audio)",
+            "スピーカー (USB オーディオ)",
+            "Динамики",
+            "🎧 Headphones",
+        ] {
+            assert_eq!(name_or_fallback::<String>(Ok(String::from(name)), "{1}", 0), name);
+        }
+    }
+
+    #[test]
+    fn fallback_device_names() {
+        let err = || Err(String::from("invalid UTF-16"));
+
+        assert_eq!(name_or_fallback(err(), "{1}", 0), "Unknown Device #1");
+        assert_eq!(name_or_fallback(err(), "{3}", 2), "Unknown Device #3");
+    }
 }
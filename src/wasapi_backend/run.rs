@@ -1,22 +1,35 @@
+use std::collections::VecDeque;
 use std::error::Error;
+use std::panic::AssertUnwindSafe;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    mpsc, Arc,
+    atomic::{AtomicBool, AtomicU32, Ordering},
+    mpsc, Arc, Mutex,
 };
 
-use ringbuf::RingBuffer;
+use ringbuf::{Consumer, RingBuffer};
 use wasapi::SampleType;
 
 const PREALLOC_FRAMES: usize = 48_000;
 
+/// The number of streams that have been run, used to give the thread of each
+/// stream a distinct name.
+static NUM_STREAMS: AtomicU32 = AtomicU32::new(0);
+
+use crate::audio_thread::mark_audio_thread;
+use crate::block_size_stats::SharedBlockSizeStats;
+use crate::clip_detector::ClipDetector;
+use crate::dither::Ditherer;
+use crate::glitch_counts::SharedGlitchCounts;
+use crate::overload_detector::OverloadDetector;
 use crate::{
     error::{ChangeBlockSizeError, RunConfigError, StreamError},
     ProcessInfo,
 };
 use crate::{
-    AudioBufferStreamInfo, AudioDeviceConfig, AudioDeviceStreamInfo, AutoOption, Backend,
-    BlockSizeRange, ChannelLayout, DeviceID, PlatformStreamHandle, ProcessHandler, RainoutConfig,
-    RunOptions, StreamHandle, StreamInfo, StreamMsg,
+    validate_backend_device_compatibility, AudioBufferStreamInfo, AudioDeviceConfig,
+    AudioDeviceStreamInfo, AutoOption, Backend, BlockSizeRange, BufferConfig, ChannelLayout,
+    ConfigWarning, DeviceID, NativeSampleFormat, PlatformStreamHandle, ProcessHandler,
+    RainoutConfig, RunOptions, StreamHandle, StreamInfo, StreamMsg, WasapiMode,
 };
 
 #[cfg(feature = "midi")]
@@ -29,27 +42,46 @@ pub fn estimated_sample_rate_and_latency(
     Ok((None, None))
 }
 
-pub fn run<P: ProcessHandler>(
+pub fn run<P: ProcessHandler<M>, M: Send + 'static>(
     config: &RainoutConfig,
     options: &RunOptions,
     process_handler: P,
-) -> Result<StreamHandle<P>, RunConfigError> {
-    let (res_tx, res_rx) = mpsc::channel::<Result<StreamHandle<P>, RunConfigError>>();
+) -> Result<StreamHandle<P, M>, RunConfigError> {
+    validate_backend_device_compatibility(Backend::Wasapi, &config.audio_device)?;
+
+    let (res_tx, res_rx) = mpsc::channel::<Result<StreamHandle<P, M>, RunConfigError>>();
 
     let config = config.clone();
     let options = options.clone();
 
+    // Each stream gets its own thread (and COM apartment), so several streams can
+    // run at the same time.
+    //
     // TODO: Make sure we spawn a thread with high priority.
-    std::thread::spawn(move || match spawn_stream(config, options, process_handler) {
-        Ok((stream_handle, audio_thread)) => {
-            res_tx.send(Ok(stream_handle)).unwrap();
+    let on_teardown = options.on_teardown.clone();
+    let spawn_res = std::thread::Builder::new().name(stream_thread_name()).spawn(move || {
+        match spawn_stream(config, options, process_handler) {
+            Ok((stream_handle, audio_thread)) => {
+                res_tx.send(Ok(stream_handle)).unwrap();
 
-            audio_thread.run();
-        }
-        Err(e) => {
-            res_tx.send(Err(e)).unwrap();
+                // The device is released once the audio thread returns.
+                audio_thread.run();
+
+                if let Some(on_teardown) = on_teardown {
+                    on_teardown.call();
+                }
+            }
+            Err(e) => {
+                res_tx.send(Err(e)).unwrap();
+            }
         }
     });
+    if let Err(e) = spawn_res {
+        return Err(RunConfigError::PlatformSpecific(format!(
+            "Failed to spawn WASAPI thread: {}",
+            e
+        )));
+    }
 
     // Wait for the returned value.
     match res_rx.recv_timeout(std::time::Duration::from_secs(10)) {
@@ -61,13 +93,140 @@ pub fn run<P: ProcessHandler>(
     }
 }
 
-fn spawn_stream<P: ProcessHandler>(
+fn spawn_stream<P: ProcessHandler<M>, M: Send + 'static>(
     config: RainoutConfig,
     options: RunOptions,
     mut process_handler: P,
-) -> Result<(StreamHandle<P>, AudioThread<P>), RunConfigError> {
-    super::check_init();
+) -> Result<(StreamHandle<P, M>, AudioThread<P, M>), RunConfigError> {
+    super::check_init()?;
+
+    let mut config_warnings = Vec::new();
+    let device = open_device(&config, &options, &mut config_warnings)?;
+
+    let stream_dropped = Arc::new(AtomicBool::new(false));
+    let stream_dropped_clone = Arc::clone(&stream_dropped);
+
+    let (mut to_handle_tx, from_audio_thread_rx) =
+        RingBuffer::<StreamMsg>::new(options.msg_buffer_size).split();
+    for warning in config_warnings {
+        let _ = to_handle_tx.push(StreamMsg::ConfigWarning(warning));
+    }
+    let (user_msg_tx, user_msg_rx) = RingBuffer::<M>::new(options.user_msg_buffer_size).split();
+
+    let stream_info = device.stream_info.clone();
+
+    process_handler.init(&stream_info);
+
+    let clip_detector = if options.detect_clipping {
+        Some(ClipDetector::new(device.channels, stream_info.sample_rate))
+    } else {
+        None
+    };
+
+    let overload_detector = if options.detect_process_overload {
+        Some(OverloadDetector::new(
+            stream_info.sample_rate,
+            options.process_overload_threshold,
+            options.process_overload_blocks,
+        ))
+    } else {
+        None
+    };
+
+    let block_size_stats = if options.collect_block_size_stats {
+        Some(Arc::new(SharedBlockSizeStats::new()))
+    } else {
+        None
+    };
+
+    let glitch_counts = Arc::new(SharedGlitchCounts::new());
+
+    // The dither noise doesn't need to be high quality, so just seed it from the
+    // current time.
+    let dither_seed = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    let ditherer = Ditherer::new(options.dither, dither_seed);
+
+    // Reconnect to the same device that was opened, even if the config asked for
+    // the default device.
+    let reconnect_config = if options.auto_reconnect {
+        match &stream_info.audio_device {
+            AudioDeviceStreamInfo::Single { id, .. } => Some(RainoutConfig {
+                audio_device: AudioDeviceConfig::Single(id.clone()),
+                ..config.clone()
+            }),
+            _ => None,
+        }
+    } else {
+        None
+    };
+
+    let shared_stream_info = Arc::new(Mutex::new(stream_info.clone()));
 
+    Ok((
+        StreamHandle {
+            messages: from_audio_thread_rx,
+            user_messages: user_msg_tx,
+            platform_handle: Box::new(WasapiStreamHandle {
+                stream_info: Arc::clone(&shared_stream_info),
+                stream_dropped,
+            }),
+            block_size_stats: block_size_stats.clone(),
+            glitch_counts: Arc::clone(&glitch_counts),
+            // The only inputs are the loopback capture, which would feed back
+            // into itself if it were monitored.
+            monitor_mix: None,
+        },
+        AudioThread {
+            stream_dropped: stream_dropped_clone,
+            device,
+            to_handle_tx,
+            num_scratch_buffers: options.num_scratch_buffers,
+            zero_outputs: options.zero_unwritten_outputs,
+            catch_process_panics: options.catch_process_panics,
+            process_handler,
+            user_msg_rx,
+            clip_detector,
+            overload_detector,
+            block_size_stats,
+            glitch_counts,
+            ditherer,
+            output_frame: 0,
+            is_first_block: true,
+            started_msg_info: Some(Box::new(stream_info)),
+            shared_stream_info,
+            reconnect_config,
+            options,
+        },
+    ))
+}
+
+/// A WASAPI output device that has been opened and started.
+struct DeviceStream {
+    audio_client: wasapi::AudioClient,
+    waiter: DeviceWaiter,
+    render_client: wasapi::AudioRenderClient,
+    block_align: usize,
+    vbps: u16,
+    sample_type: wasapi::SampleType,
+    channels: usize,
+    max_frames: usize,
+    device_buffer_frames: usize,
+    // In exclusive event-driven mode the whole device buffer is handed over at
+    // every event, so a free buffer does not mean that the device underran.
+    exclusive_event_driven: bool,
+    loopback: Option<LoopbackCapture>,
+    stream_info: StreamInfo,
+}
+
+/// Open and start the device in the given config.
+fn open_device(
+    config: &RainoutConfig,
+    options: &RunOptions,
+    config_warnings: &mut Vec<ConfigWarning>,
+) -> Result<DeviceStream, RunConfigError> {
     let (id, device) = match &config.audio_device {
         AudioDeviceConfig::Auto => match wasapi::get_default_device(&wasapi::Direction::Render) {
             Ok(device) => {
@@ -100,25 +259,44 @@ fn spawn_stream<P: ProcessHandler>(
                 return Err(RunConfigError::AudioDeviceNotFound(device_id.clone()));
             }
         }
-        AudioDeviceConfig::LinkedInOut { .. } => {
-            return Err(RunConfigError::MalformedConfig(String::from(
-                "WASAPI backend does not support linked in/out devices",
-            )));
-        }
-        #[cfg(any(feature = "jack-linux", feature = "jack-macos", feature = "jack-windows"))]
-        AudioDeviceConfig::Jack { .. } => {
+        // Every other device config was rejected by
+        // `validate_backend_device_compatibility()` in `run()`.
+        _ => {
             return Err(RunConfigError::MalformedConfig(String::from(
-                "Jack device in WASAPI backend",
+                "WASAPI backend does not support this device config",
             )));
         }
     };
 
     let mut audio_client = device.get_iaudioclient()?;
     let default_format = audio_client.get_mixformat()?;
-    let default_sample_type = default_format.get_subformat()?;
     let (default_period, min_period) = audio_client.get_periods()?;
-    let default_bps = default_format.get_bitspersample();
-    let default_vbps = default_format.get_validbitspersample();
+    let (default_sample_type, default_bps, default_vbps) = match default_format.get_subformat() {
+        Ok(sample_type) => (
+            sample_type,
+            default_format.get_bitspersample(),
+            default_format.get_validbitspersample(),
+        ),
+        Err(e) => {
+            if config.take_exclusive_access {
+                log::error!(
+                    "Cannot run WASAPI device {} in exclusive mode, it has an unrecognized mix format: {}",
+                    &id.name,
+                    e
+                );
+                return Err(RunConfigError::CouldNotUseExclusive);
+            }
+
+            // Fall back to `f32` samples, which the audio engine will convert for us
+            // in shared mode.
+            log::warn!(
+                "WASAPI device {} has an unrecognized mix format, falling back to f32 samples: {}",
+                &id.name,
+                e
+            );
+            (wasapi::SampleType::Float, 32, 32)
+        }
+    };
     let default_sample_rate = default_format.get_samplespersec();
     let default_num_channels = default_format.get_nchannels();
 
@@ -130,9 +308,78 @@ fn spawn_stream<P: ProcessHandler>(
         return Err(RunConfigError::AutoNoStereoOutputFound);
     }
 
+    // The maximum number of frames passed to the process method at once.
+    let max_frames = match config.block_size {
+        AutoOption::Use(block_size) => block_size,
+        AutoOption::Auto => options.max_buffer_size,
+    };
+
+    // Try the exact format that was validated before, if there is one.
+    let exact_format = config.exact_format.as_ref().and_then(|exact| {
+        let sample_type = if exact.sample_format.is_float {
+            wasapi::SampleType::Float
+        } else {
+            wasapi::SampleType::Int
+        };
+        let share_mode = if exact.take_exclusive_access {
+            wasapi::ShareMode::Exclusive
+        } else {
+            wasapi::ShareMode::Shared
+        };
+
+        let is_supported = exact.sample_format.num_channels == u32::from(default_num_channels)
+            && matches!(
+                audio_client.is_supported(
+                    &wasapi::WaveFormat::new(
+                        exact.sample_format.container_bits as usize,
+                        exact.sample_format.valid_bits as usize,
+                        &sample_type,
+                        exact.sample_rate as usize,
+                        default_num_channels as usize,
+                    ),
+                    &share_mode,
+                ),
+                Ok(None)
+            );
+
+        if is_supported {
+            Some((exact, share_mode, sample_type))
+        } else {
+            log::warn!(
+                "WASAPI device {} no longer supports the format {:?}, negotiating a new one",
+                &id.name,
+                exact
+            );
+            config_warnings.push(ConfigWarning::ExactFormatNotUsed(exact.clone()));
+            None
+        }
+    });
+
     // Check if this device supports running in exclusive mode.
-    let (share_mode, sample_rate, bps, vbps, sample_type, period) = if config.take_exclusive_access
+    let (share_mode, sample_rate, bps, vbps, sample_type, mut period) = if let Some((
+        exact,
+        share_mode,
+        sample_type,
+    )) = exact_format
     {
+        let period = match share_mode {
+            wasapi::ShareMode::Exclusive => {
+                exclusive_period(config, &id, exact.sample_rate, min_period, config_warnings)
+            }
+            wasapi::ShareMode::Shared => {
+                shared_period(config, &id, exact.sample_rate, default_period, config_warnings)
+            }
+        };
+
+        (
+            share_mode,
+            exact.sample_rate,
+            exact.sample_format.container_bits,
+            exact.sample_format.valid_bits,
+            sample_type,
+            period,
+        )
+    } else if config.take_exclusive_access {
         let supports_exclusive = match audio_client.is_supported(
             &wasapi::WaveFormat::new(
                 default_bps as usize,
@@ -178,6 +425,8 @@ fn spawn_stream<P: ProcessHandler>(
             }
             sample_rate
         } else {
+            // Use the device's native sample rate (from its mix format), so that
+            // Windows doesn't resample the stream.
             default_sample_rate
         };
 
@@ -218,15 +467,36 @@ fn spawn_stream<P: ProcessHandler>(
             }
         };
 
-        (wasapi::ShareMode::Exclusive, sample_rate, bps, vbps, sample_type, min_period)
+        let period = exclusive_period(config, &id, sample_rate, min_period, config_warnings);
+
+        (wasapi::ShareMode::Exclusive, sample_rate, bps, vbps, sample_type, period)
     } else {
+        // The sample rate cannot be changed in shared mode.
+        if let AutoOption::Use(sample_rate) = config.sample_rate {
+            if sample_rate != default_sample_rate {
+                log::warn!(
+                    "Could not use sample rate {} on WASAPI device {} in shared mode, using {} instead",
+                    sample_rate,
+                    &id.name,
+                    default_sample_rate
+                );
+                config_warnings.push(ConfigWarning::SampleRateNotUsed {
+                    requested: sample_rate,
+                    actual: default_sample_rate,
+                });
+            }
+        }
+
+        let period =
+            shared_period(config, &id, default_sample_rate, default_period, config_warnings);
+
         (
             wasapi::ShareMode::Shared,
             default_sample_rate,
             default_bps,
             default_vbps,
             default_sample_type,
-            default_period,
+            period,
         )
     };
 
@@ -242,95 +512,529 @@ fn spawn_stream<P: ProcessHandler>(
 
     // TODO: MIDI stuff
 
-    audio_client.initialize_client(
+    // TODO: Let the application set the audio session category (Media, Communications,
+    // Game, etc.) so that Windows ducks the stream correctly, and expose the session volume
+    // on the stream handle. The category must be set with `IAudioClient2::SetClientProperties`
+    // before the client is initialized, and the volume is controlled through
+    // `ISimpleAudioVolume`, but neither of these are exposed by the version of the `wasapi`
+    // crate we currently depend on.
+
+    let mut buffer_duration =
+        client_buffer_duration(&share_mode, period, options.wasapi_extra_periods);
+
+    if let Err(e) = audio_client.initialize_client(
         &desired_format,
-        period,
+        buffer_duration,
         &wasapi::Direction::Render,
         &share_mode,
         false,
-    )?;
+    ) {
+        // In exclusive mode some devices require the buffer size to be aligned to
+        // their own block size (`AUDCLNT_E_BUFFER_SIZE_NOT_ALIGNED`). Only in that case
+        // can the buffer size still be queried from the failed client, and it gives the
+        // aligned size. The client then has to be recreated with the aligned period.
+        let aligned_frames = match share_mode {
+            wasapi::ShareMode::Exclusive => {
+                audio_client.get_bufferframecount().ok().filter(|frames| *frames > 0)
+            }
+            wasapi::ShareMode::Shared => None,
+        };
+        let Some(aligned_frames) = aligned_frames else {
+            return Err(e.into());
+        };
+
+        log::warn!(
+            "WASAPI device {} requires an aligned buffer size, retrying with {} frames",
+            &id.name,
+            aligned_frames
+        );
+
+        period = (10_000_000.0 * f64::from(aligned_frames) / f64::from(sample_rate)).round() as i64;
+        buffer_duration = period;
+
+        // Report the aligned size against the block size that was requested, replacing
+        // any warning `exclusive_period()` already pushed for the unaligned period.
+        if let AutoOption::Use(block_size) = config.block_size {
+            config_warnings.retain(|w| !matches!(w, ConfigWarning::BlockSizeNotUsed { .. }));
+            if block_size != aligned_frames {
+                config_warnings.push(ConfigWarning::BlockSizeNotUsed {
+                    requested: block_size,
+                    actual: aligned_frames,
+                });
+            }
+        }
+
+        audio_client = device.get_iaudioclient()?;
+        if let Err(e) = audio_client.initialize_client(
+            &desired_format,
+            period,
+            &wasapi::Direction::Render,
+            &share_mode,
+            false,
+        ) {
+            log::error!(
+                "Failed to initialize WASAPI device {} with the aligned buffer size of {} frames: {}",
+                &id.name,
+                aligned_frames,
+                e
+            );
+            return Err(e.into());
+        }
+    }
+
+    // The `wasapi` crate always initializes the client with `AUDCLNT_STREAMFLAGS_EVENTCALLBACK`,
+    // and such a client can't be started until an event handle is registered. So the event
+    // handle is registered even in timer-driven mode, where the audio thread never waits on it.
+    let (h_event, event_driven) = match audio_client.set_get_eventhandle() {
+        Ok(h_event) => (h_event, options.wasapi_mode == WasapiMode::EventDriven),
+        Err(e) => {
+            log::warn!(
+                "Failed to register an event handle for WASAPI device {}, recreating the client: {}",
+                &id.name,
+                e
+            );
+
+            // A client that failed to register its event handle can't be started, so it
+            // has to be recreated. The device already failed to use an event once, so the
+            // new client is never waited on.
+            audio_client = device.get_iaudioclient()?;
+            audio_client.initialize_client(
+                &desired_format,
+                buffer_duration,
+                &wasapi::Direction::Render,
+                &share_mode,
+                false,
+            )?;
+            let h_event = audio_client.set_get_eventhandle()?;
+
+            if options.wasapi_mode == WasapiMode::EventDriven {
+                log::warn!("Falling back to timer-driven mode for WASAPI device {}", &id.name);
+                config_warnings.push(ConfigWarning::WasapiFellBackToTimerDriven);
+            }
 
-    let h_event = audio_client.set_get_eventhandle()?;
+            (h_event, false)
+        }
+    };
+
+    // The period is in units of 100 nanoseconds.
+    let timer_interval = std::time::Duration::from_nanos(period.max(0) as u64 * 100 / 2);
+    let period_frames = (period.max(0) as u64 * u64::from(sample_rate) / 10_000_000) as u32;
 
     let render_client = audio_client.get_audiorenderclient()?;
 
-    audio_client.start_stream()?;
+    // Prime the whole device buffer with silence before starting the stream, otherwise
+    // the device will immediately underrun and cause an audible pop.
+    let buffer_frames = audio_client.get_bufferframecount()?;
+    let prefill_frames = buffer_frames as usize;
+    let silence = vec![0u8; prefill_frames * block_align as usize];
+    render_client.write_to_device(prefill_frames, block_align as usize, &silence, None)?;
 
-    let stream_dropped = Arc::new(AtomicBool::new(false));
-    let stream_dropped_clone = Arc::clone(&stream_dropped);
+    audio_client.start_stream()?;
 
-    let (to_handle_tx, from_audio_thread_rx) =
-        RingBuffer::<StreamMsg>::new(options.msg_buffer_size).split();
+    let loopback = if options.wasapi_loopback_input {
+        if !matches!(share_mode, wasapi::ShareMode::Shared)
+            || !matches!(sample_type, SampleType::Float)
+            || bps != 32
+        {
+            log::warn!(
+                "WASAPI loopback capture of device {} is only available in shared mode with a 32 bit float mix format",
+                &id.name
+            );
+            None
+        } else {
+            match LoopbackCapture::open(&device, &desired_format, period, block_align as usize) {
+                Ok(loopback) => Some(loopback),
+                Err(e) => {
+                    log::warn!(
+                        "Failed to start WASAPI loopback capture of device {}: {}",
+                        &id.name,
+                        e
+                    );
+                    None
+                }
+            }
+        }
+    } else {
+        None
+    };
+    let num_in_channels = if loopback.is_some() { u32::from(default_num_channels) } else { 0 };
 
     let stream_info = StreamInfo {
         audio_backend: Backend::Wasapi,
         audio_backend_version: None,
         audio_device: AudioDeviceStreamInfo::Single { id, connected_to_system: true },
         sample_rate,
-        buffer_size: AudioBufferStreamInfo::UnfixedWithMaxSize(options.max_buffer_size),
-        num_in_channels: 0,
+        // In exclusive event-driven mode the whole buffer (one period) is handed over
+        // every cycle, so `process()` always gets the same number of frames.
+        buffer_size: if matches!(share_mode, wasapi::ShareMode::Exclusive)
+            && event_driven
+            && buffer_frames > 0
+            && buffer_frames <= max_frames
+        {
+            AudioBufferStreamInfo::FixedSized(buffer_frames)
+        } else {
+            AudioBufferStreamInfo::UnfixedWithMaxSize(max_frames)
+        },
+        buffer_config: if period_frames > 0 {
+            Some(BufferConfig {
+                frames_per_period: period_frames,
+                // The device buffer is not always an exact multiple of the period.
+                num_periods: buffer_frames.div_ceil(period_frames),
+            })
+        } else {
+            None
+        },
+        num_in_channels,
         num_out_channels: default_num_channels as u32,
-        in_channel_layout: ChannelLayout::Unspecified,
+        in_channel_layout: if loopback.is_some() {
+            channel_layout.clone()
+        } else {
+            ChannelLayout::Unspecified
+        },
         out_channel_layout: channel_layout,
-        estimated_latency: None,           // TODO: Get estimated latency.
-        checking_for_silent_inputs: false, // We don't support inputs with WASAPI.
+        input_is_loopback: loopback.is_some(),
+        estimated_latency: None, // TODO: Get estimated latency.
+        // The loopback stream is read once every period. Physical inputs aren't
+        // supported with WASAPI.
+        input_latency: if loopback.is_some() { Some(period_frames) } else { None },
+        output_latency: Some(period_frames),
+        resampler_latency: Some(0), // We always use the device's sample rate.
+        // The part of the device buffer beyond a single period.
+        safety_latency: Some(buffer_frames.saturating_sub(period_frames)),
+        checking_for_silent_inputs: false, // We don't support checking for silence with WASAPI.
+        native_format: Some(NativeSampleFormat {
+            is_float: matches!(sample_type, SampleType::Float),
+            container_bits: bps,
+            valid_bits: vbps,
+            num_channels: u32::from(default_num_channels),
+            bytes_per_frame: block_align,
+        }),
 
         #[cfg(feature = "midi")]
         midi_info: None, // TODO
     };
 
-    process_handler.init(&stream_info);
+    Ok(DeviceStream {
+        audio_client,
+        waiter: DeviceWaiter { render_event: h_event, event_driven, timer_interval },
+        render_client,
+        block_align: block_align as usize,
+        vbps,
+        sample_type,
+        channels: default_num_channels as usize,
+        max_frames: max_frames as usize,
+        device_buffer_frames: buffer_frames as usize,
+        exclusive_event_driven: matches!(share_mode, wasapi::ShareMode::Exclusive) && event_driven,
+        loopback,
+        stream_info,
+    })
+}
 
-    Ok((
-        StreamHandle {
-            messages: from_audio_thread_rx,
-            platform_handle: Box::new(WasapiStreamHandle { stream_info, stream_dropped }),
-        },
-        AudioThread {
-            stream_dropped: stream_dropped_clone,
+/// A loopback capture of the audio that is being played on a render device.
+///
+/// The render stream drives the audio thread, so this is read once every time
+/// the render stream needs more data, and the captured frames are queued until
+/// they are passed to the process handler.
+struct LoopbackCapture {
+    audio_client: wasapi::AudioClient,
+    capture_client: wasapi::AudioCaptureClient,
+    // The `wasapi` crate always opens loopback streams in event-driven mode, so an
+    // event handle has to be set even though it is never waited on.
+    _event: wasapi::Handle,
+    queue: VecDeque<u8>,
+    block_align: usize,
+}
+
+impl LoopbackCapture {
+    /// The maximum number of device packets to read at once.
+    const MAX_PACKETS_PER_READ: usize = 16;
+
+    fn open(
+        device: &wasapi::Device,
+        format: &wasapi::WaveFormat,
+        period: i64,
+        block_align: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut audio_client = device.get_iaudioclient()?;
+
+        // Opening a capture stream on a render device makes the `wasapi` crate
+        // open it as a loopback stream.
+        audio_client.initialize_client(
+            format,
+            period,
+            &wasapi::Direction::Capture,
+            &wasapi::ShareMode::Shared,
+            false,
+        )?;
+        let event = audio_client.set_get_eventhandle()?;
+        let capture_client = audio_client.get_audiocaptureclient()?;
+
+        audio_client.start_stream()?;
+
+        Ok(Self {
             audio_client,
-            h_event,
-            render_client,
-            block_align: block_align as usize,
-            vbps,
-            sample_type,
-            channels: default_num_channels as usize,
-            to_handle_tx,
-            max_frames: options.max_buffer_size as usize,
-            process_handler,
-        },
-    ))
+            capture_client,
+            _event: event,
+            queue: VecDeque::with_capacity(PREALLOC_FRAMES * block_align),
+            block_align,
+        })
+    }
+
+    /// Move every captured frame from the device into the queue.
+    fn read(&mut self) -> Result<(), Box<dyn Error>> {
+        for _ in 0..Self::MAX_PACKETS_PER_READ {
+            let len = self.queue.len();
+            self.capture_client.read_from_device_to_deque(self.block_align, &mut self.queue)?;
+            if self.queue.len() == len {
+                break;
+            }
+        }
+
+        // If the process handler falls behind, drop the oldest frames instead of
+        // growing the queue on the audio thread.
+        let max_len = PREALLOC_FRAMES * self.block_align;
+        if self.queue.len() > max_len {
+            let excess = self.queue.len() - max_len;
+            self.queue.drain(0..excess);
+        }
+
+        Ok(())
+    }
+
+    /// Fill the input buffers with `frames` frames from the queue. Any frames
+    /// that haven't been captured yet are filled with silence.
+    fn fill_buffers(&mut self, buffers: &mut [Vec<f32>], frames: usize) {
+        let channel_align = self.block_align / buffers.len().max(1);
+
+        for buffer in buffers.iter_mut() {
+            buffer.clear();
+        }
+
+        for _ in 0..frames {
+            if self.queue.len() < self.block_align {
+                for buffer in buffers.iter_mut() {
+                    buffer.push(0.0);
+                }
+                continue;
+            }
+
+            for (ch_i, buffer) in buffers.iter_mut().enumerate() {
+                let offset = ch_i * channel_align;
+                buffer.push(f32::from_le_bytes([
+                    self.queue[offset],
+                    self.queue[offset + 1],
+                    self.queue[offset + 2],
+                    self.queue[offset + 3],
+                ]));
+            }
+            self.queue.drain(0..self.block_align);
+        }
+    }
 }
 
-struct AudioThread<P: ProcessHandler> {
+impl Drop for LoopbackCapture {
+    fn drop(&mut self) {
+        if let Err(e) = self.audio_client.stop_stream() {
+            log::error!("Error stopping WASAPI loopback stream: {}", e);
+        }
+    }
+}
+
+struct AudioThread<P: ProcessHandler<M>, M: Send + 'static> {
     stream_dropped: Arc<AtomicBool>,
-    audio_client: wasapi::AudioClient,
-    h_event: wasapi::Handle,
-    render_client: wasapi::AudioRenderClient,
-    block_align: usize,
-    vbps: u16,
-    sample_type: wasapi::SampleType,
-    channels: usize,
+    device: DeviceStream,
     to_handle_tx: ringbuf::Producer<StreamMsg>,
-    max_frames: usize,
+    num_scratch_buffers: usize,
+    zero_outputs: bool,
+    catch_process_panics: bool,
     process_handler: P,
+    user_msg_rx: Consumer<M>,
+    clip_detector: Option<ClipDetector>,
+    overload_detector: Option<OverloadDetector>,
+    block_size_stats: Option<Arc<SharedBlockSizeStats>>,
+    glitch_counts: Arc<SharedGlitchCounts>,
+    ditherer: Ditherer,
+    output_frame: u64,
+    is_first_block: bool,
+    // The stream info to send in `StreamMsg::Started`. This is `None` once it
+    // has been sent. It is boxed up front so that sending it doesn't allocate.
+    started_msg_info: Option<Box<StreamInfo>>,
+    // The stream info that is returned by `StreamHandle::stream_info()`. This is
+    // only locked when the device is reopened, never while processing.
+    shared_stream_info: Arc<Mutex<StreamInfo>>,
+    // The config used to reopen the device if it is lost. This is `None` if
+    // `RunOptions::auto_reconnect` is `false`.
+    reconnect_config: Option<RainoutConfig>,
+    options: RunOptions,
 }
 
-impl<P: ProcessHandler> AudioThread<P> {
-    fn run(self) {
+/// Why the audio thread stopped processing a device.
+enum DeviceExit {
+    /// The stream was dropped, or it stopped because of an error that was
+    /// already sent to the stream handle.
+    Stopped,
+    /// The device stopped working (i.e. it was unplugged). This contains the
+    /// error reported by the backend.
+    Lost(String),
+}
+
+impl<P: ProcessHandler<M>, M: Send + 'static> AudioThread<P, M> {
+    /// How long to wait between attempts to reopen a lost device.
+    const RECONNECT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+    fn run(mut self) {
+        mark_audio_thread();
+
+        loop {
+            let exit = self.process_device();
+
+            if let Err(e) = self.device.audio_client.stop_stream() {
+                log::error!("Error stopping WASAPI stream: {}", e);
+            }
+
+            let e = match exit {
+                DeviceExit::Stopped => break,
+                DeviceExit::Lost(e) => e,
+            };
+
+            let id = self.device_id();
+            let disconnected =
+                StreamError::AudioDeviceDisconnected { id: id.clone(), msg: Some(e.clone()) };
+
+            if self.reconnect_config.is_none() || self.stream_dropped.load(Ordering::Relaxed) {
+                let _ = self.to_handle_tx.push(StreamMsg::Error(disconnected));
+                break;
+            }
+
+            log::warn!("WASAPI device {} was lost, trying to reconnect: {}", &id.name, e);
+            let _ = self.to_handle_tx.push(StreamMsg::AudioDeviceDisconnected(id.clone()));
+
+            match self.reconnect() {
+                Some(device) => {
+                    log::info!("Reconnected to WASAPI device {}", &id.name);
+                    self.replace_device(device);
+                    let _ = self.to_handle_tx.push(StreamMsg::AudioDeviceReconnected(id));
+                }
+                None => {
+                    log::error!("Failed to reconnect to WASAPI device {}", &id.name);
+                    let _ = self.to_handle_tx.push(StreamMsg::Error(disconnected));
+                    break;
+                }
+            }
+        }
+
+        log::debug!("WASAPI audio thread ended");
+    }
+
+    fn device_id(&self) -> DeviceID {
+        match &self.device.stream_info.audio_device {
+            AudioDeviceStreamInfo::Single { id, .. } => id.clone(),
+            _ => DeviceID { name: String::from("unknown device"), identifier: None },
+        }
+    }
+
+    /// Try to reopen the lost device, up to `RunOptions::reconnect_attempts` times.
+    fn reconnect(&mut self) -> Option<DeviceStream> {
+        let config = self.reconnect_config.as_ref()?;
+
+        for attempt in 1..=self.options.reconnect_attempts {
+            std::thread::sleep(Self::RECONNECT_INTERVAL);
+
+            if self.stream_dropped.load(Ordering::Relaxed) {
+                return None;
+            }
+
+            let mut config_warnings = Vec::new();
+            match open_device(config, &self.options, &mut config_warnings) {
+                Ok(device) => {
+                    for warning in config_warnings {
+                        let _ = self.to_handle_tx.push(StreamMsg::ConfigWarning(warning));
+                    }
+                    return Some(device);
+                }
+                Err(e) => {
+                    log::debug!("Reconnect attempt {} failed: {}", attempt, e);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Switch to a reopened device, notifying the process handler if the stream
+    /// changed.
+    fn replace_device(&mut self, device: DeviceStream) {
+        let old_info = &self.device.stream_info;
+        let new_info = &device.stream_info;
+
+        if new_info.sample_rate != old_info.sample_rate
+            || new_info.num_out_channels != old_info.num_out_channels
+            || new_info.buffer_size != old_info.buffer_size
+        {
+            if self.clip_detector.is_some() {
+                self.clip_detector = Some(ClipDetector::new(device.channels, new_info.sample_rate));
+            }
+            if self.overload_detector.is_some() {
+                self.overload_detector = Some(OverloadDetector::new(
+                    new_info.sample_rate,
+                    self.options.process_overload_threshold,
+                    self.options.process_overload_blocks,
+                ));
+            }
+
+            // No device is running at this point, so this thread is not
+            // realtime-critical and the process handler is free to allocate.
+            self.process_handler.stream_changed(new_info);
+        }
+
+        // Publish the new stream info before `StreamMsg::AudioDeviceReconnected` is sent.
+        *self.shared_stream_info.lock().unwrap_or_else(|e| e.into_inner()) = new_info.clone();
+
+        self.device = device;
+    }
+
+    /// Process the current device until the stream is dropped or the device
+    /// stops working.
+    fn process_device(&mut self) -> DeviceExit {
         let AudioThread {
             stream_dropped,
+            device,
+            to_handle_tx,
+            num_scratch_buffers,
+            zero_outputs,
+            catch_process_panics,
+            process_handler,
+            user_msg_rx,
+            clip_detector,
+            overload_detector,
+            block_size_stats,
+            glitch_counts,
+            ditherer,
+            output_frame,
+            is_first_block,
+            started_msg_info,
+            options,
+            ..
+        } = self;
+        let raw_passthrough = options.raw_passthrough;
+
+        let DeviceStream {
             audio_client,
-            h_event,
+            waiter,
             render_client,
             block_align,
             vbps,
             sample_type,
             channels,
-            mut to_handle_tx,
             max_frames,
-            mut process_handler,
-        } = self;
+            device_buffer_frames,
+            exclusive_event_driven,
+            loopback,
+            ..
+        } = device;
+        let (block_align, vbps, channels, max_frames, device_buffer_frames) =
+            (*block_align, *vbps, *channels, *max_frames, *device_buffer_frames);
+        let exclusive_event_driven = *exclusive_event_driven;
 
         // The buffer that is sent to WASAPI. Pre-allocate a reasonably large size.
         let mut device_buffer = vec![0u8; PREALLOC_FRAMES * block_align];
@@ -340,7 +1044,18 @@ impl<P: ProcessHandler> AudioThread<P> {
         let mut proc_owned_buffers: Vec<Vec<f32>> =
             (0..channels).map(|_| vec![0.0; max_frames as usize]).collect();
 
+        let mut scratch_buffers: Vec<Vec<f32>> =
+            (0..*num_scratch_buffers).map(|_| Vec::with_capacity(max_frames)).collect();
+
+        // The loopback input buffers.
+        let num_in_channels = if loopback.is_some() { channels } else { 0 };
+        let mut proc_in_buffers: Vec<Vec<f32>> =
+            (0..num_in_channels).map(|_| Vec::with_capacity(max_frames)).collect();
+        let silent_audio_inputs = vec![false; num_in_channels];
+
         let channel_align = block_align / channels;
+        let output_format =
+            OutputFormat { sample_type: *sample_type, vbps, channel_align, raw_passthrough };
 
         match sample_type {
             wasapi::SampleType::Float => {
@@ -350,20 +1065,26 @@ impl<P: ProcessHandler> AudioThread<P> {
                 log::info!("WASAPI sample type: SampleType::Int");
             }
         }
-        log::info!("WASAPI stream bits per sample: {}", vbps);
+        log::info!("WASAPI stream bits per sample: {} ({} bit container)", vbps, channel_align * 8);
 
         while !stream_dropped.load(Ordering::Relaxed) {
             let buffer_frame_count = match audio_client.get_available_space_in_frames() {
                 Ok(f) => f as usize,
                 Err(e) => {
                     log::error!("Fatal WASAPI stream error getting buffer frame count: {}", e);
-                    to_handle_tx
-                        .push(StreamMsg::Error(StreamError::PlatformSpecific(format!("{}", e))))
-                        .unwrap();
-                    break;
+                    return DeviceExit::Lost(format!("{}", e));
                 }
             };
 
+            if is_output_underrun(
+                buffer_frame_count,
+                device_buffer_frames,
+                exclusive_event_driven,
+                *is_first_block,
+            ) {
+                glitch_counts.record_output_underrun();
+            }
+
             // Make sure that the device's buffer is large enough. In theory if we pre-allocated
             // enough frames this shouldn't ever actually trigger any allocation.
             if buffer_frame_count > device_buffer_capacity_frames {
@@ -372,56 +1093,110 @@ impl<P: ProcessHandler> AudioThread<P> {
                 device_buffer.resize(buffer_frame_count as usize * block_align, 0);
             }
 
+            if let Some(loopback) = loopback.as_mut() {
+                if let Err(e) = loopback.read() {
+                    log::error!("Fatal WASAPI stream error while reading loopback capture: {}", e);
+                    return DeviceExit::Lost(format!("{}", e));
+                }
+            }
+
             let mut frames_written = 0;
             while frames_written < buffer_frame_count {
                 let frames = (buffer_frame_count - frames_written).min(max_frames);
 
-                // Clear and resize the buffer first. Since we never allow more than
-                // `max_frames`, this will never allocate.
-                for b in proc_owned_buffers.iter_mut() {
-                    b.clear();
-                    b.resize(frames, 0.0);
+                let device_buffer_part = &mut device_buffer
+                    [frames_written * block_align..(frames_written + frames) * block_align];
+                prepare_output_buffers(
+                    device_buffer_part,
+                    &mut proc_owned_buffers,
+                    frames,
+                    &output_format,
+                    *zero_outputs,
+                );
+
+                if let Some(loopback) = loopback.as_mut() {
+                    loopback.fill_buffers(&mut proc_in_buffers, frames);
                 }
 
-                process_handler.process(ProcessInfo {
-                    audio_inputs: &[],
-                    audio_outputs: proc_owned_buffers.as_mut_slice(),
+                let proc_info = ProcessInfo {
+                    audio_inputs: &proc_in_buffers,
+                    audio_outputs: if raw_passthrough {
+                        &mut proc_owned_buffers[0..0]
+                    } else {
+                        &mut proc_owned_buffers[..]
+                    },
                     frames,
-                    silent_audio_inputs: &[],
+                    output_frame: *output_frame,
+                    is_first_block: *is_first_block,
+                    input_delay_frames: None,
+                    silent_audio_inputs: &silent_audio_inputs,
+                    raw_audio_output: if raw_passthrough {
+                        Some(&mut *device_buffer_part)
+                    } else {
+                        None
+                    },
 
                     #[cfg(feature = "midi")]
                     midi_inputs: &[],
                     #[cfg(feature = "midi")]
                     midi_outputs: &mut [],
-                });
 
-                let device_buffer_part = &mut device_buffer
-                    [frames_written * block_align..(frames_written + frames) * block_align];
+                    user_msg_rx: &mut *user_msg_rx,
+                    scratch_buffers: &mut scratch_buffers,
+                };
 
-                // Fill each slice into the device's output buffer
-                //
-                // TODO: This could be potentially optimized with unsafe bounds check eliding.
-                match sample_type {
-                    wasapi::SampleType::Float => {
-                        if vbps == 32 {
-                            for (frame_i, out_frame) in
-                                device_buffer_part.chunks_exact_mut(block_align).enumerate()
-                            {
-                                for (ch_i, out_smp_bytes) in
-                                    out_frame.chunks_exact_mut(channel_align).enumerate()
-                                {
-                                    let smp_bytes = proc_owned_buffers[ch_i][frame_i].to_le_bytes();
-
-                                    out_smp_bytes[0..smp_bytes.len()].copy_from_slice(&smp_bytes);
-                                }
-                            }
-                        } // TODO: 64 bit buffers?
+                if let Some(overload_detector) = overload_detector.as_mut() {
+                    overload_detector.begin();
+                }
+
+                if *catch_process_panics {
+                    if let Err(payload) = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                        process_handler.process(proc_info)
+                    })) {
+                        log::error!("The process handler panicked. Stopping the WASAPI stream...");
+                        let _ =
+                            to_handle_tx.push(StreamMsg::Error(StreamError::from_panic(payload)));
+                        return DeviceExit::Stopped;
                     }
-                    wasapi::SampleType::Int => {
-                        // TODO: Convert from float to int
+                } else {
+                    process_handler.process(proc_info);
+                }
+
+                if let Some(overload_detector) = overload_detector.as_mut() {
+                    overload_detector.end(frames);
+
+                    if overload_detector.is_report_due() {
+                        overload_detector.report(to_handle_tx);
                     }
                 }
 
+                *output_frame += frames as u64;
+                *is_first_block = false;
+
+                if let Some(stream_info) = started_msg_info.take() {
+                    let _ = to_handle_tx.push(StreamMsg::Started(stream_info));
+                }
+
+                if let Some(block_size_stats) = block_size_stats.as_ref() {
+                    block_size_stats.record(frames);
+                }
+
+                // With raw passthrough, there are no `f32` buffers to check.
+                if let Some(clip_detector) = clip_detector.as_mut().filter(|_| !raw_passthrough) {
+                    clip_detector.process(&proc_owned_buffers, frames);
+
+                    if clip_detector.is_report_due() {
+                        clip_detector.report(to_handle_tx);
+                    }
+                }
+
+                write_output_buffers(
+                    device_buffer_part,
+                    &proc_owned_buffers,
+                    &output_format,
+                    &mut *ditherer,
+                );
+
                 frames_written += frames;
             }
 
@@ -433,38 +1208,326 @@ impl<P: ProcessHandler> AudioThread<P> {
                 None,
             ) {
                 log::error!("Fatal WASAPI stream error while writing to device: {}", e);
-                to_handle_tx
-                    .push(StreamMsg::Error(StreamError::PlatformSpecific(format!("{}", e))))
-                    .unwrap();
-                break;
+                return DeviceExit::Lost(format!("{}", e));
             }
 
-            if let Err(e) = h_event.wait_for_event(1000) {
+            if let Err(e) = waiter.wait() {
                 log::error!("Fatal WASAPI stream error while waiting for event: {}", e);
-                to_handle_tx
-                    .push(StreamMsg::Error(StreamError::PlatformSpecific(format!("{}", e))))
-                    .unwrap();
-                break;
+                return DeviceExit::Lost(format!("{}", e));
             }
         }
 
-        if let Err(e) = audio_client.stop_stream() {
-            log::error!("Error stopping WASAPI stream: {}", e);
+        DeviceExit::Stopped
+    }
+}
+
+/// Returns a distinct name for the thread of a new stream (i.e. `rainout-wasapi-2`),
+/// so that concurrent streams can be told apart in a debugger or profiler.
+fn stream_thread_name() -> String {
+    format!("rainout-wasapi-{}", NUM_STREAMS.fetch_add(1, Ordering::Relaxed) + 1)
+}
+
+/// Returns the duration of the client buffer (in units of 100 nanoseconds) for
+/// the given device period and `RunOptions::wasapi_extra_periods`.
+///
+/// In shared mode the engine period is fixed, but a larger client buffer gives the
+/// audio thread more slack before the device is starved. In exclusive mode the
+/// buffer must match the period when event-driven, so the extra periods are ignored.
+fn client_buffer_duration(share_mode: &wasapi::ShareMode, period: i64, extra_periods: u32) -> i64 {
+    match share_mode {
+        wasapi::ShareMode::Shared => period.saturating_mul(1 + i64::from(extra_periods)),
+        wasapi::ShareMode::Exclusive => period,
+    }
+}
+
+/// Returns the device period (in units of 100 nanoseconds) to use in shared mode.
+///
+/// The period of the audio engine can't be changed in shared mode, so a
+/// `ConfigWarning::BlockSizeNotUsed` is pushed if a different block size was
+/// requested.
+fn shared_period(
+    config: &RainoutConfig,
+    id: &DeviceID,
+    sample_rate: u32,
+    default_period: i64,
+    config_warnings: &mut Vec<ConfigWarning>,
+) -> i64 {
+    if let AutoOption::Use(block_size) = config.block_size {
+        let actual = (default_period * i64::from(sample_rate) / 10_000_000) as u32;
+        if block_size != actual {
+            log::warn!(
+                "Could not use block size {} on WASAPI device {} in shared mode, using {} instead",
+                block_size,
+                &id.name,
+                actual
+            );
+            config_warnings.push(ConfigWarning::BlockSizeNotUsed { requested: block_size, actual });
         }
+    }
 
-        log::debug!("WASAPI audio thread ended");
+    default_period
+}
+
+/// Returns the device period (in units of 100 nanoseconds) to use in exclusive
+/// mode that best matches the requested block size.
+fn exclusive_period(
+    config: &RainoutConfig,
+    id: &DeviceID,
+    sample_rate: u32,
+    min_period: i64,
+    config_warnings: &mut Vec<ConfigWarning>,
+) -> i64 {
+    if let AutoOption::Use(block_size) = config.block_size {
+        let requested_period = i64::from(block_size) * 10_000_000 / i64::from(sample_rate);
+        if requested_period < min_period {
+            let actual = (min_period * i64::from(sample_rate) / 10_000_000) as u32;
+            log::warn!(
+                "Could not use block size {} on WASAPI device {}, using {} instead",
+                block_size,
+                &id.name,
+                actual
+            );
+            config_warnings.push(ConfigWarning::BlockSizeNotUsed { requested: block_size, actual });
+            min_period
+        } else {
+            requested_period
+        }
+    } else {
+        min_period
+    }
+}
+
+/// The format of the device's output buffer.
+struct OutputFormat {
+    sample_type: wasapi::SampleType,
+    vbps: u16,
+    /// The number of bytes of each sample.
+    channel_align: usize,
+    /// See `RunOptions::raw_passthrough`.
+    raw_passthrough: bool,
+}
+
+/// Get the output buffers ready for the process handler to write `frames`
+/// frames to.
+///
+/// With raw passthrough, `device_buffer_part` is always zeroed, even when
+/// `zero_outputs` is `false`. It is a different part of the device's buffer
+/// each cycle, so it would otherwise contain stale bytes from some earlier
+/// cycle rather than what the process handler wrote in the previous one.
+fn prepare_output_buffers(
+    device_buffer_part: &mut [u8],
+    buffers: &mut [Vec<f32>],
+    frames: usize,
+    format: &OutputFormat,
+    zero_outputs: bool,
+) {
+    if format.raw_passthrough {
+        device_buffer_part.fill(0);
+        return;
+    }
+
+    // Clear and resize the buffer first. Since we never allow more than
+    // `max_frames`, this will never allocate.
+    for b in buffers.iter_mut() {
+        if zero_outputs {
+            b.clear();
+        }
+        b.resize(frames, 0.0);
+    }
+}
+
+/// Convert the process handler's output buffers into the device's format and
+/// fill them into `device_buffer_part`.
+///
+/// With raw passthrough the process handler already wrote to the device's
+/// buffer, so it is left as is.
+//
+// TODO: This could be potentially optimized with unsafe bounds check eliding.
+fn write_output_buffers(
+    device_buffer_part: &mut [u8],
+    buffers: &[Vec<f32>],
+    format: &OutputFormat,
+    ditherer: &mut Ditherer,
+) {
+    if format.raw_passthrough {
+        return;
+    }
+
+    let channel_align = format.channel_align;
+    let block_align = channel_align * buffers.len();
+
+    match format.sample_type {
+        wasapi::SampleType::Float => {
+            if format.vbps == 32 {
+                for (frame_i, out_frame) in
+                    device_buffer_part.chunks_exact_mut(block_align).enumerate()
+                {
+                    for (ch_i, buffer) in buffers.iter().enumerate() {
+                        write_sample_le(out_frame, ch_i * channel_align, buffer[frame_i]);
+                    }
+                }
+            } // TODO: 64 bit buffers?
+        }
+        wasapi::SampleType::Int => {
+            if format.vbps == 16 {
+                for (frame_i, out_frame) in
+                    device_buffer_part.chunks_exact_mut(block_align).enumerate()
+                {
+                    for (ch_i, buffer) in buffers.iter().enumerate() {
+                        let offset = ch_i * channel_align;
+                        out_frame[offset..offset + 2]
+                            .copy_from_slice(&ditherer.f32_to_i16(buffer[frame_i]).to_le_bytes());
+                    }
+                }
+            } else if channel_align == 4 {
+                // 24 bit samples are left-aligned in a 32 bit container,
+                // so they are written the same way as 32 bit samples.
+                //
+                // TODO: Dither 24 bit samples?
+                for (frame_i, out_frame) in
+                    device_buffer_part.chunks_exact_mut(block_align).enumerate()
+                {
+                    for (ch_i, buffer) in buffers.iter().enumerate() {
+                        write_i32_le(out_frame, ch_i * channel_align, buffer[frame_i]);
+                    }
+                }
+            } else if channel_align == 3 {
+                // Packed 24 bit samples.
+                //
+                // TODO: Dither 24 bit samples?
+                for (frame_i, out_frame) in
+                    device_buffer_part.chunks_exact_mut(block_align).enumerate()
+                {
+                    for (ch_i, buffer) in buffers.iter().enumerate() {
+                        write_i24_le(out_frame, ch_i * channel_align, buffer[frame_i]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns `true` if the device ran out of data since the last cycle, given the
+/// number of frames that are free in the device buffer.
+///
+/// If the whole device buffer is free, then the device has played everything
+/// that was written to it. This can't be detected in exclusive event-driven
+/// mode, where the whole buffer is handed over at every event, so underruns are
+/// never counted in that mode.
+fn is_output_underrun(
+    available_frames: usize,
+    device_buffer_frames: usize,
+    exclusive_event_driven: bool,
+    is_first_block: bool,
+) -> bool {
+    !exclusive_event_driven && !is_first_block && available_frames >= device_buffer_frames
+}
+
+/// Write a single `f32` sample into `buf` at the byte `offset`.
+///
+/// WASAPI samples are always little-endian (Windows only runs on little-endian
+/// platforms), so this is explicit about the byte order rather than relying
+/// on the native one.
+///
+/// Non-finite samples are never sent to the device: `NaN` is written as `0.0`,
+/// and infinities are clamped to `1.0` or `-1.0`.
+#[inline]
+fn write_sample_le(buf: &mut [u8], offset: usize, sample: f32) {
+    let sample = if sample.is_nan() {
+        0.0
+    } else if sample.is_infinite() {
+        sample.signum()
+    } else {
+        sample
+    };
+
+    buf[offset..offset + 4].copy_from_slice(&sample.to_le_bytes());
+}
+
+/// Write a single `f32` sample into `buf` at the byte `offset` as a full-scale
+/// little-endian `i32` sample (this is also used for 24 bit samples that are
+/// left-aligned in a 32 bit container).
+///
+/// `NaN` is written as silence, and samples outside of the range `[-1.0, 1.0]`
+/// are clamped.
+#[inline]
+fn write_i32_le(buf: &mut [u8], offset: usize, sample: f32) {
+    let sample = if sample.is_nan() {
+        0
+    } else {
+        // `as` saturates, so this can't overflow.
+        (f64::from(sample) * 2_147_483_647.0).round() as i32
+    };
+
+    buf[offset..offset + 4].copy_from_slice(&sample.to_le_bytes());
+}
+
+/// Write a single `f32` sample into `buf` at the byte `offset` as a packed
+/// little-endian 24 bit sample.
+///
+/// `NaN` is written as silence, and samples outside of the range `[-1.0, 1.0]`
+/// are clamped.
+#[inline]
+fn write_i24_le(buf: &mut [u8], offset: usize, sample: f32) {
+    let sample = if sample.is_nan() {
+        0
+    } else {
+        (f64::from(sample) * 8_388_607.0).round().clamp(-8_388_608.0, 8_388_607.0) as i32
+    };
+
+    buf[offset..offset + 3].copy_from_slice(&sample.to_le_bytes()[0..3]);
+}
+
+/// Waits for the render endpoint to need more data.
+///
+/// Only the render endpoint is waited on. The loopback capture (if any) is read
+/// once every time this returns, and input-only streams are not supported yet.
+struct DeviceWaiter {
+    /// The event that is signaled when the render endpoint needs more data.
+    /// This is only waited on in event-driven mode, but it is kept for the
+    /// lifetime of the stream either way.
+    render_event: wasapi::Handle,
+    /// `false` when running in timer-driven mode.
+    event_driven: bool,
+    // TODO: Add a capture event for physical inputs. Waiting on both will need
+    // `WaitForMultipleObjects`, which the `wasapi` crate does not expose.
+    /// How long to sleep in timer-driven mode.
+    timer_interval: std::time::Duration,
+}
+
+impl DeviceWaiter {
+    /// How long to wait for an event before giving up (in milliseconds).
+    ///
+    /// The device signals an event once every period, which is at most a few
+    /// tens of milliseconds. If no event arrives within this time, then the
+    /// device has stopped processing (i.e. it was unplugged or disabled), so
+    /// the timeout is treated as a fatal stream error.
+    const EVENT_TIMEOUT_MS: u32 = 1000;
+
+    /// Block until the device needs more data.
+    ///
+    /// In timer-driven mode this sleeps for half of the device period and never
+    /// fails, so errors are only detected when writing to the device.
+    fn wait(&self) -> Result<(), Box<dyn Error>> {
+        if self.event_driven {
+            self.render_event.wait_for_event(Self::EVENT_TIMEOUT_MS)
+        } else {
+            std::thread::sleep(self.timer_interval);
+            Ok(())
+        }
     }
 }
 
 pub struct WasapiStreamHandle {
-    stream_info: StreamInfo,
+    // This is updated by the audio thread when the device is reopened.
+    stream_info: Arc<Mutex<StreamInfo>>,
 
     stream_dropped: Arc<AtomicBool>,
 }
 
-impl<P: ProcessHandler> PlatformStreamHandle<P> for WasapiStreamHandle {
-    fn stream_info(&self) -> &StreamInfo {
-        &self.stream_info
+impl<P: ProcessHandler<M>, M: Send + 'static> PlatformStreamHandle<P, M> for WasapiStreamHandle {
+    fn stream_info(&self) -> StreamInfo {
+        self.stream_info.lock().unwrap_or_else(|e| e.into_inner()).clone()
     }
 
     fn change_block_size(&mut self, _block_size: u32) -> Result<(), ChangeBlockSizeError> {
@@ -501,3 +1564,367 @@ impl From<Box<dyn Error>> for RunConfigError {
         RunConfigError::PlatformSpecific(format!("{}", e))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DitherMode, GlitchCounts};
+
+    #[test]
+    fn output_underruns_are_counted_when_the_buffer_runs_dry() {
+        let glitch_counts = SharedGlitchCounts::new();
+
+        // A shared mode device with a 1024 frame buffer. The audio thread is late
+        // on the third and fifth cycles, so the device played the whole buffer.
+        for (i, available_frames) in [1024, 480, 1024, 512, 1024].into_iter().enumerate() {
+            if is_output_underrun(available_frames, 1024, false, i == 0) {
+                glitch_counts.record_output_underrun();
+            }
+        }
+
+        assert_eq!(
+            glitch_counts.snapshot(),
+            GlitchCounts { input_overruns: 0, output_underruns: 2 }
+        );
+    }
+
+    #[test]
+    fn output_underruns_are_not_counted_in_exclusive_event_driven_mode() {
+        // The whole buffer is free at every event.
+        for is_first_block in [true, false] {
+            assert!(!is_output_underrun(256, 256, true, is_first_block));
+        }
+    }
+
+    /// Write `sample` with `write_sample_le()` and read it back.
+    fn round_trip(sample: f32) -> f32 {
+        let mut buf = [0xFF; 8];
+        write_sample_le(&mut buf, 4, sample);
+
+        // The bytes before the offset are left untouched.
+        assert_eq!(buf[0..4], [0xFF; 4]);
+
+        f32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]])
+    }
+
+    #[test]
+    fn write_sample_le_round_trip() {
+        for sample in [0.0, -0.0, 0.5, -0.25, 1.0, -1.0, 1.5, f32::MIN_POSITIVE] {
+            assert_eq!(round_trip(sample).to_bits(), sample.to_bits());
+        }
+
+        // Subnormals are passed through as is.
+        let subnormal = f32::from_bits(1);
+        assert!(subnormal.is_subnormal());
+        assert_eq!(round_trip(subnormal).to_bits(), subnormal.to_bits());
+        assert_eq!(round_trip(-subnormal).to_bits(), (-subnormal).to_bits());
+    }
+
+    #[test]
+    fn write_sample_le_non_finite() {
+        assert_eq!(round_trip(f32::INFINITY), 1.0);
+        assert_eq!(round_trip(f32::NEG_INFINITY), -1.0);
+        assert_eq!(round_trip(f32::NAN), 0.0);
+        assert_eq!(round_trip(-f32::NAN), 0.0);
+    }
+
+    #[test]
+    fn write_sample_le_byte_order() {
+        let mut buf = [0; 4];
+        write_sample_le(&mut buf, 0, 1.0);
+
+        // 1.0 is 0x3F800000, least significant byte first.
+        assert_eq!(buf, [0x00, 0x00, 0x80, 0x3F]);
+    }
+
+    fn write_i32(sample: f32) -> i32 {
+        let mut buf = [0xFF; 8];
+        write_i32_le(&mut buf, 4, sample);
+
+        assert_eq!(buf[0..4], [0xFF; 4]);
+
+        i32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]])
+    }
+
+    fn write_i24(sample: f32) -> i32 {
+        let mut buf = [0xFF; 7];
+        write_i24_le(&mut buf, 2, sample);
+
+        // Only the three bytes at the offset are written.
+        assert_eq!(buf[0..2], [0xFF; 2]);
+        assert_eq!(buf[5..7], [0xFF; 2]);
+
+        // Sign-extend the packed sample.
+        i32::from_le_bytes([0, buf[2], buf[3], buf[4]]) >> 8
+    }
+
+    #[test]
+    fn write_i32_le_full_scale() {
+        assert_eq!(write_i32(0.0), 0);
+        assert_eq!(write_i32(1.0), i32::MAX);
+        assert_eq!(write_i32(-1.0), -i32::MAX);
+        assert_eq!(write_i32(0.5), 1_073_741_824);
+    }
+
+    #[test]
+    fn write_i32_le_clamps() {
+        assert_eq!(write_i32(1.5), i32::MAX);
+        assert_eq!(write_i32(-1.5), i32::MIN);
+        assert_eq!(write_i32(f32::INFINITY), i32::MAX);
+        assert_eq!(write_i32(f32::NEG_INFINITY), i32::MIN);
+        assert_eq!(write_i32(f32::NAN), 0);
+    }
+
+    #[test]
+    fn write_i32_le_byte_order() {
+        let mut buf = [0; 4];
+        write_i32_le(&mut buf, 0, 1.0);
+
+        // 0x7FFFFFFF, least significant byte first.
+        assert_eq!(buf, [0xFF, 0xFF, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn write_i24_le_full_scale() {
+        assert_eq!(write_i24(0.0), 0);
+        assert_eq!(write_i24(1.0), 8_388_607);
+        assert_eq!(write_i24(-1.0), -8_388_607);
+        assert_eq!(write_i24(0.5), 4_194_304);
+    }
+
+    #[test]
+    fn write_i24_le_clamps() {
+        assert_eq!(write_i24(1.5), 8_388_607);
+        assert_eq!(write_i24(-1.5), -8_388_608);
+        assert_eq!(write_i24(f32::INFINITY), 8_388_607);
+        assert_eq!(write_i24(f32::NEG_INFINITY), -8_388_608);
+        assert_eq!(write_i24(f32::NAN), 0);
+    }
+
+    #[test]
+    fn write_i24_le_byte_order() {
+        let mut buf = [0; 3];
+        write_i24_le(&mut buf, 0, -1.0);
+
+        // -8388607 is 0x800001, least significant byte first.
+        assert_eq!(buf, [0x01, 0x00, 0x80]);
+    }
+
+    fn output_format(raw_passthrough: bool) -> OutputFormat {
+        OutputFormat {
+            sample_type: wasapi::SampleType::Int,
+            vbps: 16,
+            channel_align: 2,
+            raw_passthrough,
+        }
+    }
+
+    #[test]
+    fn raw_passthrough_bytes_are_not_modified() {
+        let format = output_format(true);
+        let mut ditherer = Ditherer::new(DitherMode::Tpdf, 1);
+
+        // Stale bytes from an earlier cycle.
+        let mut device_buffer_part = vec![0xAB; 16];
+        let mut buffers = vec![vec![0.5; 4], vec![-0.5; 4]];
+
+        // The bytes are zeroed even when `zero_unwritten_outputs` is false.
+        prepare_output_buffers(&mut device_buffer_part, &mut buffers, 4, &format, false);
+        assert_eq!(device_buffer_part, vec![0; 16]);
+
+        // The process handler writes bytes that are not valid dithered samples
+        // of anything in the `f32` buffers.
+        let written: Vec<u8> = (0..16).map(|i| (i * 17) as u8).collect();
+        device_buffer_part.copy_from_slice(&written);
+
+        write_output_buffers(&mut device_buffer_part, &buffers, &format, &mut ditherer);
+        assert_eq!(device_buffer_part, written);
+    }
+
+    #[test]
+    fn output_buffers_are_converted_without_raw_passthrough() {
+        let format = output_format(false);
+        let mut ditherer = Ditherer::new(DitherMode::None, 1);
+
+        let mut device_buffer_part = vec![0xAB; 8];
+        let mut buffers = vec![vec![0.0; 2], vec![0.0; 2]];
+        prepare_output_buffers(&mut device_buffer_part, &mut buffers, 2, &format, true);
+        buffers[0].copy_from_slice(&[1.0, -1.0]);
+        buffers[1].copy_from_slice(&[0.5, 0.0]);
+
+        write_output_buffers(&mut device_buffer_part, &buffers, &format, &mut ditherer);
+
+        let samples: Vec<i16> =
+            device_buffer_part.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+        assert_eq!(samples, [32_767, 16_384, -32_767, 0]);
+    }
+
+    #[test]
+    fn buffer_duration_scales_with_extra_periods() {
+        // A 10 ms period.
+        let period = 100_000;
+
+        assert_eq!(client_buffer_duration(&wasapi::ShareMode::Shared, period, 0), 100_000);
+        assert_eq!(client_buffer_duration(&wasapi::ShareMode::Shared, period, 1), 200_000);
+        assert_eq!(client_buffer_duration(&wasapi::ShareMode::Shared, period, 3), 400_000);
+        assert_eq!(
+            client_buffer_duration(&wasapi::ShareMode::Shared, period, u32::MAX),
+            i64::from(u32::MAX) * period + period
+        );
+    }
+
+    #[test]
+    fn buffer_duration_ignores_extra_periods_in_exclusive_mode() {
+        for extra_periods in [0, 1, 3] {
+            assert_eq!(
+                client_buffer_duration(&wasapi::ShareMode::Exclusive, 30_000, extra_periods),
+                30_000
+            );
+        }
+    }
+
+    fn block_size_config(block_size: AutoOption<u32>) -> RainoutConfig {
+        RainoutConfig {
+            sample_rate: AutoOption::Use(48_000),
+            block_size,
+            take_exclusive_access: true,
+            ..Default::default()
+        }
+    }
+
+    fn speakers() -> DeviceID {
+        DeviceID { name: String::from("Speakers"), identifier: None }
+    }
+
+    #[test]
+    fn exclusive_period_uses_the_requested_block_size() {
+        let mut warnings = Vec::new();
+
+        // 256 frames at 48 kHz is 5.333 ms, above the 3 ms minimum period.
+        let period = exclusive_period(
+            &block_size_config(AutoOption::Use(256)),
+            &speakers(),
+            48_000,
+            30_000,
+            &mut warnings,
+        );
+        assert_eq!(period, 53_333);
+        assert!(warnings.is_empty());
+
+        // Without a requested block size, the minimum period is used.
+        let period = exclusive_period(
+            &block_size_config(AutoOption::Auto),
+            &speakers(),
+            48_000,
+            30_000,
+            &mut warnings,
+        );
+        assert_eq!(period, 30_000);
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn exclusive_period_warns_below_the_minimum_period() {
+        let mut warnings = Vec::new();
+
+        // A 10 ms minimum period is 480 frames at 48 kHz.
+        let period = exclusive_period(
+            &block_size_config(AutoOption::Use(256)),
+            &speakers(),
+            48_000,
+            100_000,
+            &mut warnings,
+        );
+        assert_eq!(period, 100_000);
+        assert_eq!(warnings, [ConfigWarning::BlockSizeNotUsed { requested: 256, actual: 480 }]);
+    }
+
+    #[test]
+    fn shared_period_warns_when_the_block_size_is_not_used() {
+        let mut warnings = Vec::new();
+
+        // The engine period is 10 ms, which is 480 frames at 48 kHz.
+        let period = shared_period(
+            &block_size_config(AutoOption::Use(256)),
+            &speakers(),
+            48_000,
+            100_000,
+            &mut warnings,
+        );
+        assert_eq!(period, 100_000);
+        assert_eq!(warnings, [ConfigWarning::BlockSizeNotUsed { requested: 256, actual: 480 }]);
+
+        // No warning when the requested block size matches the engine period.
+        let mut warnings = Vec::new();
+        shared_period(
+            &block_size_config(AutoOption::Use(480)),
+            &speakers(),
+            48_000,
+            100_000,
+            &mut warnings,
+        );
+        assert!(warnings.is_empty());
+
+        shared_period(
+            &block_size_config(AutoOption::Auto),
+            &speakers(),
+            48_000,
+            100_000,
+            &mut warnings,
+        );
+        assert!(warnings.is_empty());
+    }
+
+    /// Runs two blocks of 2 frames through the output buffers of a stereo
+    /// stream, where the process handler writes to both channels in the first
+    /// block but only to the left channel in the second. Returns the 16 bit
+    /// samples sent to the device for the second block.
+    fn second_block_with_right_channel_untouched(zero_outputs: bool) -> Vec<i16> {
+        let format = output_format(false);
+        let mut ditherer = Ditherer::new(DitherMode::None, 1);
+
+        let mut device_buffer_part = vec![0; 8];
+        let mut buffers: Vec<Vec<f32>> = (0..2).map(|_| Vec::with_capacity(2)).collect();
+
+        prepare_output_buffers(&mut device_buffer_part, &mut buffers, 2, &format, zero_outputs);
+        buffers[0].copy_from_slice(&[0.5, 0.5]);
+        buffers[1].copy_from_slice(&[-0.5, -0.5]);
+        write_output_buffers(&mut device_buffer_part, &buffers, &format, &mut ditherer);
+
+        prepare_output_buffers(&mut device_buffer_part, &mut buffers, 2, &format, zero_outputs);
+        buffers[0].copy_from_slice(&[0.25, 0.25]);
+        write_output_buffers(&mut device_buffer_part, &buffers, &format, &mut ditherer);
+
+        device_buffer_part.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect()
+    }
+
+    #[test]
+    fn untouched_channels_are_silent_with_zero_unwritten_outputs() {
+        assert_eq!(second_block_with_right_channel_untouched(true), [8_192, 0, 8_192, 0]);
+    }
+
+    #[test]
+    fn untouched_channels_repeat_without_zero_unwritten_outputs() {
+        assert_eq!(
+            second_block_with_right_channel_untouched(false),
+            [8_192, -16_384, 8_192, -16_384]
+        );
+    }
+
+    #[test]
+    fn concurrent_streams_have_distinct_thread_names() {
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                std::thread::Builder::new()
+                    .name(stream_thread_name())
+                    .spawn(|| String::from(std::thread::current().name().unwrap()))
+                    .unwrap()
+            })
+            .collect();
+
+        let names: Vec<String> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+
+        assert!(names.iter().all(|name| name.starts_with("rainout-wasapi-")));
+        assert_ne!(names[0], names[1]);
+    }
+}